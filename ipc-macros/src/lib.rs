@@ -0,0 +1,357 @@
+//! Procedural macros for the `ipc` crate. Use them through their re-exports in `ipc`, e.g.
+//! `#[ipc::service]`.
+
+extern crate proc_macro;
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::parse::Parser;
+use syn::{parse_quote, FnArg, Ident, ItemTrait, Pat, Path, ReturnType, TraitItem, TraitItemFn, Type};
+
+/// Turns a trait of async methods into an IPC service. For a trait `Storage` this generates:
+///
+/// * The trait itself, with each method returning `ipc::Result<T>` in place of `T` and its
+///   future required to be `Send`.
+/// * `StorageClient`, which implements `Storage` by calling the methods over an
+///   `ipc::MessageIpcConnection`.
+/// * `StorageServer`, which serves calls on a connection by dispatching them to any
+///   implementation of `Storage`.
+///
+/// Methods must be `async`, take `&self` and otherwise take arguments by value. Arguments and
+/// return values are encoded with the client's and server's codec, bincode by default.
+///
+/// Generated code refers to the `ipc` crate as `::ipc`. Crates that re-export it under another
+/// name can say where it is with `#[service(crate = path::to::ipc)]`.
+#[proc_macro_attribute]
+pub fn service(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    match expand_service(attr.into(), item.into()) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+struct ServiceMethod {
+    name: Ident,
+    args: Vec<Ident>,
+    arg_types: Vec<Type>,
+    output: Type,
+}
+
+impl ServiceMethod {
+    fn parse(method: &TraitItemFn) -> syn::Result<ServiceMethod> {
+        let sig = &method.sig;
+
+        if sig.asyncness.is_none() {
+            return Err(syn::Error::new_spanned(sig, "service methods must be async"));
+        }
+
+        if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+            return Err(syn::Error::new_spanned(&sig.generics, "service methods can't be generic"));
+        }
+
+        let mut inputs = sig.inputs.iter();
+
+        match inputs.next() {
+            Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+            _ => {
+                return Err(syn::Error::new_spanned(sig, "service methods must take &self"));
+            }
+        };
+
+        let mut args = vec![];
+        let mut arg_types = vec![];
+
+        for input in inputs {
+            let input = match input {
+                FnArg::Typed(input) => input,
+                FnArg::Receiver(receiver) => {
+                    return Err(syn::Error::new_spanned(receiver, "unexpected receiver"));
+                }
+            };
+
+            match (&*input.pat, &*input.ty) {
+                (Pat::Ident(pat), ty) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                    if let Type::Reference(_) = ty {
+                        return Err(syn::Error::new_spanned(ty, "service method arguments must be taken by value"));
+                    }
+
+                    args.push(pat.ident.clone());
+                    arg_types.push(ty.clone());
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(&input.pat, "service method arguments must be named"));
+                }
+            };
+        }
+
+        let output = match &sig.output {
+            ReturnType::Default => parse_quote!(()),
+            ReturnType::Type(_, ty) => (**ty).clone(),
+        };
+
+        Ok(ServiceMethod {
+            name: sig.ident.clone(),
+            args,
+            arg_types,
+            output,
+        })
+    }
+
+    /// The name a call to this method goes by on the wire.
+    fn path(&self, service: &Ident) -> String {
+        format!("{}/{}", service, self.name)
+    }
+
+    /// The method's signature, with the async sugar removed so the future can be `Send`.
+    fn signature(&self, krate: &Path) -> TokenStream {
+        let ServiceMethod { name, args, arg_types, output } = self;
+
+        quote! {
+            fn #name(&self, #(#args: #arg_types),*)
+                -> impl ::std::future::Future<Output = #krate::Result<#output>> + ::std::marker::Send
+        }
+    }
+
+    /// The tuple type requests to this method are encoded as.
+    fn request_type(&self) -> TokenStream {
+        let arg_types = &self.arg_types;
+
+        quote! { (#(#arg_types,)*) }
+    }
+}
+
+fn expand_service(attr: TokenStream, item: TokenStream) -> syn::Result<TokenStream> {
+    let mut krate: Path = parse_quote!(::ipc);
+
+    let attr_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("crate") {
+            krate = meta.value()?.parse()?;
+            Ok(())
+        } else {
+            Err(meta.error("unsupported service argument"))
+        }
+    });
+
+    attr_parser.parse2(attr)?;
+
+    let mut item: ItemTrait = syn::parse2(item)?;
+
+    if !item.generics.params.is_empty() || item.generics.where_clause.is_some() {
+        return Err(syn::Error::new_spanned(&item.generics, "services can't be generic"));
+    }
+
+    let mut methods = vec![];
+
+    for trait_item in &mut item.items {
+        let method = match trait_item {
+            TraitItem::Fn(method) => method,
+            trait_item => {
+                return Err(syn::Error::new_spanned(trait_item, "services may only contain methods"));
+            }
+        };
+
+        if let Some(default) = &method.default {
+            return Err(syn::Error::new_spanned(default, "service methods can't have default implementations"));
+        }
+
+        let service_method = ServiceMethod::parse(method)?;
+        let attrs = &method.attrs;
+        let signature = service_method.signature(&krate);
+
+        *trait_item = parse_quote! {
+            #(#attrs)*
+            #signature;
+        };
+
+        methods.push(service_method);
+    }
+
+    let client = generate_client(&item, &methods, &krate);
+    let server = generate_server(&item, &methods, &krate);
+
+    Ok(quote! {
+        #item
+        #client
+        #server
+    })
+}
+
+fn generate_client(item: &ItemTrait, methods: &[ServiceMethod], krate: &Path) -> TokenStream {
+    let vis = &item.vis;
+    let service = &item.ident;
+    let client = format_ident!("{}Client", service);
+    let doc = format!("Calls the `{}` service over a message connection.", service);
+
+    let bounds = methods.iter().map(|method| {
+        let request = method.request_type();
+        let output = &method.output;
+
+        quote! { #krate::codec::Encoder<#request> + #krate::codec::Decoder<#output> }
+    });
+
+    let impls = methods.iter().map(|method| {
+        let signature = method.signature(krate);
+        let path = method.path(service);
+        let args = &method.args;
+
+        quote! {
+            #signature {
+                async move { self.client.call(#path, &(#(#args,)*)).await }
+            }
+        }
+    });
+
+    quote! {
+        #[doc = #doc]
+        #vis struct #client<C = #krate::codec::BincodeCodec> {
+            client: #krate::rpc::RpcClient<C>,
+        }
+
+        impl<C: ::std::default::Default> #client<C> {
+            /// Creates a client that encodes calls with the default instance of codec `C`. Must
+            /// be called within a tokio runtime.
+            #vis fn new(connection: #krate::MessageIpcConnection) -> #krate::Result<#client<C>> {
+                #client::with_codec(connection, C::default())
+            }
+        }
+
+        impl<C> #client<C> {
+            /// Creates a client that encodes calls with `codec`. Must be called within a tokio
+            /// runtime.
+            #vis fn with_codec(connection: #krate::MessageIpcConnection, codec: C) -> #krate::Result<#client<C>> {
+                ::std::result::Result::Ok(#client {
+                    client: #krate::rpc::RpcClient::with_codec(connection, codec)?,
+                })
+            }
+        }
+
+        impl<C> #service for #client<C>
+        where
+            C: #(#bounds +)* ::std::marker::Send + ::std::marker::Sync,
+        {
+            #(#impls)*
+        }
+    }
+}
+
+fn generate_server(item: &ItemTrait, methods: &[ServiceMethod], krate: &Path) -> TokenStream {
+    let vis = &item.vis;
+    let service = &item.ident;
+    let server = format_ident!("{}Server", service);
+    let doc = format!("Serves the `{}` service by dispatching calls to an implementation of it.", service);
+
+    let bounds = methods.iter().map(|method| {
+        let request = method.request_type();
+        let output = &method.output;
+
+        quote! { #krate::codec::Encoder<#output> + #krate::codec::Decoder<#request> }
+    });
+
+    let registrations = methods.iter().map(|method| {
+        let path = method.path(service);
+        let name = &method.name;
+        let args = &method.args;
+        let request = method.request_type();
+
+        quote! {
+            let __ipc_service = __ipc_implementation.clone();
+
+            __ipc_server.register(#path, move |(#(#args,)*): #request| {
+                let __ipc_service = __ipc_service.clone();
+
+                async move { __ipc_service.#name(#(#args),*).await }
+            });
+        }
+    });
+
+    quote! {
+        #[doc = #doc]
+        #vis struct #server<C = #krate::codec::BincodeCodec> {
+            server: #krate::rpc::RpcServer<C>,
+        }
+
+        impl<C> #server<C>
+        where
+            C: #(#bounds +)* ::std::clone::Clone + ::std::marker::Send + ::std::marker::Sync + 'static,
+        {
+            /// Creates a server that decodes calls with the default instance of codec `C`.
+            #vis fn new<T>(implementation: T) -> #server<C>
+            where
+                T: #service + ::std::marker::Send + ::std::marker::Sync + 'static,
+                C: ::std::default::Default,
+            {
+                #server::with_codec(implementation, C::default())
+            }
+
+            /// Creates a server that decodes calls with `codec`.
+            #vis fn with_codec<T>(implementation: T, codec: C) -> #server<C>
+            where
+                T: #service + ::std::marker::Send + ::std::marker::Sync + 'static,
+            {
+                let __ipc_implementation = ::std::sync::Arc::new(implementation);
+                let mut __ipc_server = #krate::rpc::RpcServer::with_codec(codec);
+
+                #(#registrations)*
+
+                #server { server: __ipc_server }
+            }
+
+            /// Serves calls on `connection` until the client disconnects.
+            #vis async fn serve(&self, connection: #krate::MessageIpcConnection) -> #krate::Result<()> {
+                self.server.serve(connection).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_service;
+
+    use quote::quote;
+
+    fn expand_error(item: proc_macro2::TokenStream) -> String {
+        expand_service(quote!(), item).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn generates_client_and_server() {
+        let tokens = expand_service(
+            quote!(),
+            quote! {
+                pub trait Storage {
+                    async fn get(&self, key: String) -> Vec<u8>;
+                    async fn clear(&self);
+                }
+            },
+        )
+        .unwrap()
+        .to_string();
+
+        assert!(tokens.contains("pub struct StorageClient"));
+        assert!(tokens.contains("pub struct StorageServer"));
+        assert!(tokens.contains("\"Storage/get\""));
+        assert!(tokens.contains("\"Storage/clear\""));
+        assert!(!tokens.contains("async fn get"));
+    }
+
+    #[test]
+    fn rejects_unsupported_methods() {
+        assert_eq!(
+            expand_error(quote! { trait Storage { fn get(&self) -> Vec<u8>; } }),
+            "service methods must be async"
+        );
+        assert_eq!(
+            expand_error(quote! { trait Storage { async fn get(&mut self) -> Vec<u8>; } }),
+            "service methods must take &self"
+        );
+        assert_eq!(
+            expand_error(quote! { trait Storage { async fn get(&self, key: &str) -> Vec<u8>; } }),
+            "service method arguments must be taken by value"
+        );
+        assert_eq!(
+            expand_error(quote! { trait Storage { async fn get<K>(&self, key: K) -> Vec<u8>; } }),
+            "service methods can't be generic"
+        );
+    }
+}
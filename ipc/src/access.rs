@@ -0,0 +1,185 @@
+use crate::credentials::PeerCredentials;
+
+use std::fmt;
+use std::sync::Arc;
+
+type Predicate = Arc<dyn Fn(&PeerCredentials) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+enum Rule {
+    Uid(u32),
+    Gid(u32),
+    Predicate(Predicate),
+}
+
+impl Rule {
+    fn matches(&self, peer: &PeerCredentials) -> bool {
+        match self {
+            Rule::Uid(uid) => peer.uid == Some(*uid),
+            Rule::Gid(gid) => peer.gid == Some(*gid),
+            Rule::Predicate(predicate) => predicate(peer),
+        }
+    }
+}
+
+impl fmt::Debug for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Uid(uid) => write!(f, "Uid({})", uid),
+            Rule::Gid(gid) => write!(f, "Gid({})", gid),
+            Rule::Predicate(_) => write!(f, "Predicate"),
+        }
+    }
+}
+
+/// Decides which peers a server accepts connections from, based on the `PeerCredentials`
+/// captured when they connect. Peers the policy denies are disconnected before
+/// `wait_for_connection` returns, so the application never sees them.
+///
+/// A peer is denied if any deny rule matches it. Otherwise it's allowed if the policy has no
+/// allow rules, or if any of them match. The default policy allows everyone.
+///
+/// Windows pipes don't report the peer's uid or gid, so rules on them never match there.
+///
+/// ```no_run
+/// # use ipc::{AccessPolicy, MessageIpcServer, ServerOptions};
+/// let policy = AccessPolicy::new()
+///     .allow_uid(1000)
+///     .allow_gid(27)
+///     .deny_if(|peer| peer.pid.is_none());
+///
+/// let server = MessageIpcServer::with_options("my_server", &ServerOptions::new().access_policy(policy));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct AccessPolicy {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+impl AccessPolicy {
+    /// Creates a policy that allows everyone, until rules are added.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows peers running as `uid`.
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.allow.push(Rule::Uid(uid));
+        self
+    }
+
+    /// Allows peers whose effective group is `gid`.
+    pub fn allow_gid(mut self, gid: u32) -> Self {
+        self.allow.push(Rule::Gid(gid));
+        self
+    }
+
+    /// Allows peers for which `predicate` returns true.
+    pub fn allow_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&PeerCredentials) -> bool + Send + Sync + 'static,
+    {
+        self.allow.push(Rule::Predicate(Arc::new(predicate)));
+        self
+    }
+
+    /// Denies peers running as `uid`.
+    pub fn deny_uid(mut self, uid: u32) -> Self {
+        self.deny.push(Rule::Uid(uid));
+        self
+    }
+
+    /// Denies peers whose effective group is `gid`.
+    pub fn deny_gid(mut self, gid: u32) -> Self {
+        self.deny.push(Rule::Gid(gid));
+        self
+    }
+
+    /// Denies peers for which `predicate` returns true.
+    pub fn deny_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&PeerCredentials) -> bool + Send + Sync + 'static,
+    {
+        self.deny.push(Rule::Predicate(Arc::new(predicate)));
+        self
+    }
+
+    /// Whether the policy accepts connections from `peer`.
+    pub fn allows(&self, peer: &PeerCredentials) -> bool {
+        if self.deny.iter().any(|rule| rule.matches(peer)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AccessPolicy;
+    use crate::credentials::PeerCredentials;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::options::ServerOptions;
+    use crate::test_utils::{get_server_name, install_logger};
+    use crate::IpcError;
+
+    use tokio::runtime;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn peer(uid: u32, gid: u32) -> PeerCredentials {
+        PeerCredentials {
+            pid: Some(1),
+            uid: Some(uid),
+            gid: Some(gid),
+            session_id: None,
+        }
+    }
+
+    #[test]
+    fn deny_rules_take_precedence() {
+        assert!(AccessPolicy::new().allows(&peer(0, 0)));
+        assert!(AccessPolicy::new().allows(&PeerCredentials::default()));
+
+        let policy = AccessPolicy::new().allow_uid(1000).allow_gid(27).deny_if(|peer| peer.pid == Some(1));
+
+        assert!(!policy.allows(&peer(1000, 27)));
+
+        let policy = AccessPolicy::new().allow_uid(1000).allow_gid(27).deny_uid(1001);
+
+        assert!(policy.allows(&peer(1000, 0)));
+        assert!(policy.allows(&peer(0, 27)));
+        assert!(!policy.allows(&peer(0, 0)));
+        assert!(!policy.allows(&peer(1001, 27)));
+        assert!(!policy.allows(&PeerCredentials::default()));
+    }
+
+    #[test]
+    fn denied_peers_are_disconnected() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            // Both clients come from this process, so deny whichever connects first.
+            let denied_one = AtomicBool::new(false);
+            let policy = AccessPolicy::new().deny_if(move |_| !denied_one.swap(true, Ordering::SeqCst));
+
+            let options = ServerOptions::new().access_policy(policy);
+            let server = MessageIpcServer::with_options(&server_name, &options).unwrap();
+
+            let denied = MessageIpcClient::new(&server_name).unwrap();
+            let accept = tokio::spawn(server.wait_for_connection());
+
+            assert!(matches!(denied.read().await, Err(IpcError::PeerDisconnected)));
+
+            let allowed = MessageIpcClient::new(&server_name).unwrap();
+            let (connection, server) = accept.await.unwrap().unwrap();
+
+            allowed.write(b"hello").await.unwrap();
+            assert_eq!(connection.read().await.unwrap(), b"hello");
+            assert_eq!(server.stats().accepted, 1);
+        });
+    }
+}
@@ -0,0 +1,251 @@
+//! Mutual authentication of connections with a pre-shared key.
+//!
+//! Before any application messages flow, each end proves it knows the key without revealing
+//! it:
+//!
+//! 1. The server sends `PROTOCOL` followed by a random 32 byte nonce.
+//! 2. The client replies with its own nonce and
+//!    `HMAC-SHA256(key, "client" || server nonce || client nonce)`.
+//! 3. The server checks the client's MAC and, if it matches, replies with
+//!    `HMAC-SHA256(key, "server" || client nonce || server nonce)`. Otherwise it closes the
+//!    connection.
+//! 4. The client checks the server's MAC.
+//!
+//! Fresh nonces from both ends mean a recorded handshake can't be replayed, and the labels
+//! stop either MAC from being reflected back as the other.
+
+use crate::error::{IpcError, Result};
+use crate::ipc::MessageIpcConnection;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Identifies the handshake, so a peer that isn't expecting one fails clearly.
+const PROTOCOL: &[u8] = b"ipc-psk-hmac-sha256/1";
+
+const NONCE_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+
+/// The largest frame either end reads during the handshake, whatever the connection's
+/// `max_message_size`.
+pub(crate) const MAX_HANDSHAKE_FRAME: u64 = 64;
+
+/// How long a peer has to complete the handshake. Servers authenticate several clients at
+/// once, so this only bounds how long a silent client holds on to its connection.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A key shared by a server and its clients. Its `Debug` output doesn't include the key.
+#[derive(Clone)]
+pub(crate) struct PreSharedKey(Arc<[u8]>);
+
+impl PreSharedKey {
+    pub fn new(key: &[u8]) -> PreSharedKey {
+        PreSharedKey(key.into())
+    }
+
+    fn mac(&self, label: &[u8], first: &[u8], second: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(label);
+        mac.update(first);
+        mac.update(second);
+        mac
+    }
+}
+
+impl fmt::Debug for PreSharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PreSharedKey(..)")
+    }
+}
+
+/// Authenticates a client that just connected, failing with `AuthenticationFailed` if it
+/// doesn't know the key.
+pub(crate) async fn accept(connection: &MessageIpcConnection, key: &PreSharedKey) -> Result<()> {
+    with_timeout(async {
+        let server_nonce = nonce()?;
+        connection.write(&[PROTOCOL, &server_nonce[..]].concat()).await?;
+
+        let response = connection.read().await?;
+
+        if response.len() != NONCE_LEN + MAC_LEN {
+            return Err(IpcError::protocol_mismatch("Malformed authentication response"));
+        }
+
+        let (client_nonce, client_mac) = response.split_at(NONCE_LEN);
+
+        key.mac(b"client", &server_nonce, client_nonce)
+            .verify_slice(client_mac)
+            .map_err(|_| IpcError::AuthenticationFailed)?;
+
+        let server_mac = key.mac(b"server", client_nonce, &server_nonce).finalize().into_bytes();
+        connection.write(&server_mac).await
+    })
+    .await
+}
+
+/// Authenticates to the server we just connected to, and checks that it knows the key too.
+pub(crate) async fn connect(connection: &MessageIpcConnection, key: &PreSharedKey) -> Result<()> {
+    with_timeout(async {
+        let challenge = connection.read().await?;
+
+        let server_nonce = match challenge.strip_prefix(PROTOCOL) {
+            Some(nonce) if nonce.len() == NONCE_LEN => nonce,
+            _ => return Err(IpcError::protocol_mismatch("The server didn't ask to authenticate")),
+        };
+
+        let client_nonce = nonce()?;
+        let client_mac = key.mac(b"client", server_nonce, &client_nonce).finalize().into_bytes();
+        connection.write(&[&client_nonce[..], &client_mac[..]].concat()).await?;
+
+        // A server that rejects us hangs up rather than replying.
+        let server_mac = match connection.read().await {
+            Ok(mac) => mac,
+            Err(IpcError::PeerDisconnected) => return Err(IpcError::AuthenticationFailed),
+            Err(err) => return Err(err),
+        };
+
+        key.mac(b"server", &client_nonce, server_nonce)
+            .verify_slice(&server_mac)
+            .map_err(|_| IpcError::AuthenticationFailed)
+    })
+    .await
+}
+
+async fn with_timeout(handshake: impl std::future::Future<Output = Result<()>>) -> Result<()> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await.unwrap_or_else(|_| {
+        Err(IpcError::Io(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "The authentication handshake timed out",
+        )))
+    })
+}
+
+fn nonce() -> Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0; NONCE_LEN];
+
+    getrandom::fill(&mut nonce).map_err(|err| IpcError::Io(std::io::Error::other(err.to_string())))?;
+
+    Ok(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::IpcError;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer, RawIpcServer};
+    use crate::options::{ClientOptions, ServerOptions};
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+
+    use std::io::ErrorKind;
+    use std::time::Duration;
+
+    #[test]
+    fn clients_with_the_key_connect() {
+        install_logger();
+
+        let name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::with_options(&name, &ServerOptions::new().pre_shared_key(b"secret")).unwrap();
+
+            let accepted = tokio::spawn(async move {
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+                assert_eq!(connection.read().await.unwrap(), b"hello");
+                connection.write(b"welcome").await.unwrap();
+            });
+
+            let options = ClientOptions::new().pre_shared_key(b"secret");
+            let connection = MessageIpcClient::connect(&name, &options).await.unwrap();
+
+            connection.write(b"hello").await.unwrap();
+            assert_eq!(connection.read().await.unwrap(), b"welcome");
+
+            accepted.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn clients_without_the_key_are_disconnected() {
+        install_logger();
+
+        let name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::with_options(&name, &ServerOptions::new().pre_shared_key(b"secret")).unwrap();
+            let accepted = tokio::spawn(async move { server.wait_for_connection().await.unwrap().0.read().await.unwrap() });
+
+            let wrong_key = ClientOptions::new().pre_shared_key(b"guess");
+            assert!(matches!(MessageIpcClient::connect(&name, &wrong_key).await, Err(IpcError::AuthenticationFailed)));
+
+            // A client that doesn't authenticate at all sees the challenge, and nothing it
+            // sends reaches the application.
+            let unauthenticated = MessageIpcClient::new(&name).unwrap();
+            assert!(unauthenticated.read().await.unwrap().starts_with(super::PROTOCOL));
+            unauthenticated.write(b"let me in").await.unwrap();
+            assert!(matches!(unauthenticated.read().await, Err(IpcError::PeerDisconnected)));
+
+            let connection = MessageIpcClient::connect(&name, &ClientOptions::new().pre_shared_key(b"secret")).await.unwrap();
+            connection.write(b"authenticated").await.unwrap();
+
+            assert_eq!(accepted.await.unwrap(), b"authenticated");
+        });
+    }
+
+    #[test]
+    fn silent_clients_dont_hold_up_others() {
+        install_logger();
+
+        let name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::with_options(&name, &ServerOptions::new().pre_shared_key(b"secret")).unwrap();
+            let accepted = tokio::spawn(async move { server.wait_for_connection().await.unwrap().0.read().await.unwrap() });
+
+            // Connects, but never answers the challenge.
+            let _silent = MessageIpcClient::new(&name).unwrap();
+
+            let options = ClientOptions::new().pre_shared_key(b"secret");
+            let connect = MessageIpcClient::connect(&name, &options);
+            let connection = tokio::time::timeout(Duration::from_secs(2), connect).await.unwrap().unwrap();
+            connection.write(b"authenticated").await.unwrap();
+
+            let accepted = tokio::time::timeout(Duration::from_secs(2), accepted).await.unwrap();
+            assert_eq!(accepted.unwrap(), b"authenticated");
+        });
+    }
+
+    #[test]
+    fn constructors_that_cant_authenticate_reject_keys() {
+        install_logger();
+
+        let name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server_options = ServerOptions::new().pre_shared_key(b"secret");
+            let client_options = ClientOptions::new().pre_shared_key(b"secret");
+
+            assert!(matches!(RawIpcServer::with_options(&name, &server_options), Err(IpcError::Io(err)) if err.kind() == ErrorKind::InvalidInput));
+
+            let _server = MessageIpcServer::with_options(&name, &server_options).unwrap();
+
+            assert!(matches!(MessageIpcClient::with_options(&name, &client_options), Err(IpcError::Io(err)) if err.kind() == ErrorKind::InvalidInput));
+        });
+    }
+
+    #[test]
+    fn debug_output_hides_the_key() {
+        let options = ServerOptions::new().pre_shared_key(b"hunter2");
+
+        assert!(!format!("{:?}", options).contains("hunter2"));
+        assert!(!format!("{:?}", options).contains("104, 117"));
+    }
+}
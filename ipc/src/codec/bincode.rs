@@ -0,0 +1,21 @@
+use super::{CodecError, Decoder, Encoder};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Encodes values with bincode's default configuration. This is the default codec for typed
+/// connections, as it's compact and fast when both ends are Rust.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+impl<T: Serialize> Encoder<T> for BincodeCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(::bincode::serialize(value)?)
+    }
+}
+
+impl<T: DeserializeOwned> Decoder<T> for BincodeCodec {
+    fn decode(&self, data: &[u8]) -> Result<T, CodecError> {
+        Ok(::bincode::deserialize(data)?)
+    }
+}
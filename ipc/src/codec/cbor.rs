@@ -0,0 +1,24 @@
+use super::{CodecError, Decoder, Encoder};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Encodes values as CBOR (RFC 8949).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CborCodec;
+
+impl<T: Serialize> Encoder<T> for CborCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut data = vec![];
+
+        ciborium::into_writer(value, &mut data)?;
+
+        Ok(data)
+    }
+}
+
+impl<T: DeserializeOwned> Decoder<T> for CborCodec {
+    fn decode(&self, data: &[u8]) -> Result<T, CodecError> {
+        Ok(ciborium::from_reader(data)?)
+    }
+}
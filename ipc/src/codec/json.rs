@@ -0,0 +1,20 @@
+use super::{CodecError, Decoder, Encoder};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Encodes values as UTF-8 JSON, for talking to peers written in other languages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl<T: Serialize> Encoder<T> for JsonCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+}
+
+impl<T: DeserializeOwned> Decoder<T> for JsonCodec {
+    fn decode(&self, data: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
@@ -0,0 +1,121 @@
+//! Codecs that turn values into message bodies and back, for `TypedIpcConnection` and the
+//! `rpc` module.
+//!
+//! `BincodeCodec` is always available and is the default. `JsonCodec`, `MsgPackCodec`,
+//! `CborCodec` and `PostcardCodec` are behind the `json`, `msgpack`, `cbor` and `postcard`
+//! features, and `ProstCodec`, which encodes prost messages, is behind `protobuf`.
+
+mod bincode;
+#[cfg(feature = "cbor")]
+mod cbor;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+#[cfg(feature = "postcard")]
+mod postcard;
+#[cfg(feature = "protobuf")]
+mod prost;
+
+pub use self::bincode::BincodeCodec;
+#[cfg(feature = "cbor")]
+pub use self::cbor::CborCodec;
+#[cfg(feature = "json")]
+pub use self::json::JsonCodec;
+#[cfg(feature = "msgpack")]
+pub use self::msgpack::MsgPackCodec;
+#[cfg(feature = "postcard")]
+pub use self::postcard::PostcardCodec;
+#[cfg(feature = "protobuf")]
+pub use self::prost::ProstCodec;
+
+/// The error a codec returns when it fails to encode or decode a value.
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Converts values of type `T` into the bytes carried in a single message. Codecs only produce
+/// message bodies; framing is always done by `MessageIpcConnection`. Implementations should
+/// be cheap to clone, as servers hand a copy to every connection they accept.
+pub trait Encoder<T> {
+    /// Serializes `value` into the body of a message.
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+}
+
+/// Converts the bytes of a single message back into a value of type `T`.
+pub trait Decoder<T> {
+    /// Deserializes a value from the body of a message.
+    fn decode(&self, data: &[u8]) -> Result<T, CodecError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::{Deserialize, Serialize};
+
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        id: u64,
+        name: String,
+        tags: Vec<String>,
+        attributes: BTreeMap<String, i32>,
+        parent: Option<Box<Sample>>,
+    }
+
+    fn sample() -> Sample {
+        let mut attributes = BTreeMap::new();
+        attributes.insert("legs".to_owned(), 4);
+
+        Sample {
+            id: 42,
+            name: "horse".to_owned(),
+            tags: vec!["hay".to_owned(), "oats".to_owned()],
+            attributes,
+            parent: Some(Box::new(Sample {
+                id: 1,
+                name: "pony".to_owned(),
+                tags: vec![],
+                attributes: BTreeMap::new(),
+                parent: None,
+            })),
+        }
+    }
+
+    fn assert_round_trips<C: Encoder<Sample> + Decoder<Sample>>(codec: C) {
+        let data = codec.encode(&sample()).unwrap();
+        let decoded: Sample = codec.decode(&data).unwrap();
+
+        assert_eq!(decoded, sample());
+        assert!(codec.decode(&data[..data.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn bincode_round_trips() {
+        assert_round_trips(BincodeCodec);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trips() {
+        assert_round_trips(JsonCodec);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips() {
+        assert_round_trips(MsgPackCodec);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trips() {
+        assert_round_trips(CborCodec);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_round_trips() {
+        assert_round_trips(PostcardCodec);
+    }
+}
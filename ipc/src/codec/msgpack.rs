@@ -0,0 +1,21 @@
+use super::{CodecError, Decoder, Encoder};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Encodes values as MessagePack. Structs are written as maps keyed by field name rather than
+/// arrays so they decode naturally as objects in Python and Node.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MsgPackCodec;
+
+impl<T: Serialize> Encoder<T> for MsgPackCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+}
+
+impl<T: DeserializeOwned> Decoder<T> for MsgPackCodec {
+    fn decode(&self, data: &[u8]) -> Result<T, CodecError> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
@@ -0,0 +1,21 @@
+use super::{CodecError, Decoder, Encoder};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Encodes values with postcard, a compact format suited to peers on embedded or `no_std`
+/// targets.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PostcardCodec;
+
+impl<T: Serialize> Encoder<T> for PostcardCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(::postcard::to_allocvec(value)?)
+    }
+}
+
+impl<T: DeserializeOwned> Decoder<T> for PostcardCodec {
+    fn decode(&self, data: &[u8]) -> Result<T, CodecError> {
+        Ok(::postcard::from_bytes(data)?)
+    }
+}
@@ -0,0 +1,20 @@
+use super::{CodecError, Decoder, Encoder};
+
+use ::prost::Message;
+
+/// Encodes protobuf messages generated by prost. Unlike the other codecs, this works with
+/// `prost::Message` types rather than serde.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProstCodec;
+
+impl<T: Message> Encoder<T> for ProstCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(value.encode_to_vec())
+    }
+}
+
+impl<T: Message + Default> Decoder<T> for ProstCodec {
+    fn decode(&self, data: &[u8]) -> Result<T, CodecError> {
+        Ok(T::decode(data)?)
+    }
+}
@@ -0,0 +1,20 @@
+/// Who is on the other end of a connection, as reported by the OS when the connection was
+/// made. Servers see the client; clients see the server.
+///
+/// On Unix the credentials come from the socket (`SO_PEERCRED` on Linux), and are those the
+/// peer had when it connected or started listening. On Windows, pipes only report the peer's
+/// process and session ids.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// The peer's process id. Not available on every Unix.
+    pub pid: Option<u32>,
+
+    /// The peer's effective user id. Unix only.
+    pub uid: Option<u32>,
+
+    /// The peer's effective group id. Unix only.
+    pub gid: Option<u32>,
+
+    /// The id of the peer's Terminal Services session. Windows only.
+    pub session_id: Option<u32>,
+}
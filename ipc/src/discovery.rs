@@ -0,0 +1,222 @@
+#[cfg(unix)]
+use super::unix::{list_endpoints as find_endpoints, wait_for_endpoint as wait_for};
+#[cfg(windows)]
+use super::windows::{list_endpoints as find_endpoints, wait_for_endpoint as wait_for};
+use crate::credentials::PeerCredentials;
+use crate::error::{IpcError, Result};
+use crate::name::{IntoIpcName, IpcName};
+
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// An endpoint found by `list_endpoints`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EndpointInfo {
+    pub name: IpcName,
+
+    /// What we could find out about the server that owns the endpoint, without connecting to
+    /// it. On Unix, this is the socket file's uid and gid, and the pid of the server holding
+    /// its lock. On Windows, it's the session id of session-scoped endpoints.
+    pub owner: PeerCredentials,
+}
+
+/// Lists the endpoints whose names start with `prefix`, in every scope visible to the current
+/// user, sorted by name. This includes the abstract socket namespace on Linux.
+///
+/// Endpoints are found by looking at the filesystem or pipe namespace rather than by
+/// connecting to them, so a socket left behind by a server that crashed is listed until
+/// another server replaces it. Endpoints that other users have scoped to themselves aren't
+/// listed, nor are names `IpcName` can't represent. On Unix, only sockets with the lock file
+/// our servers create next to them are listed, so other programs' sockets in the same
+/// directories aren't.
+///
+/// ```no_run
+/// for endpoint in ipc::list_endpoints("my_app/")? {
+///     println!("{} ({:?}), pid {:?}", endpoint.name, endpoint.name.scope(), endpoint.owner.pid);
+/// }
+/// # Ok::<(), ipc::IpcError>(())
+/// ```
+pub fn list_endpoints(prefix: &str) -> Result<Vec<EndpointInfo>> {
+    let mut endpoints = find_endpoints(prefix)?;
+    endpoints.sort_by(|a, b| a.name.name().cmp(b.name.name()));
+
+    Ok(endpoints)
+}
+
+/// Waits for a server to start listening on `name`, failing with `TimedOut` if none has
+/// within `timeout`. Returns straight away if one already is.
+///
+/// On Linux, the socket's directory is watched with inotify, so this returns as soon as the
+/// server is ready. Abstract names, Windows pipes and sockets on other platforms are polled
+/// for instead.
+///
+/// ```no_run
+/// # async fn run() -> ipc::Result<()> {
+/// ipc::wait_for_endpoint("my_daemon", std::time::Duration::from_secs(5)).await?;
+/// let connection = ipc::MessageIpcClient::new("my_daemon")?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn wait_for_endpoint(name: impl IntoIpcName, timeout: Duration) -> Result<()> {
+    let name = name.into_ipc_name()?;
+
+    match tokio::time::timeout(timeout, wait_for(&name)).await {
+        Ok(result) => result,
+        Err(_) => Err(IpcError::Io(std::io::Error::new(
+            ErrorKind::TimedOut,
+            format!("Timed out waiting for a server on {}", name),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list_endpoints, wait_for_endpoint};
+    use crate::error::IpcError;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::name::{IpcName, Scope};
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+
+    use std::io::ErrorKind;
+    use std::time::Duration;
+
+    #[test]
+    fn lists_endpoints_under_a_prefix() {
+        install_logger();
+
+        let prefix = format!("{}/", get_server_name());
+        let name = |name: &str| format!("{}{}", prefix, name);
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let _user = MessageIpcServer::new(name("user")).unwrap();
+            let _nested = MessageIpcServer::new(name("nested/server")).unwrap();
+            let _session = MessageIpcServer::new(IpcName::session(&name("session")).unwrap()).unwrap();
+            let _global = MessageIpcServer::new(IpcName::global(&name("global")).unwrap()).unwrap();
+            let _other = MessageIpcServer::new(get_server_name()).unwrap();
+
+            // Another program's socket, which has no lock file.
+            #[cfg(unix)]
+            let _foreign = {
+                let (directory, _) = crate::unix::endpoint::directory(Scope::User).unwrap();
+                std::os::unix::net::UnixListener::bind(directory.join(name("foreign"))).unwrap()
+            };
+
+            let endpoints = list_endpoints(&prefix).unwrap();
+            let names = endpoints.iter().map(|e| (e.name.name(), e.name.scope())).collect::<Vec<_>>();
+
+            assert_eq!(
+                names,
+                vec![
+                    (&*name("global"), Scope::Global),
+                    (&*name("nested/server"), Scope::User),
+                    (&*name("session"), Scope::Session),
+                    (&*name("user"), Scope::User),
+                ]
+            );
+
+            #[cfg(unix)]
+            for endpoint in &endpoints {
+                assert_eq!(endpoint.owner.pid, Some(std::process::id()));
+                assert_eq!(endpoint.owner.uid, Some(unsafe { libc::geteuid() }));
+            }
+
+            assert_eq!(list_endpoints(&name("nested/")).unwrap().len(), 1);
+        });
+
+        assert!(list_endpoints(&prefix).unwrap().is_empty());
+
+        #[cfg(unix)]
+        for scope in &[Scope::Global, Scope::User, Scope::Session] {
+            let (directory, _) = crate::unix::endpoint::directory(*scope).unwrap();
+            std::fs::remove_dir_all(directory.join(&prefix)).unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn lists_abstract_endpoints() {
+        install_logger();
+
+        let name = IpcName::new(&get_server_name()).unwrap().in_abstract_namespace();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let _server = MessageIpcServer::new(&name).unwrap();
+
+            // Other tests' names may share our prefix.
+            assert!(list_endpoints(name.name()).unwrap().iter().any(|endpoint| endpoint.name == name));
+        });
+    }
+
+    #[test]
+    fn waits_for_servers_to_start() {
+        install_logger();
+
+        let directory = get_server_name();
+        // The server creates the directory, so the wait starts out watching its parent.
+        let name = IpcName::new(&format!("{}/nested/daemon", directory)).unwrap();
+        let abstract_name = IpcName::new(&get_server_name()).unwrap().in_abstract_namespace();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            for name in &[name.clone(), abstract_name] {
+                let server_name = name.clone();
+
+                let server = tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+
+                    let server = MessageIpcServer::new(&server_name).unwrap();
+                    server.wait_for_connection().await.unwrap();
+                });
+
+                wait_for_endpoint(name, Duration::from_secs(10)).await.unwrap();
+                let _client = MessageIpcClient::new(name).unwrap();
+
+                server.await.unwrap();
+            }
+        });
+
+        #[cfg(unix)]
+        {
+            let (user_dir, _) = crate::unix::endpoint::directory(Scope::User).unwrap();
+            std::fs::remove_dir_all(user_dir.join(&directory)).unwrap();
+        }
+    }
+
+    #[test]
+    fn waiting_for_an_endpoint_times_out() {
+        install_logger();
+
+        let name = IpcName::new(&get_server_name()).unwrap();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            // A socket file without a live server holding its lock isn't an endpoint.
+            #[cfg(unix)]
+            let _stale = {
+                use crate::unix::endpoint::Endpoint;
+
+                let endpoint = Endpoint::resolve(&name).unwrap();
+                endpoint.create_parent_dirs(0o700).unwrap();
+
+                let path = match endpoint {
+                    Endpoint::Path { path, .. } => path,
+                    endpoint => panic!("Unexpected endpoint {:?}", endpoint),
+                };
+
+                (std::os::unix::net::UnixListener::bind(&path).unwrap(), path)
+            };
+
+            match wait_for_endpoint(&name, Duration::from_millis(100)).await {
+                Err(IpcError::Io(err)) if err.kind() == ErrorKind::TimedOut => {}
+                result => panic!("Unexpected result {:?}", result),
+            }
+
+            #[cfg(unix)]
+            std::fs::remove_file(&_stale.1).unwrap();
+        });
+    }
+}
@@ -0,0 +1,216 @@
+#[cfg(windows)]
+use winapi::shared::winerror::{ERROR_BROKEN_PIPE, ERROR_NO_DATA, ERROR_PIPE_NOT_CONNECTED};
+
+use crate::codec::CodecError;
+
+use std::error::Error;
+use std::fmt;
+use std::io::ErrorKind;
+
+/// The error type returned by every IPC operation.
+#[derive(Debug)]
+pub enum IpcError {
+    /// The other end closed the connection.
+    PeerDisconnected,
+
+    /// Another server already owns the requested name.
+    AddrInUse,
+
+    /// A message exceeded the maximum message size configured on the connection.
+    FrameTooLarge {
+        size: u64,
+        max: u64,
+    },
+
+    /// The peer sent data that doesn't follow the expected protocol.
+    ProtocolMismatch(String),
+
+    /// The peer couldn't prove it knows the pre-shared key, or rejected our proof.
+    AuthenticationFailed,
+
+    /// The operation was abandoned before it completed.
+    Cancelled,
+
+    /// A call's deadline passed before it completed.
+    DeadlineExceeded,
+
+    /// A value couldn't be serialized into a message.
+    Encode(CodecError),
+
+    /// A message couldn't be deserialized into the expected type. The transport is still
+    /// healthy, so later messages may decode fine.
+    Decode(CodecError),
+
+    /// The peer failed to handle a request and replied with this description.
+    Remote(String),
+
+    /// Any other I/O error reported by the OS.
+    Io(std::io::Error),
+}
+
+/// A specialized `Result` for IPC operations.
+pub type Result<T> = std::result::Result<T, IpcError>;
+
+impl IpcError {
+    /// Creates a `ProtocolMismatch` error with the given description.
+    pub fn protocol_mismatch<S: Into<String>>(description: S) -> IpcError {
+        IpcError::ProtocolMismatch(description.into())
+    }
+
+    /// A copy of this error to report to each of several waiters, e.g. every pending call on a
+    /// connection that failed. OS errors are flattened to their kind and description.
+    pub(crate) fn duplicate(&self) -> IpcError {
+        match self {
+            IpcError::PeerDisconnected => IpcError::PeerDisconnected,
+            IpcError::AddrInUse => IpcError::AddrInUse,
+            IpcError::FrameTooLarge { size, max } => IpcError::FrameTooLarge { size: *size, max: *max },
+            IpcError::ProtocolMismatch(description) => IpcError::ProtocolMismatch(description.clone()),
+            IpcError::AuthenticationFailed => IpcError::AuthenticationFailed,
+            IpcError::Cancelled => IpcError::Cancelled,
+            IpcError::DeadlineExceeded => IpcError::DeadlineExceeded,
+            IpcError::Remote(description) => IpcError::Remote(description.clone()),
+            err => IpcError::Io(std::io::Error::new(err.kind(), err.to_string())),
+        }
+    }
+
+    /// The description sent to a peer whose request failed with this error.
+    pub(crate) fn into_remote_description(self) -> String {
+        match self {
+            IpcError::Remote(description) => description,
+            err => err.to_string(),
+        }
+    }
+
+    /// The `std::io::ErrorKind` that best describes this error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            IpcError::PeerDisconnected => ErrorKind::BrokenPipe,
+            IpcError::AddrInUse => ErrorKind::AddrInUse,
+            IpcError::FrameTooLarge { .. } => ErrorKind::InvalidData,
+            IpcError::ProtocolMismatch(_) => ErrorKind::InvalidData,
+            IpcError::AuthenticationFailed => ErrorKind::PermissionDenied,
+            IpcError::Cancelled => ErrorKind::Interrupted,
+            IpcError::DeadlineExceeded => ErrorKind::TimedOut,
+            IpcError::Encode(_) => ErrorKind::InvalidInput,
+            IpcError::Decode(_) => ErrorKind::InvalidData,
+            IpcError::Remote(_) => ErrorKind::Other,
+            IpcError::Io(err) => err.kind(),
+        }
+    }
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpcError::PeerDisconnected => write!(f, "The peer closed the connection"),
+            IpcError::AddrInUse => write!(f, "Another server already owns this name"),
+            IpcError::FrameTooLarge { size, max } => {
+                write!(f, "Message of {} bytes exceeds the maximum message size of {} bytes", size, max)
+            }
+            IpcError::ProtocolMismatch(description) => write!(f, "Protocol mismatch: {}", description),
+            IpcError::AuthenticationFailed => write!(f, "The peer failed to authenticate"),
+            IpcError::Cancelled => write!(f, "The operation was cancelled"),
+            IpcError::DeadlineExceeded => write!(f, "The call's deadline passed"),
+            IpcError::Encode(err) => write!(f, "Failed to encode message: {}", err),
+            IpcError::Decode(err) => write!(f, "Failed to decode message: {}", err),
+            IpcError::Remote(description) => write!(f, "The peer failed to handle the request: {}", description),
+            IpcError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for IpcError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IpcError::Io(err) => Some(err),
+            IpcError::Encode(err) => Some(err.as_ref()),
+            IpcError::Decode(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(windows)]
+fn is_disconnect_os_error(err: &std::io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => {
+            let code = code as u32;
+
+            code == ERROR_BROKEN_PIPE || code == ERROR_NO_DATA || code == ERROR_PIPE_NOT_CONNECTED
+        }
+        None => false,
+    }
+}
+
+#[cfg(not(windows))]
+fn is_disconnect_os_error(_err: &std::io::Error) -> bool {
+    false
+}
+
+impl From<std::io::Error> for IpcError {
+    fn from(err: std::io::Error) -> IpcError {
+        // Round trip errors that were converted into an io::Error by the impl below.
+        if err.get_ref().is_some_and(|inner| inner.is::<IpcError>()) {
+            let inner = err.into_inner().unwrap();
+
+            return *inner.downcast::<IpcError>().unwrap();
+        }
+
+        if is_disconnect_os_error(&err) {
+            return IpcError::PeerDisconnected;
+        }
+
+        match err.kind() {
+            ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::UnexpectedEof => IpcError::PeerDisconnected,
+            ErrorKind::AddrInUse => IpcError::AddrInUse,
+            _ => IpcError::Io(err),
+        }
+    }
+}
+
+impl From<IpcError> for std::io::Error {
+    fn from(err: IpcError) -> std::io::Error {
+        match err {
+            IpcError::Io(err) => err,
+            err => std::io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpcError;
+
+    use std::io::ErrorKind;
+
+    #[test]
+    fn classifies_io_errors() {
+        let err = IpcError::from(std::io::Error::from(ErrorKind::BrokenPipe));
+        assert!(matches!(err, IpcError::PeerDisconnected));
+
+        let err = IpcError::from(std::io::Error::from(ErrorKind::AddrInUse));
+        assert!(matches!(err, IpcError::AddrInUse));
+
+        let err = IpcError::from(std::io::Error::from(ErrorKind::PermissionDenied));
+        assert!(matches!(err, IpcError::Io(_)));
+    }
+
+    #[test]
+    fn round_trips_through_io_error() {
+        let err = std::io::Error::from(IpcError::FrameTooLarge { size: 17, max: 16 });
+
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        match IpcError::from(err) {
+            IpcError::FrameTooLarge { size, max } => {
+                assert_eq!(size, 17);
+                assert_eq!(max, 16);
+            }
+            err => panic!("Unexpected error {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::error::{IpcError, Result};
+
+use tokio::sync::Notify;
+
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+/// How far a receiver lets its peer send ahead of what it has read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Window {
+    pub messages: u32,
+    pub bytes: u64,
+}
+
+struct SendState {
+    messages: u64,
+
+    /// May go negative, since a message is sent whenever any byte credit remains.
+    bytes: i64,
+
+    /// Set once the peer can no longer grant credit.
+    failed: Option<IpcError>,
+}
+
+/// The credit the peer has granted us to send with. There's none until the peer's first grant.
+pub(crate) struct SendCredit {
+    state: Mutex<SendState>,
+    granted: Notify,
+}
+
+impl SendCredit {
+    pub fn new() -> SendCredit {
+        SendCredit {
+            state: Mutex::new(SendState {
+                messages: 0,
+                bytes: 0,
+                failed: None,
+            }),
+            granted: Notify::new(),
+        }
+    }
+
+    /// Waits for credit to send a message of `len` bytes, then takes it. Messages larger than
+    /// the peer's window still get through, one at a time, once all earlier ones are read.
+    pub async fn acquire(&self, len: usize) -> Result<()> {
+        loop {
+            let granted = self.granted.notified();
+            futures::pin_mut!(granted);
+
+            // Register for the notification before checking, so a grant between the two
+            // isn't missed.
+            granted.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if let Some(err) = &state.failed {
+                    return Err(err.duplicate());
+                }
+
+                if state.messages > 0 && state.bytes > 0 {
+                    state.messages -= 1;
+                    state.bytes = state.bytes.saturating_sub(i64::try_from(len).unwrap_or(i64::MAX));
+
+                    return Ok(());
+                }
+            }
+
+            granted.await;
+        }
+    }
+
+    pub fn grant(&self, messages: u32, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.messages = state.messages.saturating_add(messages as u64);
+        state.bytes = state.bytes.saturating_add(i64::try_from(bytes).unwrap_or(i64::MAX));
+
+        self.granted.notify_waiters();
+    }
+
+    /// Fails every current and future `acquire` with `err`.
+    pub fn fail(&self, err: &IpcError) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.failed.is_none() {
+            state.failed = Some(err.duplicate());
+        }
+
+        self.granted.notify_waiters();
+    }
+}
+
+/// Tracks what we've read since we last granted the peer credit.
+pub(crate) struct ReceiveCredit {
+    window: Window,
+    messages: u32,
+    bytes: u64,
+
+    /// The credit we've granted the peer that it hasn't yet used, mirroring its `SendCredit`.
+    /// Only kept up to date by users that call `granted_window` and `received`.
+    unused_messages: u64,
+    unused_bytes: i64,
+}
+
+impl ReceiveCredit {
+    pub fn new(window: Window) -> ReceiveCredit {
+        ReceiveCredit {
+            window,
+            messages: 0,
+            bytes: 0,
+            unused_messages: 0,
+            unused_bytes: 0,
+        }
+    }
+
+    /// Records that the peer has been granted our whole window, returning the window.
+    pub fn granted_window(&mut self) -> Window {
+        self.granted(self.window);
+        self.window
+    }
+
+    /// Charges a message of `len` bytes from the peer against the credit we've granted it,
+    /// failing if it had none left.
+    pub fn received(&mut self, len: usize) -> Result<()> {
+        if self.unused_messages == 0 || self.unused_bytes <= 0 {
+            return Err(IpcError::protocol_mismatch("Peer sent beyond its window"));
+        }
+
+        self.unused_messages -= 1;
+        self.unused_bytes = self.unused_bytes.saturating_sub(i64::try_from(len).unwrap_or(i64::MAX));
+
+        Ok(())
+    }
+
+    /// Records that a message of `len` bytes was read, returning the credit to grant the peer
+    /// once enough has built up. Grants are batched at half the window rather than sent for
+    /// every message.
+    pub fn consumed(&mut self, len: usize) -> Option<Window> {
+        self.messages += 1;
+        self.bytes = self.bytes.saturating_add(len as u64);
+
+        if self.messages < (self.window.messages / 2).max(1) && self.bytes < (self.window.bytes / 2).max(1) {
+            return None;
+        }
+
+        let grant = Window {
+            messages: self.messages,
+            bytes: self.bytes,
+        };
+
+        self.messages = 0;
+        self.bytes = 0;
+
+        // Recorded before it's sent, so the peer can never have used credit we don't yet know
+        // about.
+        self.granted(grant);
+
+        Some(grant)
+    }
+
+    fn granted(&mut self, credit: Window) {
+        self.unused_messages = self.unused_messages.saturating_add(credit.messages as u64);
+        self.unused_bytes = self.unused_bytes.saturating_add(i64::try_from(credit.bytes).unwrap_or(i64::MAX));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReceiveCredit, SendCredit, Window};
+    use crate::error::IpcError;
+
+    use futures::FutureExt;
+
+    #[test]
+    fn credit_is_granted_in_batches() {
+        let mut credit = ReceiveCredit::new(Window { messages: 4, bytes: 1000 });
+
+        assert_eq!(credit.consumed(10), None);
+        assert_eq!(credit.consumed(10), Some(Window { messages: 2, bytes: 20 }));
+
+        // A large message is granted back straight away.
+        assert_eq!(credit.consumed(600), Some(Window { messages: 1, bytes: 600 }));
+    }
+
+    #[test]
+    fn receiving_is_charged_against_the_grants() {
+        let mut credit = ReceiveCredit::new(Window { messages: 2, bytes: 1000 });
+
+        assert!(credit.received(1).is_err());
+        assert_eq!(credit.granted_window(), Window { messages: 2, bytes: 1000 });
+
+        // Like sending, any byte credit lets a message through, however large.
+        credit.received(10).unwrap();
+        credit.received(2000).unwrap();
+
+        match credit.received(1) {
+            Err(IpcError::ProtocolMismatch(_)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        };
+
+        assert_eq!(credit.consumed(10), Some(Window { messages: 1, bytes: 10 }));
+        assert!(credit.received(1).is_err());
+
+        assert_eq!(credit.consumed(2000), Some(Window { messages: 1, bytes: 2000 }));
+        credit.received(1).unwrap();
+    }
+
+    #[test]
+    fn sending_waits_for_credit() {
+        let credit = SendCredit::new();
+
+        assert!(credit.acquire(1).now_or_never().is_none());
+
+        credit.grant(2, 10);
+
+        // Any byte credit lets a message through, however large.
+        assert!(credit.acquire(100).now_or_never().unwrap().is_ok());
+        assert!(credit.acquire(1).now_or_never().is_none());
+
+        credit.grant(0, 100);
+        assert!(credit.acquire(1).now_or_never().unwrap().is_ok());
+        assert!(credit.acquire(1).now_or_never().is_none());
+
+        credit.fail(&IpcError::PeerDisconnected);
+
+        match credit.acquire(1).now_or_never() {
+            Some(Err(IpcError::PeerDisconnected)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        };
+    }
+}
@@ -0,0 +1,100 @@
+use crate::error::{IpcError, Result};
+use crate::ipc::MessageIpcConnection;
+
+const KIND_DATA: u8 = 0;
+const KIND_CREDIT: u8 = 1;
+
+/// One message on a flow controlled connection. Every frame starts with a kind byte, followed
+/// by a kind-specific payload.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    /// Carries one of the application's messages. The payload is the message.
+    Data { body: Vec<u8> },
+
+    /// Grants the receiver of the frame credit to send more. The payload is the little-endian
+    /// u32 number of messages followed by the little-endian u64 number of bytes.
+    Credit { messages: u32, bytes: u64 },
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Frame::Data { body } => {
+                let mut data = Vec::with_capacity(1 + body.len());
+                data.push(KIND_DATA);
+                data.extend_from_slice(body);
+
+                data
+            }
+            Frame::Credit { messages, bytes } => {
+                let mut data = Vec::with_capacity(13);
+                data.push(KIND_CREDIT);
+                data.extend_from_slice(&messages.to_le_bytes());
+                data.extend_from_slice(&bytes.to_le_bytes());
+
+                data
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Frame> {
+        let (kind, payload) = match data.split_first() {
+            Some((kind, payload)) => (*kind, payload),
+            None => return Err(IpcError::protocol_mismatch("Flow control frame is empty")),
+        };
+
+        match kind {
+            KIND_DATA => Ok(Frame::Data { body: payload.to_vec() }),
+            KIND_CREDIT => {
+                let (messages, bytes) = decode_credit(payload)?;
+
+                Ok(Frame::Credit { messages, bytes })
+            }
+            kind => Err(IpcError::protocol_mismatch(format!("Unknown flow control frame kind {}", kind))),
+        }
+    }
+
+    pub async fn write(&self, connection: &MessageIpcConnection) -> Result<()> {
+        connection.write(&self.encode()).await
+    }
+}
+
+/// Decodes the payload of a credit grant, which multiplexer frames share.
+pub(crate) fn decode_credit(payload: &[u8]) -> Result<(u32, u64)> {
+    if payload.len() != 12 {
+        return Err(IpcError::protocol_mismatch("Credit grant is the wrong size"));
+    }
+
+    let mut messages = [0; 4];
+    let mut bytes = [0; 8];
+    messages.copy_from_slice(&payload[..4]);
+    bytes.copy_from_slice(&payload[4..]);
+
+    Ok((u32::from_le_bytes(messages), u64::from_le_bytes(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Frame;
+    use crate::error::IpcError;
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = vec![
+            Frame::Data { body: vec![] },
+            Frame::Data { body: vec![1, 2, 3] },
+            Frame::Credit { messages: 7, bytes: u64::MAX },
+        ];
+
+        for frame in frames {
+            assert_eq!(Frame::decode(&frame.encode()).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert!(matches!(Frame::decode(&[]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[9]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[1, 0, 0, 0]), Err(IpcError::ProtocolMismatch(_))));
+    }
+}
@@ -0,0 +1,513 @@
+//! Credit-based flow control over a message connection.
+//!
+//! The receiver grants its peer credit for a window of messages and bytes, and grants more as
+//! it reads. A sender that runs out of credit waits instead of filling the OS buffers, and
+//! outgoing messages wait in a bounded queue whose fullness the sender can watch.
+
+pub(crate) mod credit;
+mod frame;
+
+pub(crate) use self::frame::decode_credit;
+
+use self::credit::{ReceiveCredit, SendCredit, Window};
+use self::frame::Frame;
+use crate::error::{IpcError, Result};
+use crate::ipc::MessageIpcConnection;
+
+use futures::channel::mpsc as unbounded;
+use futures::future::{self, Either};
+use futures::lock::Mutex as AsyncMutex;
+use futures::StreamExt;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tracing::trace;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The number of messages a receiver lets its peer send ahead of what it has read, unless
+/// configured otherwise.
+pub const DEFAULT_WINDOW_MESSAGES: u32 = 64;
+
+/// The number of bytes a receiver lets its peer send ahead of what it has read, unless
+/// configured otherwise.
+pub const DEFAULT_WINDOW_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Options controlling a `FlowControlledConnection`. Each end advertises its own window, so
+/// the two ends needn't agree.
+///
+/// ```no_run
+/// # use ipc::MessageIpcClient;
+/// # use ipc::flow::{FlowControl, FlowControlledConnection};
+/// # async fn connect() -> ipc::Result<()> {
+/// let options = FlowControl::new()
+///     .window_messages(16)
+///     .queue_capacity(4);
+///
+/// let connection = FlowControlledConnection::with_options(MessageIpcClient::new("my_server")?, &options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct FlowControl {
+    pub(crate) window: Window,
+    pub(crate) queue_capacity: usize,
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        FlowControl {
+            window: Window {
+                messages: DEFAULT_WINDOW_MESSAGES,
+                bytes: DEFAULT_WINDOW_BYTES,
+            },
+            queue_capacity: 64,
+        }
+    }
+}
+
+impl FlowControl {
+    /// Creates the default options: a window of `DEFAULT_WINDOW_MESSAGES` messages and
+    /// `DEFAULT_WINDOW_BYTES` bytes, and room to queue 64 messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many messages the peer may send before we read them. Passing 0 is treated as 1.
+    pub fn window_messages(mut self, messages: u32) -> Self {
+        self.window.messages = messages.max(1);
+        self
+    }
+
+    /// How many bytes the peer may send before we read them. A message larger than this is
+    /// still accepted once everything before it has been read. Passing 0 is treated as 1.
+    pub fn window_bytes(mut self, bytes: u64) -> Self {
+        self.window.bytes = bytes.max(1);
+        self
+    }
+
+    /// How many outgoing messages may wait for credit before `write` waits too. Passing 0 is
+    /// treated as 1.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+}
+
+/// The state of the outbound queue, shared with the task draining it.
+struct QueueState {
+    /// Messages queued but not yet written to the connection.
+    unsent: AtomicUsize,
+    flushed: Notify,
+
+    /// Why the queue stopped draining, if it did.
+    failed: Mutex<Option<IpcError>>,
+}
+
+impl QueueState {
+    fn error(&self) -> IpcError {
+        match self.failed.lock().unwrap().as_ref() {
+            Some(err) => err.duplicate(),
+            None => IpcError::PeerDisconnected,
+        }
+    }
+
+    fn sent(&self) {
+        if self.unsent.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.flushed.notify_waiters();
+        }
+    }
+
+    fn fail(&self, err: IpcError) {
+        *self.failed.lock().unwrap() = Some(err);
+        self.flushed.notify_waiters();
+    }
+}
+
+/// A `MessageIpcConnection` whose writer can't get further ahead of its reader than the
+/// reader allows. Both ends of the connection need to be flow controlled.
+///
+/// Writes go into a bounded queue, which a background task drains as the peer grants credit;
+/// `write` only waits when the queue is full. Dropping the connection discards whatever is
+/// still queued, so `flush` first to make sure it's sent.
+///
+/// A peer that sends beyond the window we've granted it fails the connection with
+/// `IpcError::ProtocolMismatch`, so at most a window of messages waits to be read.
+pub struct FlowControlledConnection {
+    connection: Arc<MessageIpcConnection>,
+    incoming: AsyncMutex<mpsc::Receiver<Result<Vec<u8>>>>,
+    receive_credit: Arc<Mutex<ReceiveCredit>>,
+    grants: unbounded::UnboundedSender<Window>,
+    queue: mpsc::Sender<Vec<u8>>,
+    queue_state: Arc<QueueState>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl FlowControlledConnection {
+    /// Adds flow control with the default options. Must be called within a tokio runtime,
+    /// which runs the tasks reading from and writing to the connection.
+    pub fn new(connection: MessageIpcConnection) -> Result<FlowControlledConnection> {
+        FlowControlledConnection::with_options(connection, &FlowControl::default())
+    }
+
+    pub fn with_options(connection: MessageIpcConnection, options: &FlowControl) -> Result<FlowControlledConnection> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            IpcError::Io(std::io::Error::other("Flow controlled connections must be created within a tokio runtime"))
+        })?;
+
+        let connection = Arc::new(connection);
+        let send_credit = Arc::new(SendCredit::new());
+
+        let queue_state = Arc::new(QueueState {
+            unsent: AtomicUsize::new(0),
+            flushed: Notify::new(),
+            failed: Mutex::new(None),
+        });
+
+        // The peer can't send more than our window before we read it, and there's room for the
+        // error that ends the connection on top.
+        let (incoming_tx, incoming) = mpsc::channel(options.window.messages as usize + 1);
+        let (queue, queued) = mpsc::channel(options.queue_capacity);
+
+        // The peer can't send anything until we grant it our window.
+        let mut receive_credit = ReceiveCredit::new(options.window);
+        let (grants, granted) = unbounded::unbounded();
+        let _ = grants.unbounded_send(receive_credit.granted_window());
+
+        let receive_credit = Arc::new(Mutex::new(receive_credit));
+
+        let reader = runtime.spawn(read_frames(
+            connection.clone(),
+            receive_credit.clone(),
+            send_credit.clone(),
+            incoming_tx,
+        ));
+        let writer = runtime.spawn(write_queued(
+            connection.clone(),
+            granted,
+            send_credit,
+            queued,
+            queue_state.clone(),
+        ));
+
+        Ok(FlowControlledConnection {
+            connection,
+            incoming: AsyncMutex::new(incoming),
+            receive_credit,
+            grants,
+            queue,
+            queue_state,
+            reader,
+            writer,
+        })
+    }
+
+    /// Reads the next message, granting the peer credit to send more.
+    pub async fn read(&self) -> Result<Vec<u8>> {
+        let message = {
+            let mut incoming = self.incoming.lock().await;
+
+            match incoming.recv().await {
+                Some(message) => message?,
+                None => return Err(IpcError::PeerDisconnected),
+            }
+        };
+
+        let grant = self.receive_credit.lock().unwrap().consumed(message.len());
+
+        if let Some(grant) = grant {
+            // The writer only stops once the connection has failed, which the next read
+            // reports.
+            let _ = self.grants.unbounded_send(grant);
+        }
+
+        Ok(message)
+    }
+
+    /// Queues `data` to be sent as one message, waiting for room if the queue is full.
+    /// Succeeding doesn't mean the message has been sent; see `flush`.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // The kind byte counts towards the connection's limit, and a message over it would
+        // fail the writer rather than this call.
+        self.connection.check_message_size(data.len() as u64 + 1)?;
+
+        let permit = match self.queue.reserve().await {
+            Ok(permit) => permit,
+            Err(_) => return Err(self.queue_state.error()),
+        };
+
+        self.queue_state.unsent.fetch_add(1, Ordering::SeqCst);
+        permit.send(data.to_vec());
+
+        Ok(())
+    }
+
+    /// Waits for room in the queue, so the next `write` won't have to.
+    pub async fn ready(&self) -> Result<()> {
+        match self.queue.reserve().await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(self.queue_state.error()),
+        }
+    }
+
+    /// Waits until every queued message has been written to the connection.
+    pub async fn flush(&self) -> Result<()> {
+        loop {
+            let flushed = self.queue_state.flushed.notified();
+            futures::pin_mut!(flushed);
+            flushed.as_mut().enable();
+
+            if self.queue_state.failed.lock().unwrap().is_some() {
+                return Err(self.queue_state.error());
+            }
+
+            if self.queue_state.unsent.load(Ordering::SeqCst) == 0 {
+                return Ok(());
+            }
+
+            flushed.await;
+        }
+    }
+
+    /// The number of messages waiting in the queue. Doesn't include one the writer has taken
+    /// and is waiting for credit to send.
+    pub fn queued(&self) -> usize {
+        self.queue.max_capacity() - self.queue.capacity()
+    }
+
+    /// Whether the queue is full, so `write` would wait.
+    pub fn is_full(&self) -> bool {
+        self.queue.capacity() == 0
+    }
+}
+
+impl Drop for FlowControlledConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+async fn read_frames(
+    connection: Arc<MessageIpcConnection>,
+    receive_credit: Arc<Mutex<ReceiveCredit>>,
+    send_credit: Arc<SendCredit>,
+    incoming: mpsc::Sender<Result<Vec<u8>>>,
+) {
+    let err = loop {
+        let frame = match connection.read().await.and_then(|data| Frame::decode(&data)) {
+            Ok(frame) => frame,
+            Err(err) => break err,
+        };
+
+        match frame {
+            Frame::Data { body } => {
+                if let Err(err) = receive_credit.lock().unwrap().received(body.len()) {
+                    break err;
+                }
+
+                // The window bounds what's waiting to be read, so there's always room.
+                let _ = incoming.try_send(Ok(body));
+            }
+            Frame::Credit { messages, bytes } => send_credit.grant(messages, bytes),
+        };
+    };
+
+    trace!(%err, "Flow controlled connection failed");
+
+    send_credit.fail(&err);
+    let _ = incoming.try_send(Err(err));
+}
+
+/// Writes the credit we grant the peer as soon as it's granted, and the queued messages as
+/// the peer grants us credit to send them.
+async fn write_queued(
+    connection: Arc<MessageIpcConnection>,
+    mut granted: unbounded::UnboundedReceiver<Window>,
+    send_credit: Arc<SendCredit>,
+    mut queued: mpsc::Receiver<Vec<u8>>,
+    queue_state: Arc<QueueState>,
+) {
+    enum Event {
+        Granted(Option<Window>),
+        Queued(Option<Vec<u8>>),
+        Sendable(Result<()>),
+    }
+
+    let result = async {
+        // The next message to send, once we have credit for it.
+        let mut next: Option<Vec<u8>> = None;
+
+        loop {
+            // Grants mustn't wait behind a message waiting for credit, or two peers each
+            // waiting for the other's grant would wait forever.
+            let event = match &next {
+                None => match future::select(granted.next(), Box::pin(queued.recv())).await {
+                    Either::Left((grant, _)) => Event::Granted(grant),
+                    Either::Right((body, _)) => Event::Queued(body),
+                },
+                Some(body) => match future::select(granted.next(), Box::pin(send_credit.acquire(body.len()))).await {
+                    Either::Left((grant, _)) => Event::Granted(grant),
+                    Either::Right((result, _)) => Event::Sendable(result),
+                },
+            };
+
+            match event {
+                Event::Granted(Some(grant)) => {
+                    Frame::Credit {
+                        messages: grant.messages,
+                        bytes: grant.bytes,
+                    }
+                    .write(&connection)
+                    .await?
+                }
+                Event::Queued(Some(body)) => next = Some(body),
+                Event::Sendable(result) => {
+                    result?;
+
+                    Frame::Data { body: next.take().unwrap() }.write(&connection).await?;
+                    queue_state.sent();
+                }
+                // The connection has been dropped.
+                Event::Granted(None) | Event::Queued(None) => return Ok(()),
+            }
+        }
+    };
+
+    if let Err(err) = result.await {
+        trace!(%err, "Flow controlled connection failed");
+
+        // Close the queue before reporting the failure, so a writer that sees the failure
+        // can't queue anything more.
+        queued.close();
+        queue_state.fail(err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::frame::Frame;
+    use super::{FlowControl, FlowControlledConnection};
+    use crate::error::IpcError;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+
+    use std::time::Duration;
+
+    #[test]
+    fn writers_wait_for_the_reader() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+
+            let client = MessageIpcClient::new(&server_name).unwrap();
+            let client = FlowControlledConnection::with_options(client, &FlowControl::new().queue_capacity(1)).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server =
+                FlowControlledConnection::with_options(connection, &FlowControl::new().window_messages(2)).unwrap();
+
+            // Two messages fill the server's window, the writer holds a third and the fourth
+            // fills the queue.
+            for i in 0..4u8 {
+                client.write(&[i]).await.unwrap();
+            }
+
+            assert!(tokio::time::timeout(Duration::from_millis(200), client.write(&[4])).await.is_err());
+            assert!(client.is_full());
+            assert_eq!(client.queued(), 1);
+
+            let (written, _) = futures::join!(client.write(&[4]), async {
+                for i in 0..5u8 {
+                    assert_eq!(server.read().await.unwrap(), vec![i]);
+                }
+            });
+
+            written.unwrap();
+            client.flush().await.unwrap();
+            assert_eq!(client.queued(), 0);
+
+            // Messages larger than the window still get through.
+            let large = vec![7; 8 * 1024 * 1024];
+
+            server.write(&large).await.unwrap();
+            server.write(&large).await.unwrap();
+
+            assert_eq!(client.read().await.unwrap(), large);
+            assert_eq!(client.read().await.unwrap(), large);
+        });
+    }
+
+    #[test]
+    fn queued_writes_fail_when_the_connection_does() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = FlowControlledConnection::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            // The server never grants credit, then goes away.
+            client.write(b"hello").await.unwrap();
+            drop(connection);
+
+            match client.flush().await {
+                Err(IpcError::PeerDisconnected) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            match client.read().await {
+                Err(IpcError::PeerDisconnected) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            assert!(client.write(b"hello").await.is_err());
+        });
+    }
+
+    #[test]
+    fn peers_cant_send_beyond_the_window() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = MessageIpcClient::new(&server_name).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server = FlowControlledConnection::with_options(connection, &FlowControl::new().window_messages(1)).unwrap();
+
+            // A peer that ignores flow control, sending without waiting for credit.
+            for _ in 0..4 {
+                let data = Frame::Data { body: b"neigh".to_vec() };
+                client.write(&data.encode()).await.unwrap();
+            }
+
+            // Reading grants more credit, so some may arrive within it before the rest don't.
+            loop {
+                match server.read().await {
+                    Ok(message) => assert_eq!(message, b"neigh"),
+                    Err(IpcError::ProtocolMismatch(_)) => break,
+                    Err(err) => panic!("Unexpected error {:?}", err),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,76 @@
+use tokio::sync::Notify;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+struct InstanceCounterInner {
+    count: AtomicU32,
+    max: u32,
+    released: Notify,
+}
+
+/// Counts the live connections on a server so it can honor `ServerOptions::max_instances`.
+/// Clones share the same count. On Unix this only holds off accepting; clients over the limit
+/// still connect, and wait in the listen backlog.
+#[derive(Clone)]
+pub struct InstanceCounter {
+    inner: Arc<InstanceCounterInner>,
+}
+
+impl InstanceCounter {
+    pub fn new(max: Option<u32>) -> InstanceCounter {
+        InstanceCounter {
+            inner: Arc::new(InstanceCounterInner {
+                count: AtomicU32::new(0),
+                max: max.unwrap_or(u32::MAX),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    /// The number of live connections.
+    pub fn count(&self) -> u32 {
+        self.inner.count.load(Ordering::SeqCst)
+    }
+
+    /// True if there are fewer than `max` live connections.
+    pub fn has_capacity(&self) -> bool {
+        self.count() < self.inner.max
+    }
+
+    /// Blocks the current task until there are fewer than `max` live connections.
+    pub async fn wait_for_capacity(&self) {
+        loop {
+            // Register interest before checking so a release between the check and the await
+            // isn't lost.
+            let released = self.inner.released.notified();
+
+            if self.has_capacity() {
+                return;
+            }
+
+            released.await;
+        }
+    }
+
+    /// Records a new live connection, which lasts until the returned guard is dropped.
+    pub fn acquire(&self) -> InstanceGuard {
+        self.inner.count.fetch_add(1, Ordering::SeqCst);
+
+        InstanceGuard {
+            counter: self.clone(),
+        }
+    }
+}
+
+/// Held by a connection for its lifetime, releasing its instance when dropped.
+pub struct InstanceGuard {
+    counter: InstanceCounter,
+}
+
+impl Drop for InstanceGuard {
+    fn drop(&mut self) {
+        self.counter.inner.count.fetch_sub(1, Ordering::SeqCst);
+        self.counter.inner.released.notify_waiters();
+    }
+}
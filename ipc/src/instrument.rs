@@ -0,0 +1,322 @@
+//! `tracing` spans for connections and the operations on them.
+//!
+//! Every connection gets an id, and an `ipc.connection` span that lasts as long as it does.
+//! Operations get spans of their own: `ipc.connect` and `ipc.accept` when the connection is
+//! made, `ipc.frame` for each message a `MessageIpcConnection` reads or writes, and
+//! `ipc.read` and `ipc.write` for each transfer to or from the OS. Operation spans are children
+//! of whatever span they're performed in, so time spent in IPC counts towards the caller, and
+//! carry the connection's id, the bytes transferred and how long they took in microseconds.
+
+use crate::error::Result;
+
+use tracing::{field, Instrument, Span};
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Which end of a connection we are.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn as_str(self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Server => "server",
+        }
+    }
+}
+
+/// A connection's id and the span that lasts as long as it does.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionSpan {
+    pub id: u64,
+    pub span: Span,
+}
+
+impl ConnectionSpan {
+    fn new(side: Side, name: &str) -> ConnectionSpan {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst);
+
+        ConnectionSpan {
+            id,
+            span: tracing::info_span!("ipc.connection", connection = id, side = side.as_str(), name),
+        }
+    }
+
+    /// A span for reading or writing a whole message of `bytes` bytes.
+    pub fn frame(&self, direction: &'static str) -> Span {
+        let span = tracing::debug_span!(
+            "ipc.frame",
+            connection = self.id,
+            direction,
+            bytes = field::Empty,
+            duration_us = field::Empty,
+            error = field::Empty,
+        );
+
+        span.follows_from(&self.span);
+        span
+    }
+
+    /// A span for one read from the OS.
+    pub fn read(&self) -> Span {
+        self.transfer(tracing::trace_span!(
+            "ipc.read",
+            connection = self.id,
+            bytes = field::Empty,
+            duration_us = field::Empty,
+            error = field::Empty,
+        ))
+    }
+
+    /// A span for one write to the OS.
+    pub fn write(&self) -> Span {
+        self.transfer(tracing::trace_span!(
+            "ipc.write",
+            connection = self.id,
+            bytes = field::Empty,
+            duration_us = field::Empty,
+            error = field::Empty,
+        ))
+    }
+
+    fn transfer(&self, span: Span) -> Span {
+        span.follows_from(&self.span);
+        span
+    }
+}
+
+/// Runs `connect`, returning what it connected along with its connection's span.
+pub(crate) fn connect<T>(name: &str, connect: impl FnOnce() -> Result<T>) -> Result<(T, ConnectionSpan)> {
+    let span = connect_span(name);
+
+    let started = Instant::now();
+    let result = span.in_scope(connect);
+
+    connected(name, &span, started, result)
+}
+
+/// Waits for `connect`, returning what it connected along with its connection's span.
+pub(crate) async fn connect_async<T>(
+    name: &str,
+    connect: impl Future<Output = Result<T>>,
+) -> Result<(T, ConnectionSpan)> {
+    let span = connect_span(name);
+
+    let started = Instant::now();
+    let result = connect.instrument(span.clone()).await;
+
+    connected(name, &span, started, result)
+}
+
+fn connect_span(name: &str) -> Span {
+    tracing::info_span!(
+        "ipc.connect",
+        name,
+        connection = field::Empty,
+        duration_us = field::Empty,
+        error = field::Empty,
+    )
+}
+
+fn connected<T>(name: &str, span: &Span, started: Instant, result: Result<T>) -> Result<(T, ConnectionSpan)> {
+    record(span, started, result).map(|connection| {
+        let connection_span = ConnectionSpan::new(Side::Client, name);
+        span.record("connection", connection_span.id);
+
+        (connection, connection_span)
+    })
+}
+
+/// Waits for `accept`, returning what it accepted along with its connection's span.
+pub(crate) async fn accept<T>(
+    name: &str,
+    accept: impl Future<Output = Result<T>>,
+) -> Result<(T, ConnectionSpan)> {
+    let span = tracing::info_span!(
+        "ipc.accept",
+        name,
+        connection = field::Empty,
+        duration_us = field::Empty,
+        error = field::Empty,
+    );
+
+    let started = Instant::now();
+    let result = accept.instrument(span.clone()).await;
+
+    record(&span, started, result).map(|accepted| {
+        let connection_span = ConnectionSpan::new(Side::Server, name);
+        span.record("connection", connection_span.id);
+
+        (accepted, connection_span)
+    })
+}
+
+/// Runs `transfer` in `span`, recording how many bytes it moved and how long it took.
+pub(crate) async fn transfer(span: Span, transfer: impl Future<Output = Result<u32>>) -> Result<u32> {
+    let started = Instant::now();
+    let result = transfer.instrument(span.clone()).await;
+
+    if let Ok(bytes) = &result {
+        span.record("bytes", bytes);
+    }
+
+    record(&span, started, result)
+}
+
+/// Runs `operation` in `span`, recording how long it took.
+pub(crate) async fn timed<T>(span: Span, operation: impl Future<Output = Result<T>>) -> Result<T> {
+    let started = Instant::now();
+    let result = operation.instrument(span.clone()).await;
+
+    record(&span, started, result)
+}
+
+fn record<T>(span: &Span, started: Instant, result: Result<T>) -> Result<T> {
+    span.record("duration_us", started.elapsed().as_micros() as u64);
+
+    if let Err(err) = &result {
+        span.record("error", field::display(err));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ipc::{MessageIpcClient, MessageIpcServer, RawIpcClient, RawIpcServer};
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+
+    use std::collections::BTreeMap;
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+
+    type Fields = BTreeMap<String, String>;
+
+    /// Collects the name and fields of every span once it closes.
+    #[derive(Clone, Default)]
+    struct SpanCollector {
+        closed: Arc<Mutex<Vec<(String, Fields)>>>,
+    }
+
+    impl SpanCollector {
+        fn spans(&self, name: &str) -> Vec<Fields> {
+            let closed = self.closed.lock().unwrap();
+
+            closed.iter().filter(|(span, _)| span == name).map(|(_, fields)| fields.clone()).collect()
+        }
+    }
+
+    struct FieldVisitor<'a>(&'a mut Fields);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.insert(field.name().to_owned(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanCollector {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut extensions = span.extensions_mut();
+
+            values.record(&mut FieldVisitor(extensions.get_mut::<Fields>().unwrap()));
+        }
+
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let fields = span.extensions_mut().remove::<Fields>().unwrap();
+
+            self.closed.lock().unwrap().push((span.name().to_owned(), fields));
+        }
+    }
+
+    #[test]
+    fn operations_are_traced() {
+        install_logger();
+
+        let collector = SpanCollector::default();
+        let subscriber = tracing_subscriber::registry().with(collector.clone());
+
+        let server_name = get_server_name();
+        let raw_server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            pool.block_on(async {
+                let server = MessageIpcServer::new(&server_name).unwrap();
+                let client = MessageIpcClient::new(&server_name).unwrap();
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+                client.write(b"hello").await.unwrap();
+                assert_eq!(connection.read().await.unwrap(), b"hello");
+
+                let server = RawIpcServer::new(&raw_server_name).unwrap();
+                let raw_client = RawIpcClient::new(&raw_server_name).unwrap();
+                let (raw_connection, _server) = server.wait_for_connection().await.unwrap();
+
+                assert_eq!(raw_client.write(b"neigh").await.unwrap(), 5);
+
+                let mut data = [0; 5];
+                assert_eq!(raw_connection.read(&mut data).await.unwrap(), 5);
+
+                drop((client, connection, raw_client, raw_connection));
+            });
+        });
+
+        let connects = collector.spans("ipc.connect");
+        let accepts = collector.spans("ipc.accept");
+
+        assert_eq!(connects.len(), 2);
+        assert_eq!(accepts.len(), 2);
+        assert_eq!(connects[0]["name"], format!("{:?}", server_name));
+        assert!(accepts.iter().all(|accept| accept.contains_key("duration_us")));
+
+        let client_id = &connects[0]["connection"];
+        let server_id = &accepts[0]["connection"];
+
+        assert_eq!(collector.spans("ipc.connection").len(), 4);
+
+        // The message's size prefix and body are each written and read as one transfer.
+        let frames = collector.spans("ipc.frame");
+        let frame = |id: &String, direction: &str| {
+            frames.iter().find(|frame| &frame["connection"] == id && frame["direction"] == format!("{:?}", direction))
+        };
+
+        assert_eq!(frame(client_id, "write").unwrap()["bytes"], "5");
+        assert_eq!(frame(server_id, "read").unwrap()["bytes"], "5");
+
+        let writes = collector.spans("ipc.write");
+        let written = |id: &String| writes.iter().filter(|write| &write["connection"] == id).count();
+
+        assert_eq!(written(client_id), 2);
+        assert_eq!(written(&connects[1]["connection"]), 1);
+
+        let reads = collector.spans("ipc.read");
+        let raw_read = reads.iter().find(|read| read["connection"] == accepts[1]["connection"]).unwrap();
+
+        assert_eq!(raw_read["bytes"], "5");
+        assert!(raw_read.contains_key("duration_us"));
+    }
+}
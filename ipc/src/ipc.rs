@@ -1,49 +1,173 @@
 #[cfg(windows)]
 use super::windows::{IpcClientWrapper, IpcConnectionWrapper, IpcServerWrapper};
+#[cfg(unix)]
+use super::unix::{IpcClientWrapper, IpcConnectionWrapper, IpcServerWrapper};
+use crate::access::AccessPolicy;
+#[cfg(feature = "auth")]
+use crate::auth::{self, PreSharedKey};
+use crate::credentials::PeerCredentials;
+use crate::error::{IpcError, Result};
+use crate::instrument::{self, ConnectionSpan};
+use crate::message::{Headers, Message};
+use crate::name::IntoIpcName;
+use crate::options::{ClientOptions, ServerOptions};
+use crate::stats::{ConnectionRecorder, ConnectionStats, ServerRecorder, ServerStats};
+
+#[cfg(feature = "auth")]
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+#[cfg(feature = "auth")]
+use futures::StreamExt;
+#[cfg(feature = "auth")]
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::debug;
 
 use std::cmp::{min};
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Instant;
 use std::vec::{Vec};
 
+/// Set in a message's size prefix when the message starts with a header section: a
+/// little-endian u32 length followed by the encoded headers.
+const HEADERS_FLAG: u64 = 1 << 63;
+
+/// Waits for a connection from a peer `policy` allows, closing any others.
+async fn accept(
+    mut server: IpcServerWrapper,
+    policy: &AccessPolicy,
+) -> Result<(IpcConnectionWrapper, IpcServerWrapper)> {
+    loop {
+        let (connection, next_server) = server.wait_for_connection().await?;
+
+        if policy.allows(connection.peer_credentials()) {
+            return Ok((connection, next_server));
+        }
+
+        debug!(peer = ?connection.peer_credentials(), "Closing connection from a peer the access policy denies");
+
+        server = next_server;
+    }
+}
+
+/// Authenticates a client that just connected to a server with a pre-shared key, closing the
+/// connection if it fails.
+#[cfg(feature = "auth")]
+async fn authenticate(
+    mut connection: MessageIpcConnection,
+    key: &PreSharedKey,
+    max_message_size: Option<u64>,
+) -> Option<MessageIpcConnection> {
+    connection.max_message_size = Some(auth::MAX_HANDSHAKE_FRAME);
+
+    match auth::accept(&connection, key).await {
+        Ok(()) => {
+            connection.max_message_size = max_message_size;
+            Some(connection)
+        }
+        Err(err) => {
+            debug!(%err, peer = ?connection.peer_credentials(), "Closing connection from a peer that failed to authenticate");
+            None
+        }
+    }
+}
+
+/// Fails if `key` is set, for the constructors that can't authenticate.
+#[cfg(feature = "auth")]
+fn reject_pre_shared_key(key: &Option<PreSharedKey>, alternative: &str) -> Result<()> {
+    match key {
+        Some(_) => Err(IpcError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Authenticating with a pre-shared key requires {}", alternative),
+        ))),
+        None => Ok(()),
+    }
+}
+
 pub struct RawIpcServer {
     server: IpcServerWrapper,
+    name: String,
+    access_policy: AccessPolicy,
+    stats: Arc<ServerRecorder>,
 }
 
 impl RawIpcServer {
-    pub fn new(name: &str) -> std::io::Result<RawIpcServer> {
-        let server = IpcServerWrapper::new(name)?;
+    pub fn new(name: impl IntoIpcName) -> Result<RawIpcServer> {
+        RawIpcServer::with_options(name, &ServerOptions::default())
+    }
+
+    pub fn with_options(name: impl IntoIpcName, options: &ServerOptions) -> Result<RawIpcServer> {
+        #[cfg(feature = "auth")]
+        reject_pre_shared_key(&options.pre_shared_key, "a MessageIpcServer")?;
+
+        let name = name.into_ipc_name()?;
+        let server = IpcServerWrapper::new(&name, options)?;
 
         Ok(RawIpcServer {
-            server: server
+            server,
+            name: name.to_string(),
+            access_policy: options.access_policy.clone(),
+            stats: ServerRecorder::new(name.name()),
         })
     }
 
-    pub async fn wait_for_connection(self) -> std::io::Result<(RawIpcConnection, RawIpcServer)> {
-        let (connection, server) = self.server.wait_for_connection().await?;
+    /// Waits for a client the server's access policy allows to connect.
+    pub async fn wait_for_connection(self) -> Result<(RawIpcConnection, RawIpcServer)> {
+        let ((connection, server), span) = instrument::accept(&self.name, accept(self.server, &self.access_policy)).await?;
 
-        let new_server = RawIpcServer {
-            server: server
+        let new_connection = RawIpcConnection {
+            connection,
+            span,
+            stats: self.stats.accepted(),
         };
 
-        let new_connection = RawIpcConnection {
-            connection: connection
+        let new_server = RawIpcServer {
+            server,
+            name: self.name,
+            access_policy: self.access_policy,
+            stats: self.stats,
         };
 
         Ok((new_connection, new_server))
     }
+
+    /// The totals of every connection this server has accepted.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.snapshot()
+    }
 }
 
 
 pub struct RawIpcConnection {
     connection: IpcConnectionWrapper,
+    span: ConnectionSpan,
+    stats: ConnectionRecorder,
 }
 
 impl RawIpcConnection {
-    pub async fn read<'a>(&'a self, data: &'a mut [u8]) -> std::io::Result<u32> {
-        self.connection.read(data).await
+    pub async fn read<'a>(&'a self, data: &'a mut [u8]) -> Result<u32> {
+        let bytes_read = instrument::transfer(self.span.read(), self.connection.read(data)).await?;
+        self.stats.read(bytes_read);
+
+        Ok(bytes_read)
+    }
+
+    pub async fn write<'a>(&'a self, data: &'a [u8]) -> Result<u32> {
+        let bytes_written = instrument::transfer(self.span.write(), self.connection.write(data)).await?;
+        self.stats.wrote(bytes_written);
+
+        Ok(bytes_written)
+    }
+
+    /// Who is on the other end of this connection, as captured when it was made.
+    pub fn peer_credentials(&self) -> &PeerCredentials {
+        self.connection.peer_credentials()
     }
 
-    pub async fn write<'a>(&'a self, data: &'a [u8]) -> std::io::Result<u32> {
-        self.connection.write(data).await
+    /// What has been read from and written to this connection. Raw connections have no
+    /// messages, so only the byte counts are filled in.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
     }
 }
 
@@ -51,50 +175,276 @@ pub struct RawIpcClient {
 }
 
 impl RawIpcClient {
-    pub fn new(name: &str) -> std::io::Result<RawIpcConnection> {
-        let connection = IpcClientWrapper::new(name)?;
+    pub fn new(name: impl IntoIpcName) -> Result<RawIpcConnection> {
+        RawIpcClient::with_options(name, &ClientOptions::default())
+    }
+
+    pub fn with_options(name: impl IntoIpcName, options: &ClientOptions) -> Result<RawIpcConnection> {
+        #[cfg(feature = "auth")]
+        reject_pre_shared_key(&options.pre_shared_key, "MessageIpcClient::connect")?;
+
+        let name = name.into_ipc_name()?;
+        let (connection, span) = instrument::connect(name.name(), || IpcClientWrapper::new(&name, options))?;
 
         Ok(RawIpcConnection {
-            connection: connection
+            connection,
+            span,
+            stats: ConnectionRecorder::client(name.name()),
         })
     }
 }
 
+/// Where a `MessageIpcServer` gets its next connection from.
+// Direct is the common case, so it isn't boxed.
+#[allow(clippy::large_enum_variant)]
+enum Listener {
+    /// Accepts a connection each time one is waited for.
+    Direct(IpcServerWrapper),
+
+    /// Accepts and authenticates connections in the background.
+    #[cfg(feature = "auth")]
+    Authenticating(Authenticator),
+}
+
+/// Accepts connections on a task of its own, authenticating each one on a task of its own,
+/// so a client that stalls its handshake holds up only itself. Dropping the authenticator
+/// stops accepting and abandons the handshakes in progress.
+#[cfg(feature = "auth")]
+struct Authenticator {
+    authenticated: mpsc::UnboundedReceiver<Result<MessageIpcConnection>>,
+    acceptor: JoinHandle<()>,
+}
+
+#[cfg(feature = "auth")]
+impl Authenticator {
+    fn spawn(
+        mut server: IpcServerWrapper,
+        key: PreSharedKey,
+        name: String,
+        max_message_size: Option<u64>,
+        access_policy: AccessPolicy,
+        stats: Arc<ServerRecorder>,
+    ) -> Result<Authenticator> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            IpcError::Io(std::io::Error::other("Servers with a pre-shared key must accept connections within a tokio runtime"))
+        })?;
+
+        let (authenticated_tx, authenticated) = mpsc::unbounded();
+
+        let acceptor = runtime.spawn(async move {
+            let mut handshakes = JoinSet::new();
+
+            loop {
+                // Reap finished handshakes so the set doesn't grow without bound.
+                while handshakes.try_join_next().is_some() {}
+
+                let ((connection, next_server), span) = match instrument::accept(&name, accept(server, &access_policy)).await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        let _ = authenticated_tx.unbounded_send(Err(err));
+                        return;
+                    }
+                };
+
+                server = next_server;
+
+                let connection = MessageIpcConnection::new(connection, max_message_size, span, stats.accepted());
+                let authenticated_tx = authenticated_tx.clone();
+                let key = key.clone();
+
+                handshakes.spawn(async move {
+                    if let Some(connection) = authenticate(connection, &key, max_message_size).await {
+                        let _ = authenticated_tx.unbounded_send(Ok(connection));
+                    }
+                });
+            }
+        });
+
+        Ok(Authenticator { authenticated, acceptor })
+    }
+
+    /// Waits for the next client to authenticate.
+    async fn next(&mut self) -> Result<MessageIpcConnection> {
+        match self.authenticated.next().await {
+            Some(connection) => connection,
+            // The acceptor has already reported why it stopped.
+            None => Err(IpcError::Cancelled),
+        }
+    }
+}
+
+#[cfg(feature = "auth")]
+impl Drop for Authenticator {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
 pub struct MessageIpcServer {
-    server: IpcServerWrapper,
+    listener: Listener,
+    name: String,
+    max_message_size: Option<u64>,
+    access_policy: AccessPolicy,
+    stats: Arc<ServerRecorder>,
+
+    #[cfg(feature = "auth")]
+    pre_shared_key: Option<PreSharedKey>,
 }
 
 impl MessageIpcServer {
-    pub fn new(name: &str) -> std::io::Result<MessageIpcServer> {
-        let server = IpcServerWrapper::new(name)?;
+    pub fn new(name: impl IntoIpcName) -> Result<MessageIpcServer> {
+        MessageIpcServer::with_options(name, &ServerOptions::default())
+    }
+
+    pub fn with_options(name: impl IntoIpcName, options: &ServerOptions) -> Result<MessageIpcServer> {
+        let name = name.into_ipc_name()?;
+        let server = IpcServerWrapper::new(&name, options)?;
 
         Ok(MessageIpcServer {
-            server: server
+            listener: Listener::Direct(server),
+            name: name.to_string(),
+            max_message_size: options.max_message_size,
+            access_policy: options.access_policy.clone(),
+            stats: ServerRecorder::new(name.name()),
+
+            #[cfg(feature = "auth")]
+            pre_shared_key: options.pre_shared_key.clone(),
         })
     }
 
-    pub async fn wait_for_connection(self) -> std::io::Result<(MessageIpcConnection, MessageIpcServer)> {
-        let (connection, server) = self.server.wait_for_connection().await?;
+    /// Waits for a client the server's access policy allows to connect and, if the server
+    /// has a pre-shared key, that proves it knows it.
+    ///
+    /// With a pre-shared key, the server keeps accepting and authenticating clients in the
+    /// background from the first call on, several at once, until it's dropped. This must then
+    /// be called within a tokio runtime.
+    pub async fn wait_for_connection(mut self) -> Result<(MessageIpcConnection, MessageIpcServer)> {
+        let (new_connection, listener) = match self.listener {
+            #[cfg(feature = "auth")]
+            Listener::Direct(server) if self.pre_shared_key.is_some() => {
+                let key = self.pre_shared_key.clone().unwrap();
+                let mut authenticator = Authenticator::spawn(
+                    server,
+                    key,
+                    self.name.clone(),
+                    self.max_message_size,
+                    self.access_policy.clone(),
+                    self.stats.clone(),
+                )?;
+
+                (authenticator.next().await?, Listener::Authenticating(authenticator))
+            }
+            Listener::Direct(server) => {
+                let ((connection, next_server), span) = instrument::accept(&self.name, accept(server, &self.access_policy)).await?;
+                let connection = MessageIpcConnection::new(connection, self.max_message_size, span, self.stats.accepted());
 
-        let new_server = MessageIpcServer {
-            server: server
+                (connection, Listener::Direct(next_server))
+            }
+            #[cfg(feature = "auth")]
+            Listener::Authenticating(mut authenticator) => {
+                (authenticator.next().await?, Listener::Authenticating(authenticator))
+            }
         };
 
-        let new_connection = MessageIpcConnection {
-            connection: connection
-        };
+        self.listener = listener;
 
-        Ok((new_connection, new_server))
+        Ok((new_connection, self))
+    }
+
+    /// The totals of every connection this server has accepted.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.snapshot()
     }
 }
 
+/// A connection that sends and receives whole messages. Reads and writes may be issued from
+/// several tasks at once; each message is read or written without interleaving with others.
+///
+/// Messages may carry `Headers`, sent with `write_message` and received with `read_message`.
+/// `read` drops any headers, and messages without headers are framed exactly as they would be
+/// by `write`. With the `opentelemetry` feature, every message sent from within a traced span
+/// carries its trace context, and the `ipc.frame` span reading it is linked to the sender's.
 pub struct MessageIpcConnection {
     connection: IpcConnectionWrapper,
+    max_message_size: Option<u64>,
+    read_lock: Mutex<()>,
+    write_lock: Mutex<()>,
+    span: ConnectionSpan,
+    stats: ConnectionRecorder,
 }
 
 
 impl MessageIpcConnection {
-    pub async fn read<'a>(&'a self) -> std::io::Result<Vec<u8>> {
+    fn new(
+        connection: IpcConnectionWrapper,
+        max_message_size: Option<u64>,
+        span: ConnectionSpan,
+        stats: ConnectionRecorder,
+    ) -> MessageIpcConnection {
+        MessageIpcConnection {
+            connection,
+            max_message_size,
+            read_lock: Mutex::new(()),
+            write_lock: Mutex::new(()),
+            span,
+            stats,
+        }
+    }
+
+    /// Who is on the other end of this connection, as captured when it was made.
+    pub fn peer_credentials(&self) -> &PeerCredentials {
+        self.connection.peer_credentials()
+    }
+
+    /// What has been read from and written to this connection.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
+    }
+
+    /// The largest message the connection reads or writes, if limited.
+    #[cfg(feature = "noise")]
+    pub(crate) fn max_message_size(&self) -> Option<u64> {
+        self.max_message_size
+    }
+
+    #[cfg(feature = "noise")]
+    pub(crate) fn set_max_message_size(&mut self, max_message_size: Option<u64>) {
+        self.max_message_size = max_message_size;
+    }
+
+    pub async fn read(&self) -> Result<Vec<u8>> {
+        Ok(self.read_frame().await?.body)
+    }
+
+    /// Reads the next message along with its headers, which are empty if it was sent without.
+    pub async fn read_message(&self) -> Result<Message> {
+        self.read_frame().await
+    }
+
+    /// Reads a frame, splitting off its headers if it has any.
+    async fn read_frame(&self) -> Result<Message> {
+        let span = self.span.frame("read");
+        let (has_headers, mut data) = instrument::timed(span.clone(), self.read_frame_internal()).await?;
+
+        span.record("bytes", data.len());
+
+        if !has_headers {
+            return Ok(Message::new(data));
+        }
+
+        let headers_len = MessageIpcConnection::headers_len(&data)?;
+        let headers = Headers::decode(&data[4..4 + headers_len])?;
+        data.drain(..4 + headers_len);
+
+        #[cfg(feature = "opentelemetry")]
+        headers.link_trace(&span);
+
+        Ok(Message { headers, body: data })
+    }
+
+    async fn read_frame_internal(&self) -> Result<(bool, Vec<u8>)> {
+        let _read_lock = self.read_lock.lock().await;
+
         let mut size_bytes: [u8; 8] = [0; 8];
 
         let mut bytes_remaining: u32 = 8;
@@ -102,13 +452,23 @@ impl MessageIpcConnection {
         while bytes_remaining > 0 {
             let (_, buffer) = size_bytes.split_at_mut(8 - bytes_remaining as usize);
 
-            let bytes_read = self.connection.read(buffer).await?;
+            let bytes_read = self.read_some(buffer).await?;
 
             bytes_remaining -= bytes_read;
         }
 
         let size: u64 = u64::from_ne_bytes(size_bytes);
+        let has_headers = size & HEADERS_FLAG != 0;
+        let size = size & !HEADERS_FLAG;
+
+        // Skip over an oversized message, so the next read starts at the frame after it.
+        if let Err(err) = self.check_message_size(size) {
+            self.discard(size).await?;
+            return Err(err);
+        }
 
+        // Latency counts from the message's arrival, not from when we started waiting for it.
+        let started = Instant::now();
         let mut bytes_remaining: u64 = size;
 
         let mut data = vec![0; bytes_remaining as usize];
@@ -119,44 +479,150 @@ impl MessageIpcConnection {
             // Perform our read in 16MB chunks.
             let (buffer, _) = buffer.split_at_mut(MessageIpcConnection::get_chunk_size(bytes_remaining as usize));
 
-            bytes_remaining = bytes_remaining - self.connection.read(buffer).await? as u64;
+            bytes_remaining -= self.read_some(buffer).await? as u64;
+        }
+
+        self.stats.received_message(size, started.elapsed());
+
+        Ok((has_headers, data))
+    }
+
+    /// Reads and throws away the next `size` bytes, buffering at most 64KB of them at a time.
+    async fn discard(&self, size: u64) -> Result<()> {
+        let mut buffer = vec![0; min(size, 64 * 1024) as usize];
+        let mut bytes_remaining = size;
+
+        while bytes_remaining > 0 {
+            let len = min(bytes_remaining, buffer.len() as u64) as usize;
+
+            bytes_remaining -= self.read_some(&mut buffer[..len]).await? as u64;
+        }
+
+        Ok(())
+    }
+
+    async fn read_some(&self, buffer: &mut [u8]) -> Result<u32> {
+        let bytes_read = instrument::transfer(self.span.read(), self.connection.read(buffer)).await?;
+        self.stats.read(bytes_read);
+
+        Ok(bytes_read)
+    }
+
+    fn headers_len(data: &[u8]) -> Result<usize> {
+        let headers_len = match data.get(..4) {
+            Some(len) => u32::from_le_bytes(len.try_into().unwrap()) as usize,
+            None => return Err(IpcError::protocol_mismatch("Message is too short for its headers")),
+        };
+
+        if data.len() - 4 < headers_len {
+            return Err(IpcError::protocol_mismatch("Message is too short for its headers"));
         }
 
-        Ok(data)
+        Ok(headers_len)
     }
 
-    pub async fn write<'a>(&'a self, data: &'a [u8]) -> std::io::Result<()> {
-        if data.len() == 0 {
+    pub async fn write<'a>(&'a self, data: &'a [u8]) -> Result<()> {
+        if data.is_empty() {
             return Ok(());
         }
 
-        let size_bytes = (data.len() as u64).to_ne_bytes();
+        self.write_with_headers(Headers::new(), data).await
+    }
+
+    /// Sends a message along with its headers. A message with empty headers is sent exactly
+    /// as `write` would send its body, except that an empty message is still sent.
+    pub async fn write_message(&self, message: &Message) -> Result<()> {
+        self.write_with_headers(message.headers.clone(), &message.body).await
+    }
+
+    /// Sends `body` as one message, even if it's empty. Layers whose encodings can be empty
+    /// write through this rather than `write`, which skips empty messages.
+    pub(crate) async fn write_body(&self, body: &[u8]) -> Result<()> {
+        self.write_with_headers(Headers::new(), body).await
+    }
+
+    async fn write_with_headers(&self, headers: Headers, body: &[u8]) -> Result<()> {
+        #[cfg(feature = "opentelemetry")]
+        let headers = {
+            let mut headers = headers;
+            headers.inject_trace_context();
+            headers
+        };
+
+        if headers.is_empty() {
+            return self.write_frame(&[body], false).await;
+        }
+
+        let headers = headers.encode();
+        let headers_len = (headers.len() as u32).to_le_bytes();
+
+        self.write_frame(&[&headers_len, &headers, body], true).await
+    }
+
+    /// Writes one frame made up of `parts`.
+    async fn write_frame(&self, parts: &[&[u8]], has_headers: bool) -> Result<()> {
+        let size = parts.iter().map(|part| part.len() as u64).sum::<u64>();
+
+        self.check_message_size(size)?;
+
+        let span = self.span.frame("write");
+        span.record("bytes", size);
+
+        let started = Instant::now();
+        instrument::timed(span, self.write_frame_internal(parts, size, has_headers)).await?;
+        self.stats.sent_message(size, started.elapsed());
+
+        Ok(())
+    }
+
+    async fn write_frame_internal(&self, parts: &[&[u8]], size: u64, has_headers: bool) -> Result<()> {
+        let _write_lock = self.write_lock.lock().await;
+
+        let size_bytes = (if has_headers { size | HEADERS_FLAG } else { size }).to_ne_bytes();
 
         let mut bytes_remaining: u32 = 8;
 
         while bytes_remaining > 0 {
             let (_, buffer) = size_bytes.split_at(8 - bytes_remaining as usize);
 
-            let bytes_written = self.connection.write(buffer).await?;
+            let bytes_written = self.write_some(buffer).await?;
 
             bytes_remaining -= bytes_written;
         }
 
-        let mut bytes_remaining: u64 = data.len() as u64;
+        for data in parts {
+            let mut bytes_remaining: u64 = data.len() as u64;
 
-        while bytes_remaining > 0 {
-            let (_, buffer) = data.split_at(data.len() - bytes_remaining as usize);
+            while bytes_remaining > 0 {
+                let (_, buffer) = data.split_at(data.len() - bytes_remaining as usize);
 
-            let (buffer, _) = buffer.split_at(MessageIpcConnection::get_chunk_size(bytes_remaining as usize));
+                let (buffer, _) = buffer.split_at(MessageIpcConnection::get_chunk_size(bytes_remaining as usize));
 
-            bytes_remaining = bytes_remaining - self.connection.write(buffer).await? as u64;
+                bytes_remaining -= self.write_some(buffer).await? as u64;
+            }
         }
 
         Ok(())
     }
 
+    async fn write_some(&self, buffer: &[u8]) -> Result<u32> {
+        let bytes_written = instrument::transfer(self.span.write(), self.connection.write(buffer)).await?;
+        self.stats.wrote(bytes_written);
+
+        Ok(bytes_written)
+    }
+
+    pub(crate) fn check_message_size(&self, size: u64) -> Result<()> {
+        match self.max_message_size {
+            Some(max) if size > max => {
+                Err(IpcError::FrameTooLarge { size, max })
+            }
+            _ => Ok(()),
+        }
+    }
+
     fn get_chunk_size(bytes_remaining: usize) -> usize {
-        min(bytes_remaining as usize, 16 * 1024 * 1024)
+        min(bytes_remaining, 16 * 1024 * 1024)
     }
 }
 
@@ -164,25 +630,63 @@ pub struct MessageIpcClient {
 }
 
 impl MessageIpcClient {
-    pub fn new(name: &str) -> std::io::Result<MessageIpcConnection> {
-        let connection = IpcClientWrapper::new(name)?;
+    pub fn new(name: impl IntoIpcName) -> Result<MessageIpcConnection> {
+        MessageIpcClient::with_options(name, &ClientOptions::default())
+    }
 
-        Ok(MessageIpcConnection {
-            connection: connection
-        })
+    pub fn with_options(name: impl IntoIpcName, options: &ClientOptions) -> Result<MessageIpcConnection> {
+        #[cfg(feature = "auth")]
+        reject_pre_shared_key(&options.pre_shared_key, "MessageIpcClient::connect")?;
+
+        MessageIpcClient::connect_unauthenticated(name, options)
+    }
+
+    /// Connects like `with_options` and, if the options have a pre-shared key, authenticates
+    /// with the server before returning the connection. On Unix, waiting for a busy server
+    /// doesn't block the thread.
+    pub async fn connect(name: impl IntoIpcName, options: &ClientOptions) -> Result<MessageIpcConnection> {
+        let name = name.into_ipc_name()?;
+        let (connection, span) = instrument::connect_async(name.name(), IpcClientWrapper::connect(&name, options)).await?;
+
+        let stats = ConnectionRecorder::client(name.name());
+
+        #[cfg_attr(not(feature = "auth"), allow(unused_mut))]
+        let mut connection = MessageIpcConnection::new(connection, options.max_message_size, span, stats);
+
+        #[cfg(feature = "auth")]
+        if let Some(key) = &options.pre_shared_key {
+            connection.max_message_size = Some(auth::MAX_HANDSHAKE_FRAME);
+            auth::connect(&connection, key).await?;
+            connection.max_message_size = options.max_message_size;
+        }
+
+        Ok(connection)
+    }
+
+    fn connect_unauthenticated(name: impl IntoIpcName, options: &ClientOptions) -> Result<MessageIpcConnection> {
+        let name = name.into_ipc_name()?;
+        let (connection, span) = instrument::connect(name.name(), || IpcClientWrapper::new(&name, options))?;
+
+        let stats = ConnectionRecorder::client(name.name());
+
+        Ok(MessageIpcConnection::new(connection, options.max_message_size, span, stats))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{MessageIpcClient, MessageIpcServer};
+    use crate::error::{IpcError, Result};
+    use crate::message::Message;
+    use crate::options::{ClientOptions, ServerOptions};
     use crate::test_utils::{get_server_name, install_logger};
 
     use tokio::runtime;
-    use log::{info};
+    use tracing::info;
 
     use std::sync::mpsc::{channel, Receiver, Sender};
     use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
 
     #[test]
     fn messaging_ipc_hello_world() {
@@ -208,7 +712,7 @@ mod tests {
                 start_tx: Sender<()>,
                 pong_rx: Receiver<()>,
                 server_name: &str
-            ) -> std::io::Result<()> {
+            ) -> Result<()> {
                 let server = MessageIpcServer::new(server_name)?;
                 start_tx.send(()).unwrap();
 
@@ -254,7 +758,7 @@ mod tests {
         {
             let pool = runtime::Runtime::new().unwrap();
 
-            async fn run_client(pong_tx: Sender<()>, server_name: &str) -> std::io::Result<()> {
+            async fn run_client(pong_tx: Sender<()>, server_name: &str) -> Result<()> {
                 let client = MessageIpcClient::new(server_name)?;
 
                 let data = "hello world".as_bytes();
@@ -335,7 +839,7 @@ mod tests {
                 start_tx: Sender<()>,
                 pong_rx: Receiver<()>,
                 server_name: &str
-            ) -> std::io::Result<()> {
+            ) -> Result<()> {
                 let server = MessageIpcServer::new(server_name)?;
                 start_tx.send(()).unwrap();
 
@@ -380,7 +884,7 @@ mod tests {
         {
             let pool = runtime::Runtime::new().unwrap();
 
-            async fn run_client(pong_tx: Sender<()>, server_name: &str) -> std::io::Result<()> {
+            async fn run_client(pong_tx: Sender<()>, server_name: &str) -> Result<()> {
                 let client = MessageIpcClient::new(server_name)?;
 
                 let message = allocate_message(100 * 1024 * 1024);
@@ -418,4 +922,119 @@ mod tests {
 
         client_connected_rx.recv().unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn messages_carry_headers() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = MessageIpcClient::new(&server_name).unwrap();
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let mut message = Message::new(b"{}".to_vec());
+            message.headers.content_type = Some("application/json".to_owned());
+            message.headers.message_type = Some("status".to_owned());
+            message.headers.timestamp = Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+            message.headers.metadata.insert("route".to_owned(), "stable".to_owned());
+
+            client.write_message(&message).await.unwrap();
+            assert_eq!(connection.read_message().await.unwrap(), message);
+
+            // Plain reads drop the headers.
+            client.write_message(&message).await.unwrap();
+            assert_eq!(connection.read().await.unwrap(), b"{}");
+
+            // Messages with headers may have an empty body.
+            let mut empty = Message::new(vec![]);
+            empty.headers.message_type = Some("ping".to_owned());
+
+            client.write_message(&empty).await.unwrap();
+            assert_eq!(connection.read_message().await.unwrap(), empty);
+
+            client.write(b"plain").await.unwrap();
+            assert_eq!(connection.read_message().await.unwrap(), Message::new(b"plain".to_vec()));
+        });
+    }
+
+    #[test]
+    fn connections_know_their_peer() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = MessageIpcClient::new(&server_name).unwrap();
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let peer = connection.peer_credentials();
+
+            assert_eq!(peer.pid, Some(std::process::id()));
+            assert_eq!(client.peer_credentials(), peer);
+
+            #[cfg(unix)]
+            {
+                assert_eq!(peer.uid, Some(unsafe { libc::geteuid() }));
+                assert_eq!(peer.gid, Some(unsafe { libc::getegid() }));
+            }
+
+            #[cfg(windows)]
+            assert!(peer.session_id.is_some());
+        });
+    }
+
+    #[test]
+    fn rejects_messages_over_max_message_size() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let options = ServerOptions::new().max_message_size(16);
+            let server = MessageIpcServer::with_options(&server_name, &options).unwrap();
+
+            let client = MessageIpcClient::with_options(&server_name, &ClientOptions::new()).unwrap();
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            match connection.write(&allocate_message(17)).await {
+                Err(IpcError::FrameTooLarge { size: 17, max: 16 }) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            client.write(&allocate_message(17)).await.unwrap();
+            client.write(&allocate_message(16)).await.unwrap();
+
+            match connection.read().await {
+                Err(IpcError::FrameTooLarge { size: 17, max: 16 }) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            // The oversized message was skipped, so the connection is still in step.
+            validate_message(connection.read().await.unwrap());
+        });
+    }
+
+    #[test]
+    fn second_server_with_same_name_is_addr_in_use() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let _server = MessageIpcServer::new(&server_name).unwrap();
+
+            match MessageIpcServer::new(&server_name) {
+                Err(IpcError::AddrInUse) => {}
+                Err(err) => panic!("Unexpected error {:?}", err),
+                Ok(_) => panic!("Expected the name to be in use"),
+            };
+        });
+    }
+}
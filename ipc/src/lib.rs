@@ -1,4 +1,50 @@
+//! Async IPC between processes on the same machine, over named pipes on Windows and Unix
+//! domain sockets elsewhere.
+//!
+//! `MessageIpcServer` and `MessageIpcClient` exchange whole messages under an `IpcName`, and
+//! `RawIpcServer` and `RawIpcClient` exchange bytes. On top of message connections,
+//! `TypedIpcConnection` sends serde values, the `rpc` module makes calls (with
+//! `#[ipc::service]` generating clients and servers from a trait), `mux` carries many channels
+//! over one connection and `flow` adds flow control.
+//!
+//! Optional features add codecs (see `codec`), pre-shared key authentication (`auth`),
+//! encryption (`noise`), protobuf services (`protobuf`), trace propagation (`opentelemetry`)
+//! and `metrics` reporting.
+
+// Clients are constructed with `new`, but return the connection they establish rather than
+// themselves.
+#![allow(clippy::new_ret_no_self)]
+// The tests fill and check buffers by index.
+#![cfg_attr(test, allow(clippy::needless_range_loop))]
+
+mod access;
+#[cfg(feature = "auth")]
+mod auth;
+pub mod codec;
+mod credentials;
+mod discovery;
+mod error;
+pub mod flow;
+mod instances;
+mod instrument;
 mod ipc;
+mod message;
+pub mod mux;
+mod name;
+#[cfg(feature = "noise")]
+pub mod noise;
+mod options;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+pub mod rpc;
+mod single_instance;
+mod stats;
+#[cfg(feature = "opentelemetry")]
+pub mod trace_context;
+mod typed;
+#[cfg(unix)]
+mod unix;
+#[cfg(windows)]
 mod windows;
 
 #[cfg(test)]
@@ -12,3 +58,14 @@ pub use self::ipc::{
     RawIpcConnection,
     RawIpcServer,
 };
+pub use self::access::AccessPolicy;
+pub use self::credentials::PeerCredentials;
+pub use self::discovery::{list_endpoints, wait_for_endpoint, EndpointInfo};
+pub use self::error::{IpcError, Result};
+pub use self::message::{Headers, Message};
+pub use self::name::{IntoIpcName, IpcName, Scope};
+pub use ipc_macros::service;
+pub use self::options::{ClientOptions, ServerOptions};
+pub use self::single_instance::SingleInstance;
+pub use self::stats::{ConnectionStats, Histogram, ServerStats};
+pub use self::typed::{TypedIpcClient, TypedIpcConnection, TypedIpcServer};
@@ -0,0 +1,171 @@
+use crate::error::{IpcError, Result};
+
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TAG_CONTENT_TYPE: u8 = 0;
+const TAG_MESSAGE_TYPE: u8 = 1;
+const TAG_TIMESTAMP: u8 = 2;
+const TAG_METADATA: u8 = 3;
+
+/// Information about a message carried alongside its body, so routing and tracing details
+/// don't have to be part of every payload's schema.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+    /// How the body is encoded, e.g. "application/json".
+    pub content_type: Option<String>,
+
+    /// What kind of message the body holds, for routing it without decoding it.
+    pub message_type: Option<String>,
+
+    /// When the message was sent. Sent with microsecond precision.
+    pub timestamp: Option<SystemTime>,
+
+    /// Any other key/value pairs.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.content_type.is_none()
+            && self.message_type.is_none()
+            && self.timestamp.is_none()
+            && self.metadata.is_empty()
+    }
+
+    /// Encodes the headers as a sequence of entries, each a tag byte followed by the entry's
+    /// fields. Strings are a little-endian u32 length followed by UTF-8, and the timestamp is
+    /// the little-endian u64 number of microseconds since the Unix epoch.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+
+        if let Some(content_type) = &self.content_type {
+            data.push(TAG_CONTENT_TYPE);
+            encode_string(&mut data, content_type);
+        }
+
+        if let Some(message_type) = &self.message_type {
+            data.push(TAG_MESSAGE_TYPE);
+            encode_string(&mut data, message_type);
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            let micros = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros();
+
+            data.push(TAG_TIMESTAMP);
+            data.extend_from_slice(&u64::try_from(micros).unwrap_or(u64::MAX).to_le_bytes());
+        }
+
+        for (key, value) in &self.metadata {
+            data.push(TAG_METADATA);
+            encode_string(&mut data, key);
+            encode_string(&mut data, value);
+        }
+
+        data
+    }
+
+    pub(crate) fn decode(mut data: &[u8]) -> Result<Headers> {
+        let mut headers = Headers::new();
+
+        while let Some((tag, rest)) = data.split_first() {
+            data = rest;
+
+            match *tag {
+                TAG_CONTENT_TYPE => headers.content_type = Some(decode_string(&mut data)?),
+                TAG_MESSAGE_TYPE => headers.message_type = Some(decode_string(&mut data)?),
+                TAG_TIMESTAMP => {
+                    let micros = u64::from_le_bytes(take(&mut data, 8)?.try_into().unwrap());
+
+                    headers.timestamp = UNIX_EPOCH.checked_add(Duration::from_micros(micros));
+                }
+                TAG_METADATA => {
+                    let key = decode_string(&mut data)?;
+                    let value = decode_string(&mut data)?;
+
+                    headers.metadata.insert(key, value);
+                }
+                tag => return Err(IpcError::protocol_mismatch(format!("Unknown header tag {}", tag))),
+            };
+        }
+
+        Ok(headers)
+    }
+}
+
+/// A message along with its headers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Message {
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    /// Creates a message with no headers.
+    pub fn new(body: Vec<u8>) -> Message {
+        Message {
+            headers: Headers::new(),
+            body,
+        }
+    }
+}
+
+fn encode_string(data: &mut Vec<u8>, value: &str) {
+    data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    data.extend_from_slice(value.as_bytes());
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(IpcError::protocol_mismatch("Message headers are truncated"));
+    }
+
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+
+    Ok(taken)
+}
+
+fn decode_string(data: &mut &[u8]) -> Result<String> {
+    let len = u32::from_le_bytes(take(data, 4)?.try_into().unwrap());
+
+    String::from_utf8(take(data, len as usize)?.to_vec())
+        .map_err(|_| IpcError::protocol_mismatch("Message header isn't UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Headers;
+    use crate::error::IpcError;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn headers_round_trip() {
+        let mut headers = Headers::new();
+        assert!(headers.is_empty());
+        assert_eq!(Headers::decode(&headers.encode()).unwrap(), headers);
+
+        headers.content_type = Some("application/json".to_owned());
+        headers.message_type = Some("status".to_owned());
+        headers.timestamp = Some(UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456));
+        headers.metadata.insert("request-id".to_owned(), "42".to_owned());
+        headers.metadata.insert("empty".to_owned(), String::new());
+
+        assert!(!headers.is_empty());
+        assert_eq!(Headers::decode(&headers.encode()).unwrap(), headers);
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        assert!(matches!(Headers::decode(&[9]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Headers::decode(&[2, 0, 0]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Headers::decode(&[0, 5, 0, 0, 0, b'a']), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Headers::decode(&[0, 1, 0, 0, 0, 0xff]), Err(IpcError::ProtocolMismatch(_))));
+    }
+}
@@ -0,0 +1,153 @@
+use super::frame::{ChannelKey, Frame};
+use super::MuxShared;
+use crate::error::{IpcError, Result};
+use crate::flow::credit::{ReceiveCredit, SendCredit};
+
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+use futures::StreamExt;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex, Weak};
+
+/// One logical channel of a `Multiplexer`. Behaves like a `MessageIpcConnection` of its own:
+/// messages arrive whole and in order, independently of every other channel.
+///
+/// Closing or dropping either end closes the channel for both; reads then fail with
+/// `IpcError::PeerDisconnected` once every message already received has been read. Writes wait
+/// while the peer has fallen a window behind on this channel. Channels don't keep their
+/// multiplexer's connection open.
+///
+/// Reads and writes are cancel-safe: a write dropped while it waits for credit sends nothing,
+/// and once it has credit its message is sent whole, whether or not it's still waited on.
+pub struct MuxChannel {
+    shared: Weak<MuxShared>,
+    key: ChannelKey,
+    name: String,
+    messages: Mutex<mpsc::UnboundedReceiver<Result<Vec<u8>>>>,
+    closed: Arc<AtomicBool>,
+    send_credit: Arc<SendCredit>,
+    receive_credit: SyncMutex<ReceiveCredit>,
+}
+
+impl MuxChannel {
+    pub(crate) fn new(
+        shared: Weak<MuxShared>,
+        key: ChannelKey,
+        name: String,
+        messages: mpsc::UnboundedReceiver<Result<Vec<u8>>>,
+        closed: Arc<AtomicBool>,
+        send_credit: Arc<SendCredit>,
+        receive_credit: ReceiveCredit,
+    ) -> MuxChannel {
+        MuxChannel {
+            shared,
+            key,
+            name,
+            messages: Mutex::new(messages),
+            closed,
+            send_credit,
+            receive_credit: SyncMutex::new(receive_credit),
+        }
+    }
+
+    pub(crate) fn key(&self) -> ChannelKey {
+        self.key
+    }
+
+    /// The channel's number, unique among the channels opened by the same side.
+    pub fn id(&self) -> u32 {
+        self.key.id
+    }
+
+    /// The name the channel was opened with, which may be empty.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reads the next message sent on this channel, granting the peer credit to send more.
+    pub async fn read(&self) -> Result<Vec<u8>> {
+        let message = {
+            let mut messages = self.messages.lock().await;
+
+            match messages.next().await {
+                Some(message) => message?,
+                None => return Err(IpcError::PeerDisconnected),
+            }
+        };
+
+        let grant = self.receive_credit.lock().unwrap().consumed(message.len());
+
+        if let (Some(grant), Some(shared)) = (grant, self.shared.upgrade()) {
+            shared.grant(self.key, grant);
+        }
+
+        Ok(message)
+    }
+
+    /// Sends `data` as one message on this channel. Messages larger than the multiplexer's
+    /// fragment size are sent in fragments, interleaved with other channels' messages.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        let shared = self.shared()?;
+
+        // A fragment over the connection's limit would fail the whole connection rather than
+        // this write. Each frame has a 6 byte header.
+        let largest_fragment = data.len().min(shared.fragment_size);
+        shared.connection.check_message_size(largest_fragment as u64 + 6)?;
+
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(IpcError::PeerDisconnected);
+        }
+
+        // Credit is taken in the same poll as the message is queued below, so a write dropped
+        // while waiting for credit neither takes any nor sends anything.
+        self.send_credit.acquire(data.len()).await?;
+
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(IpcError::PeerDisconnected);
+        }
+
+        let frame = Frame::Data {
+            channel: self.key,
+            last: true,
+            body: data.to_vec(),
+        };
+
+        shared.writer.write(frame).await
+    }
+
+    /// Closes the channel once the messages already written have been sent. The peer can
+    /// still read them.
+    pub async fn close(self) -> Result<()> {
+        if self.mark_closed() {
+            return Ok(());
+        }
+
+        self.shared()?.writer.write(Frame::Close { channel: self.key }).await
+    }
+
+    fn shared(&self) -> Result<Arc<MuxShared>> {
+        self.shared.upgrade().ok_or(IpcError::Cancelled)
+    }
+
+    /// Closes this end of the channel, returning whether it was already closed.
+    fn mark_closed(&self) -> bool {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.unregister(self.key);
+        }
+
+        self.closed.swap(true, Ordering::SeqCst)
+    }
+}
+
+impl Drop for MuxChannel {
+    fn drop(&mut self) {
+        if self.mark_closed() {
+            return;
+        }
+
+        if let Some(shared) = self.shared.upgrade() {
+            shared.writer.write_detached(Frame::Close { channel: self.key });
+        }
+    }
+}
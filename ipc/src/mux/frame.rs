@@ -0,0 +1,162 @@
+use crate::error::{IpcError, Result};
+use crate::flow::decode_credit;
+
+const KIND_OPEN: u8 = 0;
+const KIND_DATA: u8 = 1;
+const KIND_CLOSE: u8 = 2;
+const KIND_CREDIT: u8 = 3;
+
+const FLAG_OPENED_BY_SENDER: u8 = 0x1;
+const FLAG_LAST_FRAGMENT: u8 = 0x2;
+
+/// Identifies a channel on the wire. Each side numbers the channels it opens independently, so
+/// a channel is known by its number and which side opened it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ChannelKey {
+    pub opened_locally: bool,
+    pub id: u32,
+}
+
+/// One multiplexer message. Every frame starts with a kind byte, a flags byte and the
+/// little-endian u32 number of the channel it belongs to, followed by a kind-specific payload.
+/// Bit 0 of the flags is set if the sender of the frame opened the channel.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    /// Opens a channel. The payload is the channel's UTF-8 name, which may be empty.
+    Open {
+        channel: ChannelKey,
+        name: String,
+    },
+
+    /// Carries one fragment of a message. The payload is the fragment. Bit 1 of the flags is
+    /// set on a message's last fragment.
+    Data {
+        channel: ChannelKey,
+        last: bool,
+        body: Vec<u8>,
+    },
+
+    /// Closes a channel. There's no payload.
+    Close {
+        channel: ChannelKey,
+    },
+
+    /// Grants the receiver of the frame credit to send more on a channel. The payload is the
+    /// little-endian u32 number of messages followed by the little-endian u64 number of bytes.
+    Credit {
+        channel: ChannelKey,
+        messages: u32,
+        bytes: u64,
+    },
+}
+
+impl Frame {
+    /// Encodes the frame, with `channel` given from the sender's point of view.
+    pub fn encode(&self) -> Vec<u8> {
+        let credit;
+
+        let (kind, channel, mut flags, payload) = match self {
+            Frame::Open { channel, name } => (KIND_OPEN, channel, 0, name.as_bytes()),
+            Frame::Data { channel, last, body } => {
+                (KIND_DATA, channel, if *last { FLAG_LAST_FRAGMENT } else { 0 }, body.as_slice())
+            }
+            Frame::Close { channel } => (KIND_CLOSE, channel, 0, &[][..]),
+            Frame::Credit { channel, messages, bytes } => {
+                credit = [&messages.to_le_bytes()[..], &bytes.to_le_bytes()[..]].concat();
+
+                (KIND_CREDIT, channel, 0, credit.as_slice())
+            }
+        };
+
+        if channel.opened_locally {
+            flags |= FLAG_OPENED_BY_SENDER;
+        }
+
+        let mut data = Vec::with_capacity(6 + payload.len());
+        data.push(kind);
+        data.push(flags);
+        data.extend_from_slice(&channel.id.to_le_bytes());
+        data.extend_from_slice(payload);
+
+        data
+    }
+
+    /// Decodes a frame from the peer, returning its channel from the receiver's point of view.
+    pub fn decode(data: &[u8]) -> Result<Frame> {
+        if data.len() < 6 {
+            return Err(IpcError::protocol_mismatch("Multiplexer frame is too short"));
+        }
+
+        let flags = data[1];
+        let channel = ChannelKey {
+            opened_locally: flags & FLAG_OPENED_BY_SENDER == 0,
+            id: u32::from_le_bytes([data[2], data[3], data[4], data[5]]),
+        };
+        let payload = &data[6..];
+
+        match data[0] {
+            KIND_OPEN => {
+                let name = String::from_utf8(payload.to_vec())
+                    .map_err(|_| IpcError::protocol_mismatch("Multiplexer channel name isn't UTF-8"))?;
+
+                Ok(Frame::Open { channel, name })
+            }
+            KIND_DATA => Ok(Frame::Data {
+                channel,
+                last: flags & FLAG_LAST_FRAGMENT != 0,
+                body: payload.to_vec(),
+            }),
+            KIND_CLOSE => Ok(Frame::Close { channel }),
+            KIND_CREDIT => {
+                let (messages, bytes) = decode_credit(payload)?;
+
+                Ok(Frame::Credit { channel, messages, bytes })
+            }
+            kind => Err(IpcError::protocol_mismatch(format!("Unknown multiplexer frame kind {}", kind))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelKey, Frame};
+    use crate::error::IpcError;
+
+    /// Sends `frame` from one side and decodes it on the other, which sees the channel from the
+    /// opposite point of view.
+    fn assert_crosses(frame: Frame, expected: Frame) {
+        assert_eq!(Frame::decode(&frame.encode()).unwrap(), expected);
+    }
+
+    #[test]
+    fn frames_cross_to_the_peers_point_of_view() {
+        let mine = ChannelKey { opened_locally: true, id: 7 };
+        let theirs = ChannelKey { opened_locally: false, id: 7 };
+
+        assert_crosses(
+            Frame::Open { channel: mine, name: "logs".to_owned() },
+            Frame::Open { channel: theirs, name: "logs".to_owned() },
+        );
+        assert_crosses(
+            Frame::Data { channel: theirs, last: true, body: vec![1, 2] },
+            Frame::Data { channel: mine, last: true, body: vec![1, 2] },
+        );
+        assert_crosses(
+            Frame::Data { channel: mine, last: false, body: vec![] },
+            Frame::Data { channel: theirs, last: false, body: vec![] },
+        );
+        assert_crosses(Frame::Close { channel: mine }, Frame::Close { channel: theirs });
+        assert_crosses(
+            Frame::Credit { channel: theirs, messages: 3, bytes: 1024 },
+            Frame::Credit { channel: mine, messages: 3, bytes: 1024 },
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert!(matches!(Frame::decode(&[0; 3]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[9, 0, 0, 0, 0, 0]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[0, 0, 0, 0, 0, 0, 0xff]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[3, 0, 0, 0, 0, 0, 1]), Err(IpcError::ProtocolMismatch(_))));
+    }
+}
@@ -0,0 +1,644 @@
+//! Many logical channels over one message connection.
+//!
+//! Either side may open a channel, optionally naming it so the other side knows what it's for.
+//! Messages are split into fragments no larger than the multiplexer's fragment size, and
+//! fragments from different channels interleave on the connection, so one large message
+//! doesn't hold up the others. Each channel is flow controlled on its own, so a channel whose
+//! reader falls behind doesn't stall the rest.
+
+mod channel;
+mod frame;
+mod writer;
+
+pub use self::channel::MuxChannel;
+
+use self::frame::{ChannelKey, Frame};
+use self::writer::MuxWriter;
+use crate::error::{IpcError, Result};
+use crate::flow::credit::{ReceiveCredit, SendCredit, Window};
+use crate::flow::{DEFAULT_WINDOW_BYTES, DEFAULT_WINDOW_MESSAGES};
+use crate::ipc::MessageIpcConnection;
+
+use futures::channel::mpsc;
+use futures::lock::Mutex as AsyncMutex;
+use futures::StreamExt;
+use tokio::task::JoinHandle;
+use tracing::trace;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The fragment size used by `Multiplexer::new`.
+pub const DEFAULT_FRAGMENT_SIZE: usize = 64 * 1024;
+
+/// Options controlling a `Multiplexer`.
+#[derive(Clone, Debug)]
+pub struct MuxOptions {
+    pub(crate) fragment_size: usize,
+    pub(crate) window: Window,
+}
+
+impl Default for MuxOptions {
+    fn default() -> Self {
+        MuxOptions {
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            window: Window {
+                messages: DEFAULT_WINDOW_MESSAGES,
+                bytes: DEFAULT_WINDOW_BYTES,
+            },
+        }
+    }
+}
+
+impl MuxOptions {
+    /// Creates the default options: `DEFAULT_FRAGMENT_SIZE` fragments, and a window of
+    /// `DEFAULT_WINDOW_MESSAGES` messages and `DEFAULT_WINDOW_BYTES` bytes per channel.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The largest fragment a message is split into. Smaller fragments let channels share the
+    /// connection more fairly, at the cost of more overhead. Passing 0 is treated as 1.
+    pub fn fragment_size(mut self, size: usize) -> Self {
+        self.fragment_size = size.max(1);
+        self
+    }
+
+    /// How many messages the peer may send on each channel before we read them. Passing 0 is
+    /// treated as 1.
+    pub fn window_messages(mut self, messages: u32) -> Self {
+        self.window.messages = messages.max(1);
+        self
+    }
+
+    /// How many bytes the peer may send on each channel before we read them. A message
+    /// larger than this is still accepted once everything before it has been read. Passing 0
+    /// is treated as 1.
+    pub fn window_bytes(mut self, bytes: u64) -> Self {
+        self.window.bytes = bytes.max(1);
+        self
+    }
+}
+
+/// The receiving end of an open channel.
+struct ChannelSink {
+    messages: mpsc::UnboundedSender<Result<Vec<u8>>>,
+    closed: Arc<AtomicBool>,
+    send_credit: Arc<SendCredit>,
+
+    /// The fragments received so far of the message in progress.
+    partial: Vec<u8>,
+
+    /// The credit we've granted the peer that it hasn't yet used, mirroring its `SendCredit`.
+    /// Like the sender, bytes may go negative once a message larger than the window arrives.
+    granted_messages: u64,
+    granted_bytes: i64,
+}
+
+pub(crate) struct MuxShared {
+    connection: Arc<MessageIpcConnection>,
+    writer: MuxWriter,
+    fragment_size: usize,
+    window: Window,
+    next_channel_id: AtomicU32,
+
+    /// The open channels. None once the connection has failed, so later opens fail immediately.
+    channels: Mutex<Option<HashMap<ChannelKey, ChannelSink>>>,
+}
+
+impl MuxShared {
+    /// Creates the local end of a channel, registering it to receive messages.
+    fn register(self: &Arc<Self>, key: ChannelKey, name: String) -> Result<MuxChannel> {
+        let (messages_tx, messages) = mpsc::unbounded();
+        let closed = Arc::new(AtomicBool::new(false));
+        let send_credit = Arc::new(SendCredit::new());
+
+        let sink = ChannelSink {
+            messages: messages_tx,
+            closed: closed.clone(),
+            send_credit: send_credit.clone(),
+            partial: vec![],
+            granted_messages: 0,
+            granted_bytes: 0,
+        };
+
+        match self.channels.lock().unwrap().as_mut() {
+            Some(channels) => {
+                channels.insert(key, sink);
+            }
+            None => return Err(IpcError::PeerDisconnected),
+        };
+
+        Ok(MuxChannel::new(
+            Arc::downgrade(self),
+            key,
+            name,
+            messages,
+            closed,
+            send_credit,
+            ReceiveCredit::new(self.window),
+        ))
+    }
+
+    /// Grants the peer our whole window on a newly opened channel, returning once the grant
+    /// has been written.
+    async fn grant_window(&self, channel: ChannelKey) -> Result<()> {
+        self.writer.write(self.record_grant(channel, self.window)).await
+    }
+
+    /// Grants the peer credit to send more on `channel`. The grant is queued rather than
+    /// awaited, so it's sent even if whoever granted it stops waiting. A failed grant means
+    /// the connection has failed, which the channel's reads report.
+    pub(crate) fn grant(&self, channel: ChannelKey, credit: Window) {
+        self.writer.write_detached(self.record_grant(channel, credit));
+    }
+
+    /// Records credit granted on `channel`, returning the frame granting it. The credit is
+    /// recorded before it's sent, so the peer can never have used credit we don't yet know
+    /// about.
+    fn record_grant(&self, channel: ChannelKey, credit: Window) -> Frame {
+        if let Some(sink) = self.channels.lock().unwrap().as_mut().and_then(|channels| channels.get_mut(&channel)) {
+            sink.granted_messages = sink.granted_messages.saturating_add(credit.messages as u64);
+            sink.granted_bytes = sink.granted_bytes.saturating_add(i64::try_from(credit.bytes).unwrap_or(i64::MAX));
+        }
+
+        Frame::Credit {
+            channel,
+            messages: credit.messages,
+            bytes: credit.bytes,
+        }
+    }
+
+    fn unregister(&self, key: ChannelKey) {
+        if let Some(channels) = self.channels.lock().unwrap().as_mut() {
+            channels.remove(&key);
+        }
+    }
+
+    /// Closes every channel, failing their pending reads with `err`.
+    fn fail(&self, err: &IpcError) {
+        let channels = self.channels.lock().unwrap().take();
+
+        for (_, sink) in channels.into_iter().flatten() {
+            sink.closed.store(true, Ordering::SeqCst);
+            sink.send_credit.fail(err);
+            let _ = sink.messages.unbounded_send(Err(err.duplicate()));
+        }
+    }
+}
+
+/// Carries any number of independent `MuxChannel`s over one message connection. Both ends of
+/// the connection need a multiplexer.
+///
+/// Background tasks read from and write to the connection for the lifetime of the
+/// multiplexer. Dropping the multiplexer closes every channel.
+///
+/// Messages are reassembled up to the connection's maximum message size, if it has one. A
+/// larger message fails the connection with `IpcError::FrameTooLarge`, and a peer that sends
+/// beyond a channel's window fails it with `IpcError::ProtocolMismatch`.
+pub struct Multiplexer {
+    shared: Arc<MuxShared>,
+    incoming: AsyncMutex<mpsc::UnboundedReceiver<Result<MuxChannel>>>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Multiplexer {
+    /// Creates a multiplexer with the default options. Must be called within a tokio runtime,
+    /// which runs the tasks reading from and writing to the connection.
+    pub fn new(connection: MessageIpcConnection) -> Result<Multiplexer> {
+        Multiplexer::with_options(connection, &MuxOptions::default())
+    }
+
+    /// Creates a multiplexer that fragments messages larger than `fragment_size` bytes.
+    pub fn with_fragment_size(connection: MessageIpcConnection, fragment_size: usize) -> Result<Multiplexer> {
+        Multiplexer::with_options(connection, &MuxOptions::new().fragment_size(fragment_size))
+    }
+
+    pub fn with_options(connection: MessageIpcConnection, options: &MuxOptions) -> Result<Multiplexer> {
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| IpcError::Io(std::io::Error::other("Multiplexers must be created within a tokio runtime")))?;
+
+        let connection = Arc::new(connection);
+        let (writer_queue, writer) = MuxWriter::spawn(&runtime, connection.clone(), options.fragment_size);
+
+        let shared = Arc::new(MuxShared {
+            connection,
+            writer: writer_queue,
+            fragment_size: options.fragment_size,
+            window: options.window,
+            next_channel_id: AtomicU32::new(0),
+            channels: Mutex::new(Some(HashMap::new())),
+        });
+
+        let (incoming_tx, incoming) = mpsc::unbounded();
+        let reader = runtime.spawn(read_frames(shared.clone(), incoming_tx));
+
+        Ok(Multiplexer {
+            shared,
+            incoming: AsyncMutex::new(incoming),
+            reader,
+            writer,
+        })
+    }
+
+    /// Opens a channel named `name`. The peer receives it from `accept`.
+    pub async fn open(&self, name: &str) -> Result<MuxChannel> {
+        let key = ChannelKey {
+            opened_locally: true,
+            id: self.shared.next_channel_id.fetch_add(1, Ordering::SeqCst),
+        };
+
+        let channel = self.shared.register(key, name.to_owned())?;
+
+        self.shared.writer.write_detached(Frame::Open {
+            channel: key,
+            name: name.to_owned(),
+        });
+
+        // Frames are written in the order they're queued, so once the grant is written, so is
+        // the open.
+        self.shared.grant_window(key).await?;
+
+        Ok(channel)
+    }
+
+    /// Waits for the peer to open a channel. The peer can't send on the channel until it has
+    /// been accepted.
+    pub async fn accept(&self) -> Result<MuxChannel> {
+        let channel = {
+            let mut incoming = self.incoming.lock().await;
+
+            match incoming.next().await {
+                Some(channel) => channel?,
+                None => return Err(IpcError::PeerDisconnected),
+            }
+        };
+
+        self.shared.grant_window(channel.key()).await?;
+
+        Ok(channel)
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+        self.shared.fail(&IpcError::Cancelled);
+    }
+}
+
+async fn read_frames(shared: Arc<MuxShared>, incoming: mpsc::UnboundedSender<Result<MuxChannel>>) {
+    let err = loop {
+        let frame = match shared.connection.read().await.and_then(|data| Frame::decode(&data)) {
+            Ok(frame) => frame,
+            Err(err) => break err,
+        };
+
+        if let Err(err) = dispatch_frame(&shared, &incoming, frame) {
+            break err;
+        }
+    };
+
+    trace!(%err, "Multiplexer connection failed");
+
+    shared.fail(&err);
+    let _ = incoming.unbounded_send(Err(err));
+}
+
+fn dispatch_frame(
+    shared: &Arc<MuxShared>,
+    incoming: &mpsc::UnboundedSender<Result<MuxChannel>>,
+    frame: Frame,
+) -> Result<()> {
+    match frame {
+        Frame::Open { channel, name } => {
+            if channel.opened_locally {
+                return Err(IpcError::protocol_mismatch("Peer opened a channel with one of our numbers"));
+            }
+
+            let channel = shared.register(channel, name)?;
+            let _ = incoming.unbounded_send(Ok(channel));
+        }
+        Frame::Data { channel, last, body } => {
+            let mut channels = shared.channels.lock().unwrap();
+
+            // The channel may have been closed locally, in which case the rest of its
+            // messages are discarded.
+            let sink = match channels.as_mut().and_then(|channels| channels.get_mut(&channel)) {
+                Some(sink) => sink,
+                None => return Ok(()),
+            };
+
+            // The peer may only start a message while it has credit, as `SendCredit` enforces on
+            // its side.
+            if sink.partial.is_empty() && (sink.granted_messages == 0 || sink.granted_bytes <= 0) {
+                return Err(IpcError::protocol_mismatch("Peer sent beyond the channel's window"));
+            }
+
+            shared.connection.check_message_size((sink.partial.len() + body.len()) as u64)?;
+
+            sink.partial.extend_from_slice(&body);
+
+            if last {
+                let message = std::mem::take(&mut sink.partial);
+
+                sink.granted_messages -= 1;
+                sink.granted_bytes = sink.granted_bytes.saturating_sub(i64::try_from(message.len()).unwrap_or(i64::MAX));
+
+                let _ = sink.messages.unbounded_send(Ok(message));
+            }
+        }
+        Frame::Close { channel } => {
+            let sink = shared.channels.lock().unwrap()
+                .as_mut()
+                .and_then(|channels| channels.remove(&channel));
+
+            if let Some(sink) = sink {
+                sink.closed.store(true, Ordering::SeqCst);
+                sink.send_credit.fail(&IpcError::PeerDisconnected);
+            }
+        }
+        Frame::Credit { channel, messages, bytes } => {
+            let channels = shared.channels.lock().unwrap();
+
+            if let Some(sink) = channels.as_ref().and_then(|channels| channels.get(&channel)) {
+                sink.send_credit.grant(messages, bytes);
+            }
+        }
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::frame::{ChannelKey, Frame};
+    use super::{Multiplexer, MuxOptions};
+    use crate::error::IpcError;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::options::ServerOptions;
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use futures::FutureExt;
+    use tokio::runtime;
+
+    use std::time::Duration;
+
+    #[test]
+    fn channels_are_independent() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = Multiplexer::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server = Multiplexer::new(connection).unwrap();
+
+            let client_logs = client.open("logs").await.unwrap();
+            let client_control = client.open("control").await.unwrap();
+
+            let server_logs = server.accept().await.unwrap();
+            let server_control = server.accept().await.unwrap();
+
+            assert_eq!(server_logs.name(), "logs");
+            assert_eq!(server_control.name(), "control");
+
+            // The server's channels are numbered separately from the client's.
+            let server_status = server.open("status").await.unwrap();
+            let client_status = client.accept().await.unwrap();
+
+            assert_eq!(server_status.id(), client_logs.id());
+            assert_eq!(client_status.name(), "status");
+
+            client_control.write(b"stop").await.unwrap();
+            client_logs.write(b"started").await.unwrap();
+            server_status.write(b"ok").await.unwrap();
+
+            assert_eq!(server_logs.read().await.unwrap(), b"started");
+            assert_eq!(server_control.read().await.unwrap(), b"stop");
+            assert_eq!(client_status.read().await.unwrap(), b"ok");
+
+            client_logs.write(b"stopping").await.unwrap();
+            client_logs.close().await.unwrap();
+
+            // Messages sent before the channel closed are still delivered.
+            assert_eq!(server_logs.read().await.unwrap(), b"stopping");
+
+            match server_logs.read().await {
+                Err(IpcError::PeerDisconnected) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            match server_logs.write(b"hello?").await {
+                Err(IpcError::PeerDisconnected) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            server_control.write(b"stopped").await.unwrap();
+            assert_eq!(client_control.read().await.unwrap(), b"stopped");
+        });
+    }
+
+    #[test]
+    fn large_messages_are_fragmented() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = Multiplexer::with_fragment_size(MessageIpcClient::new(&server_name).unwrap(), 1024).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server = Multiplexer::new(connection).unwrap();
+
+            let client_bulk = client.open("bulk").await.unwrap();
+            let client_chat = client.open("chat").await.unwrap();
+
+            let server_bulk = server.accept().await.unwrap();
+            let server_chat = server.accept().await.unwrap();
+
+            let bulk = (0..1024 * 1024).map(|i| i as u8).collect::<Vec<u8>>();
+
+            let (bulk_written, chat_written) = futures::join!(client_bulk.write(&bulk), client_chat.write(b"neigh"));
+
+            bulk_written.unwrap();
+            chat_written.unwrap();
+
+            assert_eq!(server_chat.read().await.unwrap(), b"neigh");
+            assert_eq!(server_bulk.read().await.unwrap(), bulk);
+        });
+    }
+
+    #[test]
+    fn dropped_writes_send_whole_messages() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = Multiplexer::with_fragment_size(MessageIpcClient::new(&server_name).unwrap(), 1024).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server = Multiplexer::new(connection).unwrap();
+
+            let client_bulk = client.open("bulk").await.unwrap();
+            let server_bulk = server.accept().await.unwrap();
+
+            // Empty messages are still sent, which also makes sure the client has credit.
+            client_bulk.write(b"").await.unwrap();
+            assert_eq!(server_bulk.read().await.unwrap(), b"");
+
+            // Give up on a write after its first poll, partway through its fragments.
+            let bulk = (0..1024 * 1024).map(|i| i as u8).collect::<Vec<u8>>();
+            assert!(client_bulk.write(&bulk).now_or_never().is_none());
+
+            client_bulk.write(b"neigh").await.unwrap();
+
+            assert_eq!(server_bulk.read().await.unwrap(), bulk);
+            assert_eq!(server_bulk.read().await.unwrap(), b"neigh");
+        });
+    }
+
+    #[test]
+    fn channels_wait_for_their_own_credit() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = Multiplexer::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server = Multiplexer::with_options(connection, &MuxOptions::new().window_messages(1)).unwrap();
+
+            let client_slow = client.open("slow").await.unwrap();
+            let client_fast = client.open("fast").await.unwrap();
+
+            let server_slow = server.accept().await.unwrap();
+            let server_fast = server.accept().await.unwrap();
+
+            // The slow channel's window is full until the server reads from it, but the fast
+            // channel is unaffected.
+            client_slow.write(b"one").await.unwrap();
+            assert!(tokio::time::timeout(Duration::from_millis(200), client_slow.write(b"two")).await.is_err());
+
+            client_fast.write(b"one").await.unwrap();
+            assert_eq!(server_fast.read().await.unwrap(), b"one");
+
+            let (written, read) = futures::join!(client_slow.write(b"two"), async {
+                (server_slow.read().await.unwrap(), server_slow.read().await.unwrap())
+            });
+
+            written.unwrap();
+            assert_eq!(read, (b"one".to_vec(), b"two".to_vec()));
+        });
+    }
+
+    #[test]
+    fn channels_fail_when_the_connection_does() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = Multiplexer::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server = Multiplexer::new(connection).unwrap();
+
+            let channel = client.open("logs").await.unwrap();
+            let _accepted = server.accept().await.unwrap();
+
+            drop(server);
+
+            match channel.read().await {
+                Err(IpcError::PeerDisconnected) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            assert!(client.open("control").await.is_err());
+        });
+    }
+
+    #[test]
+    fn reassembly_is_bounded_by_the_max_message_size() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::with_options(&server_name, &ServerOptions::new().max_message_size(2048)).unwrap();
+            let client = Multiplexer::with_fragment_size(MessageIpcClient::new(&server_name).unwrap(), 1024).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server = Multiplexer::new(connection).unwrap();
+
+            let client_bulk = client.open("bulk").await.unwrap();
+            let server_bulk = server.accept().await.unwrap();
+
+            // Every fragment fits, but the message they make up doesn't.
+            client_bulk.write(&[7; 1536]).await.unwrap();
+            assert_eq!(server_bulk.read().await.unwrap(), vec![7; 1536]);
+
+            client_bulk.write(&[7; 4096]).await.unwrap();
+
+            match server_bulk.read().await {
+                Err(IpcError::FrameTooLarge { size: 3072, max: 2048 }) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+        });
+    }
+
+    #[test]
+    fn peers_cant_send_beyond_the_window() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = MessageIpcClient::new(&server_name).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+            let server = Multiplexer::with_options(connection, &MuxOptions::new().window_messages(1)).unwrap();
+
+            // A peer that ignores flow control, sending without waiting for credit.
+            let channel = ChannelKey { opened_locally: true, id: 0 };
+
+            client.write(&Frame::Open { channel, name: "flood".to_owned() }.encode()).await.unwrap();
+
+            for _ in 0..2 {
+                let data = Frame::Data { channel, last: true, body: b"neigh".to_vec() };
+                client.write(&data.encode()).await.unwrap();
+            }
+
+            let accepted = server.accept().await.unwrap();
+
+            loop {
+                match accepted.read().await {
+                    Ok(message) => assert_eq!(message, b"neigh"),
+                    Err(IpcError::ProtocolMismatch(_)) => break,
+                    Err(err) => panic!("Unexpected error {:?}", err),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,212 @@
+use super::frame::{ChannelKey, Frame};
+use crate::error::{IpcError, Result};
+use crate::ipc::MessageIpcConnection;
+
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tracing::{trace, Instrument, Span};
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// A frame waiting to be written, the span it was queued in, and where to report how writing
+/// it went.
+type Queued = (Frame, Span, Option<oneshot::Sender<Result<()>>>);
+
+/// Writes a multiplexer's frames to its connection from a task of its own.
+///
+/// Data frames are queued holding a whole message, which the writer splits into fragments, so
+/// a write dropped partway can't leave a message half sent. Channels take turns sending a
+/// fragment at a time, so one large message doesn't hold up the others, and open and credit
+/// frames go ahead of any data waiting. A failed write fails everything queued, since the
+/// connection may have been left partway through a frame.
+#[derive(Clone)]
+pub(crate) struct MuxWriter {
+    frames: mpsc::UnboundedSender<Queued>,
+}
+
+impl MuxWriter {
+    /// Starts a task on `runtime` writing to `connection`, splitting messages into fragments
+    /// of at most `fragment_size` bytes. It runs until every clone of the writer has been
+    /// dropped and the frames already queued have been written.
+    pub(crate) fn spawn(
+        runtime: &Handle,
+        connection: Arc<MessageIpcConnection>,
+        fragment_size: usize,
+    ) -> (MuxWriter, JoinHandle<()>) {
+        let (frames, queue) = mpsc::unbounded();
+        let task = runtime.spawn(write_queued(connection, fragment_size, queue));
+
+        (MuxWriter { frames }, task)
+    }
+
+    /// Writes `frame`, returning once it has been written. The frame is queued before this
+    /// first waits, so it's written even if the caller stops waiting.
+    pub(crate) async fn write(&self, frame: Frame) -> Result<()> {
+        let (written_tx, written_rx) = oneshot::channel();
+
+        self.queue(frame, Some(written_tx))?;
+
+        written_rx.await.unwrap_or(Err(IpcError::PeerDisconnected))
+    }
+
+    /// Queues `frame` to be written without waiting for it, for where we can't wait, such as
+    /// in `Drop`.
+    pub(crate) fn write_detached(&self, frame: Frame) {
+        if let Err(err) = self.queue(frame, None) {
+            trace!(%err, "Failed to queue multiplexer frame");
+        }
+    }
+
+    fn queue(&self, frame: Frame, written: Option<oneshot::Sender<Result<()>>>) -> Result<()> {
+        self.frames.unbounded_send((frame, Span::current(), written)).map_err(|_| IpcError::PeerDisconnected)
+    }
+}
+
+/// A frame taken from the queue but not yet written.
+struct Outgoing {
+    frame: Frame,
+
+    /// How much of a data frame's message has been sent so far.
+    sent: usize,
+
+    span: Span,
+    written: Option<oneshot::Sender<Result<()>>>,
+}
+
+impl Outgoing {
+    fn report(self, result: &Result<()>) {
+        if let Some(written) = self.written {
+            let _ = written.send(result.as_ref().copied().map_err(IpcError::duplicate));
+        }
+    }
+}
+
+/// The frames the writer has taken from its queue but not yet written.
+#[derive(Default)]
+struct Pending {
+    control: VecDeque<Outgoing>,
+    channels: HashMap<ChannelKey, VecDeque<Outgoing>>,
+
+    /// The channels with something to send, in the order they take turns.
+    ready: VecDeque<ChannelKey>,
+}
+
+impl Pending {
+    fn push(&mut self, (frame, span, written): Queued) {
+        let channel = match &frame {
+            Frame::Data { channel, .. } | Frame::Close { channel } => *channel,
+            Frame::Open { .. } | Frame::Credit { .. } => {
+                self.control.push_back(Outgoing { frame, sent: 0, span, written });
+                return;
+            }
+        };
+
+        let queue = self.channels.entry(channel).or_default();
+
+        if queue.is_empty() {
+            self.ready.push_back(channel);
+        }
+
+        queue.push_back(Outgoing { frame, sent: 0, span, written });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.ready.is_empty()
+    }
+
+    /// Writes the next control frame, or else the next fragment of the channel whose turn it
+    /// is.
+    async fn write_next(&mut self, connection: &MessageIpcConnection, fragment_size: usize) -> Result<()> {
+        if let Some(outgoing) = self.control.pop_front() {
+            let result = connection.write(&outgoing.frame.encode()).instrument(outgoing.span.clone()).await;
+            outgoing.report(&result);
+
+            return result;
+        }
+
+        let channel = self.ready.pop_front().expect("a channel is ready");
+        let queue = self.channels.get_mut(&channel).expect("ready channels have frames queued");
+        let outgoing = queue.front_mut().expect("ready channels have frames queued");
+
+        let (data, done) = match &outgoing.frame {
+            Frame::Data { body, .. } => {
+                let end = body.len().min(outgoing.sent + fragment_size);
+                let last = end == body.len();
+
+                let fragment = Frame::Data {
+                    channel,
+                    last,
+                    body: body[outgoing.sent..end].to_vec(),
+                };
+
+                outgoing.sent = end;
+                (fragment.encode(), last)
+            }
+            frame => (frame.encode(), true),
+        };
+
+        let result = connection.write(&data).instrument(outgoing.span.clone()).await;
+
+        if done || result.is_err() {
+            queue.pop_front().unwrap().report(&result);
+        }
+
+        if queue.is_empty() {
+            self.channels.remove(&channel);
+        } else {
+            self.ready.push_back(channel);
+        }
+
+        result
+    }
+
+    /// Reports `err` as the outcome of every frame still pending.
+    fn fail(self, err: &IpcError) {
+        let outgoing = self.control.into_iter().chain(self.channels.into_values().flatten());
+
+        for outgoing in outgoing {
+            outgoing.report(&Err(err.duplicate()));
+        }
+    }
+}
+
+async fn write_queued(
+    connection: Arc<MessageIpcConnection>,
+    fragment_size: usize,
+    mut queue: mpsc::UnboundedReceiver<Queued>,
+) {
+    let mut pending = Pending::default();
+
+    let err = loop {
+        if pending.is_empty() {
+            match queue.next().await {
+                Some(queued) => pending.push(queued),
+                None => return,
+            }
+        }
+
+        // Take everything queued since, so control frames go ahead of it.
+        while let Ok(queued) = queue.try_recv() {
+            pending.push(queued);
+        }
+
+        if let Err(err) = pending.write_next(&connection, fragment_size).await {
+            break err;
+        }
+    };
+
+    trace!(%err, "Multiplexer connection failed");
+
+    // Close the queue before reporting the failure, so nothing queued afterwards is left
+    // waiting to hear how it went.
+    queue.close();
+
+    while let Ok(queued) = queue.try_recv() {
+        pending.push(queued);
+    }
+
+    pending.fail(&err);
+}
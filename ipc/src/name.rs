@@ -0,0 +1,232 @@
+use crate::error::{IpcError, Result};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The longest name accepted, in bytes. Unix socket paths are limited to around 100 bytes
+/// including their directory, so long names may still fail there.
+pub const MAX_NAME_LEN: usize = 128;
+
+/// Who can see an endpoint with a given name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// Every user on the machine.
+    Global,
+
+    /// Only the current user's processes, so two users running the same application don't
+    /// collide. The default.
+    User,
+
+    /// Only the current user's processes in the current login session.
+    Session,
+}
+
+/// The name of an IPC endpoint, which maps to the same logical endpoint on every platform.
+///
+/// Names are made of one or more segments separated by `/`, each consisting of ASCII letters,
+/// digits, `-`, `_` and `.`, and not starting with `.`. The name is resolved according to its
+/// `Scope`:
+///
+/// | Scope     | Windows                              | Unix                                          |
+/// |-----------|--------------------------------------|-----------------------------------------------|
+/// | `Global`  | `\\.\pipe\<name>`                    | `<temp dir>/<name>`                           |
+/// | `User`    | `\\.\pipe\<user SID>\<name>`         | `$XDG_RUNTIME_DIR/<name>`                     |
+/// | `Session` | `\\.\pipe\<user SID>\session-<id>\<name>` | `$XDG_RUNTIME_DIR/session-<id>/<name>`   |
+///
+/// Without `$XDG_RUNTIME_DIR`, user-scoped sockets live in a private `<temp dir>/ipc-<uid>`
+/// directory instead. On Linux, names may instead live in the abstract socket namespace,
+/// which has no files to clean up or protect; see `in_abstract_namespace`.
+///
+/// Scoping keeps users' endpoints apart; it doesn't stop another user from deliberately
+/// creating an endpoint with a scoped name. Check `peer_credentials` where that matters.
+///
+/// Servers and clients accept a `&str` or `String` anywhere they accept an `IpcName`, which
+/// is parsed with `IpcName::new`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IpcName {
+    name: String,
+    scope: Scope,
+    abstract_namespace: bool,
+}
+
+impl IpcName {
+    /// A name scoped to the current user. Fails with `InvalidInput` if the name isn't valid.
+    pub fn new(name: &str) -> Result<IpcName> {
+        IpcName::with_scope(name, Scope::User)
+    }
+
+    /// A name visible to every user on the machine.
+    pub fn global(name: &str) -> Result<IpcName> {
+        IpcName::with_scope(name, Scope::Global)
+    }
+
+    /// A name scoped to the current user's login session.
+    pub fn session(name: &str) -> Result<IpcName> {
+        IpcName::with_scope(name, Scope::Session)
+    }
+
+    pub fn with_scope(name: &str, scope: Scope) -> Result<IpcName> {
+        validate(name)?;
+
+        Ok(IpcName {
+            name: name.to_owned(),
+            scope,
+            abstract_namespace: false,
+        })
+    }
+
+    /// Puts the name in Linux's abstract socket namespace rather than the filesystem.
+    /// Abstract sockets vanish with their server, but can't be protected with file
+    /// permissions, so `ServerOptions::mode`, `owner` and `group` don't apply to them. Has no
+    /// effect on other platforms.
+    pub fn in_abstract_namespace(mut self) -> Self {
+        self.abstract_namespace = true;
+        self
+    }
+
+    /// The name, without its scope.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn scope(&self) -> Scope {
+        self.scope
+    }
+
+    /// Whether the name lives in the abstract socket namespace. Always false outside Linux.
+    pub fn is_abstract(&self) -> bool {
+        cfg!(target_os = "linux") && self.abstract_namespace
+    }
+
+    /// The name's segments, in order.
+    pub(crate) fn segments(&self) -> impl Iterator<Item = &str> {
+        self.name.split('/')
+    }
+}
+
+impl fmt::Display for IpcName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl FromStr for IpcName {
+    type Err = IpcError;
+
+    fn from_str(name: &str) -> Result<IpcName> {
+        IpcName::new(name)
+    }
+}
+
+fn validate(name: &str) -> Result<()> {
+    let invalid = |reason: &str| {
+        Err(IpcError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid IPC name {:?}: {}", name, reason),
+        )))
+    };
+
+    if name.is_empty() {
+        return invalid("names can't be empty");
+    }
+
+    if name.len() > MAX_NAME_LEN {
+        return invalid("names are limited to 128 bytes");
+    }
+
+    // Servers keep a lock file next to their socket.
+    if name.ends_with(".lock") {
+        return invalid("names can't end with .lock");
+    }
+
+    for segment in name.split('/') {
+        if segment.is_empty() {
+            return invalid("segments can't be empty");
+        }
+
+        if segment.starts_with('.') {
+            return invalid("segments can't start with '.'");
+        }
+
+        if !segment.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'.') {
+            return invalid("names may only contain ASCII letters, digits, '-', '_', '.' and '/'");
+        }
+    }
+
+    Ok(())
+}
+
+/// Anything servers and clients accept as the name of an endpoint.
+pub trait IntoIpcName {
+    fn into_ipc_name(self) -> Result<IpcName>;
+}
+
+impl IntoIpcName for IpcName {
+    fn into_ipc_name(self) -> Result<IpcName> {
+        Ok(self)
+    }
+}
+
+impl IntoIpcName for &IpcName {
+    fn into_ipc_name(self) -> Result<IpcName> {
+        Ok(self.clone())
+    }
+}
+
+impl IntoIpcName for &str {
+    fn into_ipc_name(self) -> Result<IpcName> {
+        IpcName::new(self)
+    }
+}
+
+impl IntoIpcName for &String {
+    fn into_ipc_name(self) -> Result<IpcName> {
+        IpcName::new(self)
+    }
+}
+
+impl IntoIpcName for String {
+    fn into_ipc_name(self) -> Result<IpcName> {
+        IpcName::new(&self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpcName, Scope};
+    use crate::error::IpcError;
+
+    use std::io::ErrorKind;
+
+    #[test]
+    fn validates_names() {
+        for name in &["app", "my-app_2.0", "vendor/app/control", "a"] {
+            assert!(IpcName::new(name).is_ok(), "{}", name);
+        }
+
+        let long = "a".repeat(129);
+
+        for name in &["", "a b", "app/", "/app", "a//b", ".hidden", "a/../b", "app.lock", "pipe\\name", "ünïcode", &long] {
+            match IpcName::new(name) {
+                Err(IpcError::Io(err)) if err.kind() == ErrorKind::InvalidInput => {}
+                result => panic!("Unexpected result for {:?}: {:?}", name, result),
+            }
+        }
+    }
+
+    #[test]
+    fn names_keep_their_scope() {
+        let name: IpcName = "vendor/app".parse().unwrap();
+
+        assert_eq!(name.scope(), Scope::User);
+        assert_eq!(name.to_string(), "vendor/app");
+        assert_eq!(name.segments().collect::<Vec<_>>(), vec!["vendor", "app"]);
+
+        assert_eq!(IpcName::global("app").unwrap().scope(), Scope::Global);
+        assert_eq!(IpcName::session("app").unwrap().scope(), Scope::Session);
+        assert_ne!(IpcName::global("app").unwrap(), IpcName::new("app").unwrap());
+
+        let name = IpcName::new("app").unwrap().in_abstract_namespace();
+        assert_eq!(name.is_abstract(), cfg!(target_os = "linux"));
+    }
+}
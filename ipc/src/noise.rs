@@ -0,0 +1,470 @@
+//! Encrypted and authenticated connections using the Noise protocol.
+//!
+//! `SecureConnection` runs a `Noise_XX_25519_ChaChaPoly_BLAKE2s` handshake over a
+//! `MessageIpcConnection`, after which every message is encrypted and authenticated. Both ends
+//! have a static `Keypair` and learn each other's public key during the handshake. Clients pin
+//! the server's public key, so they never send anything to an impostor; servers can check the
+//! client's with `remote_public_key`.
+//!
+//! ```no_run
+//! # use ipc::{MessageIpcClient, MessageIpcServer};
+//! # use ipc::noise::{Keypair, SecureConnection};
+//! # async fn run(server_keys: Keypair, client_keys: Keypair) -> ipc::Result<()> {
+//! // The server.
+//! let (connection, _server) = MessageIpcServer::new("my_server")?.wait_for_connection().await?;
+//! let connection = SecureConnection::accept(connection, &server_keys).await?;
+//!
+//! // The client, which was given the server's public key out of band.
+//! let connection = MessageIpcClient::new("my_server")?;
+//! let connection = SecureConnection::connect(connection, &client_keys, server_keys.public_key()).await?;
+//! connection.write(b"hello").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::credentials::PeerCredentials;
+use crate::error::{IpcError, Result};
+use crate::ipc::MessageIpcConnection;
+
+use futures::lock::Mutex;
+use snow::params::{DHChoice, NoiseParams};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+
+use std::convert::TryInto;
+use std::fmt;
+use std::time::Duration;
+
+const PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Mixed into the handshake, so it fails against a peer speaking anything else.
+const PROLOGUE: &[u8] = b"ipc-noise/1";
+
+/// The largest Noise message, and so the largest frame either end reads.
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+const TAG_LEN: usize = 16;
+
+/// The most of a message each Noise message carries. Each starts with a flag saying whether
+/// more of the message follows.
+const MAX_CHUNK: usize = MAX_NOISE_MESSAGE - TAG_LEN - 1;
+
+const MORE: u8 = 1;
+const LAST: u8 = 0;
+
+/// How long the peer has to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A Curve25519 key pair identifying one end of a `SecureConnection`. Its `Debug` output
+/// doesn't include the private key.
+#[derive(Clone)]
+pub struct Keypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl Keypair {
+    /// Generates a new random key pair.
+    pub fn generate() -> Result<Keypair> {
+        let keypair = Builder::new(params()).generate_keypair().map_err(noise_error)?;
+
+        Ok(Keypair {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    /// The key pair with the given 32 byte private key, e.g. one saved from `private_key`.
+    pub fn from_private_key(private: &[u8]) -> Result<Keypair> {
+        let mut dh = DefaultResolver.resolve_dh(&DHChoice::Curve25519).expect("Curve25519 is supported");
+
+        if private.len() != dh.priv_len() {
+            return Err(IpcError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Private keys are {} bytes", dh.priv_len()),
+            )));
+        }
+
+        dh.set(private);
+
+        Ok(Keypair {
+            private: private.to_vec(),
+            public: dh.pubkey().to_vec(),
+        })
+    }
+
+    /// The public key, which peers pin to recognize us.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    /// The private key, to save the key pair. Keep it secret.
+    pub fn private_key(&self) -> &[u8] {
+        &self.private
+    }
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair").field("public", &self.public).finish_non_exhaustive()
+    }
+}
+
+/// An encrypted, authenticated connection over a `MessageIpcConnection`. Reads and writes may
+/// be issued from several tasks at once, as on the underlying connection.
+///
+/// Like the underlying connection's, reads and writes shouldn't be abandoned partway, e.g. by
+/// racing them against a timeout. A read dropped between Noise messages keeps what it has
+/// decrypted for the next read, but one dropped partway through a frame loses its place in
+/// the stream. A write dropped partway may have sent only part of its message, after which the
+/// peer can't make sense of what follows.
+pub struct SecureConnection {
+    connection: MessageIpcConnection,
+    transport: StatelessTransportState,
+    max_message_size: Option<u64>,
+
+    /// Locked for the whole of each read.
+    read_state: Mutex<ReadState>,
+
+    /// The nonce of the next Noise message to write, locked for the whole of each write.
+    write_nonce: Mutex<u64>,
+}
+
+struct ReadState {
+    /// The nonce of the next Noise message to read.
+    nonce: u64,
+
+    /// What has been decrypted so far of the message being read.
+    partial: Vec<u8>,
+}
+
+impl SecureConnection {
+    /// Runs the server's half of the handshake on a connection just accepted.
+    pub async fn accept(connection: MessageIpcConnection, keypair: &Keypair) -> Result<SecureConnection> {
+        let handshake = builder(keypair).build_responder().map_err(noise_error)?;
+
+        SecureConnection::handshake(connection, handshake, None).await
+    }
+
+    /// Runs the client's half of the handshake, failing with `AuthenticationFailed` before
+    /// sending anything of ours if the server's public key isn't `server_public_key`.
+    pub async fn connect(
+        connection: MessageIpcConnection,
+        keypair: &Keypair,
+        server_public_key: &[u8],
+    ) -> Result<SecureConnection> {
+        let handshake = builder(keypair).build_initiator().map_err(noise_error)?;
+
+        SecureConnection::handshake(connection, handshake, Some(server_public_key)).await
+    }
+
+    async fn handshake(
+        mut connection: MessageIpcConnection,
+        mut handshake: HandshakeState,
+        pinned_key: Option<&[u8]>,
+    ) -> Result<SecureConnection> {
+        // Every frame from here on is a single Noise message.
+        let max_message_size = connection.max_message_size();
+        connection.set_max_message_size(Some(MAX_NOISE_MESSAGE as u64));
+
+        let exchange = async {
+            let mut buffer = vec![0; MAX_NOISE_MESSAGE];
+
+            // -> e; <- e, ee, s, es; -> s, se
+            while !handshake.is_handshake_finished() {
+                if handshake.is_my_turn() {
+                    let len = handshake.write_message(&[], &mut buffer).map_err(noise_error)?;
+                    connection.write(&buffer[..len]).await?;
+                } else {
+                    handshake.read_message(&connection.read().await?, &mut buffer).map_err(noise_error)?;
+
+                    // Learned from the server's message, before we reveal who we are.
+                    if let Some(pinned_key) = pinned_key {
+                        if handshake.get_remote_static() != Some(pinned_key) {
+                            return Err(IpcError::AuthenticationFailed);
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        };
+
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange).await.unwrap_or_else(|_| {
+            Err(IpcError::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "The Noise handshake timed out",
+            )))
+        })?;
+
+        Ok(SecureConnection {
+            connection,
+            transport: handshake.into_stateless_transport_mode().map_err(noise_error)?,
+            max_message_size,
+            read_state: Mutex::new(ReadState {
+                nonce: 0,
+                partial: Vec::new(),
+            }),
+            write_nonce: Mutex::new(0),
+        })
+    }
+
+    /// The peer's static public key, as proven during the handshake.
+    pub fn remote_public_key(&self) -> &[u8] {
+        self.transport.get_remote_static().expect("XX handshakes exchange static keys")
+    }
+
+    /// Who is on the other end of the underlying connection.
+    pub fn peer_credentials(&self) -> &PeerCredentials {
+        self.connection.peer_credentials()
+    }
+
+    pub async fn read(&self) -> Result<Vec<u8>> {
+        let mut state = self.read_state.lock().await;
+        let mut chunk = vec![0; MAX_NOISE_MESSAGE];
+
+        loop {
+            let ciphertext = self.connection.read().await?;
+
+            let len = self
+                .transport
+                .read_message(state.nonce, &ciphertext, &mut chunk)
+                .map_err(|_| IpcError::protocol_mismatch("A message failed to decrypt"))?;
+
+            state.nonce += 1;
+
+            let (flag, data) = match chunk[..len].split_first() {
+                Some((&flag, data)) if flag == MORE || flag == LAST => (flag, data),
+                _ => return Err(IpcError::protocol_mismatch("Malformed Noise message")),
+            };
+
+            state.partial.extend_from_slice(data);
+
+            if let Some(max) = self.max_message_size {
+                let size = state.partial.len() as u64;
+
+                if size > max {
+                    state.partial.clear();
+                    return Err(IpcError::FrameTooLarge { size, max });
+                }
+            }
+
+            if flag == LAST {
+                return Ok(std::mem::take(&mut state.partial));
+            }
+        }
+    }
+
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        if let Some(max) = self.max_message_size {
+            let size = data.len().try_into().unwrap_or(u64::MAX);
+
+            if size > max {
+                return Err(IpcError::FrameTooLarge { size, max });
+            }
+        }
+
+        let mut nonce = self.write_nonce.lock().await;
+        let mut plaintext = Vec::with_capacity(MAX_CHUNK + 1);
+        let mut ciphertext = vec![0; MAX_NOISE_MESSAGE];
+
+        // An empty message still takes one Noise message.
+        let mut chunks = data.chunks(MAX_CHUNK).collect::<Vec<_>>();
+
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        let last = chunks.len() - 1;
+
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            plaintext.clear();
+            plaintext.push(if i == last { LAST } else { MORE });
+            plaintext.extend_from_slice(chunk);
+
+            let len = self.transport.write_message(*nonce, &plaintext, &mut ciphertext).map_err(noise_error)?;
+
+            // Only once it's written is the nonce used, so a write dropped before it starts
+            // doesn't leave the peer expecting a later one.
+            self.connection.write(&ciphertext[..len]).await?;
+            *nonce += 1;
+        }
+
+        Ok(())
+    }
+}
+
+fn params() -> NoiseParams {
+    PARAMS.parse().expect("PARAMS is a valid Noise protocol name")
+}
+
+fn builder(keypair: &Keypair) -> Builder<'_> {
+    Builder::new(params()).prologue(PROLOGUE).local_private_key(&keypair.private)
+}
+
+/// Handshake messages that fail to decrypt come from a peer without the keys it claims.
+fn noise_error(err: snow::Error) -> IpcError {
+    match err {
+        snow::Error::Decrypt => IpcError::AuthenticationFailed,
+        err => IpcError::protocol_mismatch(format!("Noise error: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Keypair, SecureConnection, LAST, MAX_CHUNK, MAX_NOISE_MESSAGE, MORE};
+    use crate::error::IpcError;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::options::ServerOptions;
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+
+    use std::time::Duration;
+
+    #[test]
+    fn messages_are_exchanged_over_a_secure_channel() {
+        install_logger();
+
+        let name = get_server_name();
+        let server_keys = Keypair::generate().unwrap();
+        let client_keys = Keypair::generate().unwrap();
+        let client_public_key = client_keys.public_key().to_vec();
+        let pool = runtime::Runtime::new().unwrap();
+
+        // Larger than one Noise message, so it's split.
+        let large = (0..3 * MAX_CHUNK + 7).map(|i| i as u8).collect::<Vec<_>>();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&name).unwrap();
+            let server_public_key = server_keys.public_key().to_vec();
+            let expected = large.clone();
+
+            let accepted = tokio::spawn(async move {
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+                let connection = SecureConnection::accept(connection, &server_keys).await.unwrap();
+
+                assert_eq!(connection.remote_public_key(), &client_public_key[..]);
+                assert_eq!(connection.read().await.unwrap(), b"hello");
+                assert_eq!(connection.read().await.unwrap(), b"");
+                assert_eq!(connection.read().await.unwrap(), expected);
+
+                connection.write(b"welcome").await.unwrap();
+            });
+
+            let connection = MessageIpcClient::new(&name).unwrap();
+            let connection = SecureConnection::connect(connection, &client_keys, &server_public_key).await.unwrap();
+
+            connection.write(b"hello").await.unwrap();
+            connection.write(b"").await.unwrap();
+            connection.write(&large).await.unwrap();
+            assert_eq!(connection.read().await.unwrap(), b"welcome");
+
+            accepted.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn reads_dropped_between_noise_messages_keep_what_they_read() {
+        install_logger();
+
+        let name = get_server_name();
+        let server_keys = Keypair::generate().unwrap();
+        let client_keys = Keypair::generate().unwrap();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&name).unwrap();
+            let server_public_key = server_keys.public_key().to_vec();
+
+            let accepted = tokio::spawn(async move {
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+                SecureConnection::accept(connection, &server_keys).await.unwrap()
+            });
+
+            let connection = MessageIpcClient::new(&name).unwrap();
+            let client = SecureConnection::connect(connection, &client_keys, &server_public_key).await.unwrap();
+            let server = accepted.await.unwrap();
+
+            // Sends a message in two Noise messages with a pause between them, as a slow peer
+            // might.
+            let mut ciphertext = vec![0; MAX_NOISE_MESSAGE];
+
+            for (nonce, plaintext) in [[MORE, 1], [LAST, 2]].iter().enumerate() {
+                let len = client.transport.write_message(nonce as u64, plaintext, &mut ciphertext).unwrap();
+                client.connection.write(&ciphertext[..len]).await.unwrap();
+
+                if nonce == 0 {
+                    assert!(tokio::time::timeout(Duration::from_millis(50), server.read()).await.is_err());
+                }
+            }
+
+            assert_eq!(server.read().await.unwrap(), [1, 2]);
+        });
+    }
+
+    #[test]
+    fn clients_reject_servers_with_other_keys() {
+        install_logger();
+
+        let name = get_server_name();
+        let impostor_keys = Keypair::generate().unwrap();
+        let pinned_key = Keypair::generate().unwrap().public_key().to_vec();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&name).unwrap();
+
+            let accepted = tokio::spawn(async move {
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+                SecureConnection::accept(connection, &impostor_keys).await.err()
+            });
+
+            let connection = MessageIpcClient::new(&name).unwrap();
+            let result = SecureConnection::connect(connection, &Keypair::generate().unwrap(), &pinned_key).await;
+
+            assert!(matches!(result, Err(IpcError::AuthenticationFailed)));
+            assert!(matches!(accepted.await.unwrap(), Some(IpcError::PeerDisconnected)));
+        });
+    }
+
+    #[test]
+    fn tampered_messages_fail_to_decrypt() {
+        install_logger();
+
+        let name = get_server_name();
+        let server_keys = Keypair::generate().unwrap();
+        let server_public_key = server_keys.public_key().to_vec();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::with_options(&name, &ServerOptions::new()).unwrap();
+
+            let accepted = tokio::spawn(async move {
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+                let connection = SecureConnection::accept(connection, &server_keys).await.unwrap();
+                connection.read().await.err()
+            });
+
+            let connection = MessageIpcClient::new(&name).unwrap();
+            let connection = SecureConnection::connect(connection, &Keypair::generate().unwrap(), &server_public_key)
+                .await
+                .unwrap();
+
+            // Bypass the encryption, as an attacker who could inject frames would.
+            connection.connection.write(&[0; 32]).await.unwrap();
+
+            assert!(matches!(accepted.await.unwrap(), Some(IpcError::ProtocolMismatch(_))));
+        });
+    }
+
+    #[test]
+    fn keypairs_round_trip_through_their_private_key() {
+        let keypair = Keypair::generate().unwrap();
+        let restored = Keypair::from_private_key(keypair.private_key()).unwrap();
+
+        assert_eq!(restored.public_key(), keypair.public_key());
+        assert!(!format!("{:?}", keypair).contains(&format!("{:?}", keypair.private_key())));
+        assert!(Keypair::from_private_key(&[1, 2, 3]).is_err());
+    }
+}
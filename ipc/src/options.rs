@@ -0,0 +1,237 @@
+use crate::access::AccessPolicy;
+#[cfg(feature = "auth")]
+use crate::auth::PreSharedKey;
+
+use std::time::Duration;
+
+/// Options controlling how an IPC server creates its endpoint. These are shared by
+/// `RawIpcServer` and `MessageIpcServer`.
+///
+/// ```no_run
+/// # use ipc::{MessageIpcServer, ServerOptions};
+/// let options = ServerOptions::new()
+///     .max_instances(4)
+///     .max_message_size(1024 * 1024);
+///
+/// let server = MessageIpcServer::with_options("my_server", &options);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    pub(crate) max_instances: Option<u32>,
+    pub(crate) first_instance: bool,
+    pub(crate) in_buffer_size: u32,
+    pub(crate) out_buffer_size: u32,
+    pub(crate) default_timeout: Option<Duration>,
+    pub(crate) max_message_size: Option<u64>,
+    pub(crate) access_policy: AccessPolicy,
+
+    #[cfg(feature = "auth")]
+    pub(crate) pre_shared_key: Option<PreSharedKey>,
+
+    #[cfg(unix)]
+    pub(crate) mode: Option<u32>,
+
+    #[cfg(unix)]
+    pub(crate) owner: Option<u32>,
+
+    #[cfg(unix)]
+    pub(crate) group: Option<u32>,
+
+    #[cfg(unix)]
+    pub(crate) directory_mode: u32,
+
+    #[cfg(windows)]
+    pub(crate) security_descriptor: Option<String>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            max_instances: None,
+            first_instance: true,
+            in_buffer_size: 0,
+            out_buffer_size: 0,
+            default_timeout: None,
+            max_message_size: None,
+            access_policy: AccessPolicy::new(),
+
+            #[cfg(feature = "auth")]
+            pre_shared_key: None,
+
+            #[cfg(unix)]
+            mode: None,
+
+            #[cfg(unix)]
+            owner: None,
+
+            #[cfg(unix)]
+            group: None,
+
+            #[cfg(unix)]
+            directory_mode: 0o700,
+
+            #[cfg(windows)]
+            security_descriptor: None,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Creates the default options: unlimited instances, first-instance exclusivity,
+    /// OS default buffer sizes, no limit on message size and connections from anyone.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum number of connections the server may have open at once. Once reached,
+    /// `wait_for_connection` waits for a connection to be dropped before accepting another
+    /// client. Passing 0 is treated as 1.
+    ///
+    /// On Windows, clients over the limit find no free pipe instance and fail to connect, or
+    /// wait up to their timeout for one. On Unix the limit isn't enforced when clients
+    /// connect: the kernel completes their connections into the listen backlog, where they
+    /// wait, connected but unanswered, until the server accepts them.
+    pub fn max_instances(mut self, max_instances: u32) -> Self {
+        self.max_instances = Some(max_instances.max(1));
+        self
+    }
+
+    /// When true (the default), creating the server fails if another server already owns
+    /// the name. On Windows, passing false allows several servers to share one pipe name.
+    /// On Unix, a socket path can only ever be owned by one server, so this is always
+    /// in effect.
+    pub fn first_instance(mut self, first_instance: bool) -> Self {
+        self.first_instance = first_instance;
+        self
+    }
+
+    /// The number of bytes to reserve for the receive buffer. 0 uses the OS default.
+    pub fn in_buffer_size(mut self, size: u32) -> Self {
+        self.in_buffer_size = size;
+        self
+    }
+
+    /// The number of bytes to reserve for the send buffer. 0 uses the OS default.
+    pub fn out_buffer_size(mut self, size: u32) -> Self {
+        self.out_buffer_size = size;
+        self
+    }
+
+    /// The pipe's default timeout, used by clients that wait on a busy server with
+    /// `NMPWAIT_USE_DEFAULT_WAIT`. Unix sockets carry no such setting, so this only
+    /// applies on Windows.
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.default_timeout = Some(timeout);
+        self
+    }
+
+    /// The largest message a `MessageIpcConnection` will read or write. Messages over
+    /// this size fail rather than being allocated; an oversized incoming message is read and
+    /// discarded, so the connection stays usable. Has no effect on raw connections.
+    pub fn max_message_size(mut self, size: u64) -> Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    /// Which peers the server accepts connections from. Connections from other peers are
+    /// closed as soon as they're made, and never returned by `wait_for_connection`.
+    pub fn access_policy(mut self, policy: AccessPolicy) -> Self {
+        self.access_policy = policy;
+        self
+    }
+
+    #[cfg(unix)]
+    /// The file mode of the created socket, e.g. 0o600 to only allow the current user
+    /// to connect.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    #[cfg(unix)]
+    /// The uid that should own the created socket. Changing the owner usually requires
+    /// privileges.
+    pub fn owner(mut self, uid: u32) -> Self {
+        self.owner = Some(uid);
+        self
+    }
+
+    #[cfg(unix)]
+    /// The gid that should own the created socket, e.g. to let members of a group connect
+    /// to a socket with mode 0o660.
+    pub fn group(mut self, gid: u32) -> Self {
+        self.group = Some(gid);
+        self
+    }
+
+    #[cfg(feature = "auth")]
+    /// Requires clients of a `MessageIpcServer` to prove they know `key`, and proves to them
+    /// that we do, before `wait_for_connection` returns their connection. Clients that fail
+    /// are disconnected. Clients must connect with `MessageIpcClient::connect` and the same
+    /// key. `RawIpcServer` doesn't support authentication and fails to start with a key set.
+    pub fn pre_shared_key(mut self, key: &[u8]) -> Self {
+        self.pre_shared_key = Some(PreSharedKey::new(key));
+        self
+    }
+
+    #[cfg(unix)]
+    /// The mode of any directories created to hold the socket, 0o700 by default. Existing
+    /// directories are left alone.
+    pub fn directory_mode(mut self, mode: u32) -> Self {
+        self.directory_mode = mode;
+        self
+    }
+
+    #[cfg(windows)]
+    /// An SDDL string describing the security descriptor applied to each pipe instance,
+    /// e.g. "D:(A;;GA;;;OW)" to only allow the owner to connect.
+    pub fn security_descriptor(mut self, sddl: &str) -> Self {
+        self.security_descriptor = Some(sddl.to_owned());
+        self
+    }
+}
+
+/// Options controlling how an IPC client connects. These are shared by `RawIpcClient`
+/// and `MessageIpcClient`.
+#[derive(Clone, Debug, Default)]
+pub struct ClientOptions {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) max_message_size: Option<u64>,
+
+    #[cfg(feature = "auth")]
+    pub(crate) pre_shared_key: Option<PreSharedKey>,
+}
+
+impl ClientOptions {
+    /// Creates the default options: fail immediately if the server is busy and no limit
+    /// on message size.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait for a busy server to accept the connection before failing.
+    /// `MessageIpcClient::connect` waits without blocking the thread on Unix; the other
+    /// constructors block it while they wait.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The largest message a `MessageIpcConnection` will read or write. Messages over
+    /// this size fail rather than being allocated; an oversized incoming message is read and
+    /// discarded, so the connection stays usable. Has no effect on raw connections.
+    pub fn max_message_size(mut self, size: u64) -> Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    #[cfg(feature = "auth")]
+    /// Authenticates with a server configured with the same pre-shared key, failing the
+    /// connection with `AuthenticationFailed` if either end doesn't know it. Only
+    /// `MessageIpcClient::connect` can authenticate; the other constructors fail with a key
+    /// set.
+    pub fn pre_shared_key(mut self, key: &[u8]) -> Self {
+        self.pre_shared_key = Some(PreSharedKey::new(key));
+        self
+    }
+}
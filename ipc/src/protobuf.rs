@@ -0,0 +1,130 @@
+//! Runtime support for IPC services generated from `.proto` files by the `tools` crate.
+//!
+//! Generated services make their calls through the `rpc` module, encoding requests and
+//! responses with `ProstCodec`. Method names are the method's full protobuf name, e.g.
+//! "storage.Storage/Get".
+
+use crate::codec::ProstCodec;
+use crate::rpc::{RpcClient, RpcServer};
+
+/// Makes calls to a generated protobuf service.
+pub type ProtobufClient = RpcClient<ProstCodec>;
+
+/// Dispatches calls to a generated protobuf service.
+pub type ProtobufServer = RpcServer<ProstCodec>;
+
+#[cfg(test)]
+mod tests {
+    use super::{ProtobufClient, ProtobufServer};
+    use crate::error::IpcError;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+    use tokio::sync::Notify;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct EchoRequest {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct EchoResponse {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    #[test]
+    fn can_call_protobuf_methods() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = ProtobufClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let mut rpc_server = ProtobufServer::new();
+            rpc_server.register("echo.Echo/Echo", |request: EchoRequest| async move {
+                Ok(EchoResponse { text: request.text })
+            });
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            let response: EchoResponse = client
+                .call("echo.Echo/Echo", &EchoRequest { text: "neigh".to_owned() })
+                .await
+                .unwrap();
+
+            assert_eq!(response.text, "neigh");
+
+            let result = client
+                .call::<_, EchoResponse>("echo.Echo/Shout", &EchoRequest { text: "neigh".to_owned() })
+                .await;
+
+            match result {
+                Err(IpcError::Remote(description)) => assert_eq!(description, "Unknown method echo.Echo/Shout"),
+                result => panic!("Unexpected result {:?}", result),
+            };
+        });
+    }
+
+    #[test]
+    fn slow_calls_dont_hold_up_others() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = ProtobufClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let released = Arc::new(Notify::new());
+            let mut rpc_server = ProtobufServer::new();
+
+            let echo_released = released.clone();
+            rpc_server.register("echo.Echo/Echo", move |request: EchoRequest| {
+                let released = echo_released.clone();
+
+                async move {
+                    if request.text == "slow" {
+                        released.notified().await;
+                    } else {
+                        released.notify_one();
+                    }
+
+                    Ok(EchoResponse { text: request.text })
+                }
+            });
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            let slow = EchoRequest { text: "slow".to_owned() };
+            let fast = EchoRequest { text: "fast".to_owned() };
+
+            // The slow call only finishes once the fast one runs, so this deadlocks unless both
+            // are in flight together.
+            let calls = async {
+                futures::join!(
+                    client.call::<_, EchoResponse>("echo.Echo/Echo", &slow),
+                    client.call::<_, EchoResponse>("echo.Echo/Echo", &fast)
+                )
+            };
+
+            let (slow, fast) = tokio::time::timeout(Duration::from_secs(10), calls).await.unwrap();
+
+            assert_eq!(slow.unwrap().text, "slow");
+            assert_eq!(fast.unwrap().text, "fast");
+        });
+    }
+}
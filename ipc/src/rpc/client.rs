@@ -0,0 +1,366 @@
+use super::context::CallContext;
+use super::frame::Frame;
+use super::stream::{RpcReceiver, RpcSender, StreamItemSender};
+use super::writer::FrameWriter;
+use crate::codec::{BincodeCodec, Decoder, Encoder};
+use crate::error::{IpcError, Result};
+use crate::ipc::MessageIpcConnection;
+
+use futures::channel::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::trace;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Where the frames for a call in flight go.
+enum Waiter {
+    /// A unary call, waiting on its response.
+    Unary(oneshot::Sender<Result<Vec<u8>>>),
+
+    /// A streaming call, whose receiver takes every message the server sends.
+    Stream(StreamItemSender),
+}
+
+type PendingCalls = HashMap<u64, Waiter>;
+
+struct ClientShared {
+    connection: Arc<MessageIpcConnection>,
+    writer: FrameWriter,
+    next_call_id: AtomicU64,
+
+    /// The calls waiting on the server, keyed by call id. None once the connection has failed,
+    /// so later calls fail immediately rather than waiting forever.
+    pending: Mutex<Option<PendingCalls>>,
+}
+
+impl ClientShared {
+    /// Allocates a call id and registers `waiter` to receive the call's frames.
+    fn register(&self, waiter: Waiter) -> Result<u64> {
+        let call_id = self.next_call_id.fetch_add(1, Ordering::SeqCst);
+
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => {
+                pending.insert(call_id, waiter);
+                Ok(call_id)
+            }
+            None => Err(IpcError::PeerDisconnected),
+        }
+    }
+
+    fn unregister(&self, call_id: u64) -> Option<Waiter> {
+        self.pending.lock().unwrap().as_mut().and_then(|pending| pending.remove(&call_id))
+    }
+}
+
+/// Removes a call from the pending set when whatever is waiting on it goes away. If that
+/// happens before the server finishes the call, the server is told to cancel it.
+pub(crate) struct CallGuard {
+    shared: Arc<ClientShared>,
+    call_id: u64,
+    finished: bool,
+}
+
+impl CallGuard {
+    /// Records that the server has finished the call, so there's nothing to cancel.
+    pub(crate) fn finish(&mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        self.shared.unregister(self.call_id);
+
+        if !self.finished {
+            self.shared.writer.write_detached(&Frame::Cancel { call_id: self.call_id });
+        }
+    }
+}
+
+/// Makes calls over a message connection to an `RpcServer`. Any number of calls may be in
+/// flight at once, and they complete in whatever order the server finishes them.
+///
+/// A background task reads responses for the lifetime of the client. If the connection fails,
+/// every pending call fails with the connection's error.
+///
+/// Dropping a call's future, or the receiver of a streaming call, before it completes tells
+/// the server to cancel it. Calls made while handling another call inherit what's left of
+/// that call's deadline.
+pub struct RpcClient<C = BincodeCodec> {
+    shared: Arc<ClientShared>,
+    codec: C,
+    timeout: Option<Duration>,
+    reader: JoinHandle<()>,
+}
+
+impl<C: Default> RpcClient<C> {
+    /// Creates a client that encodes requests with the default instance of codec `C`. Must be
+    /// called within a tokio runtime, which runs the task reading responses.
+    pub fn new(connection: MessageIpcConnection) -> Result<RpcClient<C>> {
+        RpcClient::with_codec(connection, C::default())
+    }
+}
+
+impl<C> RpcClient<C> {
+    /// Creates a client that encodes requests with `codec`. Must be called within a tokio
+    /// runtime, which runs the task reading responses.
+    pub fn with_codec(connection: MessageIpcConnection, codec: C) -> Result<RpcClient<C>> {
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| IpcError::Io(std::io::Error::other("RPC clients must be created within a tokio runtime")))?;
+
+        let connection = Arc::new(connection);
+
+        let shared = Arc::new(ClientShared {
+            writer: FrameWriter::spawn(&runtime, connection.clone()),
+            connection,
+            next_call_id: AtomicU64::new(1),
+            pending: Mutex::new(Some(HashMap::new())),
+        });
+
+        let reader = runtime.spawn(read_responses(shared.clone()));
+
+        Ok(RpcClient {
+            shared,
+            codec,
+            timeout: None,
+            reader,
+        })
+    }
+
+    /// Sets how long calls may take before they fail with `IpcError::DeadlineExceeded`. The
+    /// deadline is sent with each call so the server can abandon it too. By default, calls
+    /// have no deadline.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The timeout for a call made now: this client's timeout, or the time left on the call
+    /// being handled, whichever is shorter.
+    fn call_timeout(&self) -> Option<Duration> {
+        let inherited = CallContext::current().and_then(|context| context.remaining());
+
+        match (self.timeout, inherited) {
+            (Some(timeout), Some(inherited)) => Some(timeout.min(inherited)),
+            (timeout, inherited) => timeout.or(inherited),
+        }
+    }
+
+    fn guard(&self, call_id: u64) -> CallGuard {
+        CallGuard {
+            shared: self.shared.clone(),
+            call_id,
+            finished: false,
+        }
+    }
+
+    /// Calls `method` with an already encoded request, returning the encoded response.
+    pub async fn call_raw(&self, method: &str, request: &[u8]) -> Result<Vec<u8>> {
+        let timeout = self.call_timeout();
+        let deadline = deadline_after(timeout);
+        let (response_tx, response_rx) = oneshot::channel();
+        let call_id = self.shared.register(Waiter::Unary(response_tx))?;
+        let mut guard = self.guard(call_id);
+
+        let frame = Frame::Request {
+            call_id,
+            method: method.to_owned(),
+            timeout,
+            body: request.to_vec(),
+        };
+
+        // The server never saw a call we failed to send, so there's nothing to cancel.
+        if let Err(err) = self.shared.writer.write(&frame).await {
+            guard.finish();
+            return Err(err);
+        }
+
+        let response = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, response_rx)
+                .await
+                .map_err(|_| IpcError::DeadlineExceeded)?,
+            None => response_rx.await,
+        };
+
+        guard.finish();
+
+        response.map_err(|_| IpcError::PeerDisconnected)?
+    }
+
+    /// Calls `method`, encoding the request and decoding the response with this client's
+    /// codec.
+    pub async fn call<Req, Resp>(&self, method: &str, request: &Req) -> Result<Resp>
+    where
+        C: Encoder<Req> + Decoder<Resp>,
+    {
+        let request = self.codec.encode(request).map_err(IpcError::Encode)?;
+        let response = self.call_raw(method, &request).await?;
+
+        self.codec.decode(&response).map_err(IpcError::Decode)
+    }
+
+    /// Opens a streaming call to `method`, returning this side's halves of the stream. This
+    /// serves every kind of streaming method: a client-streaming call sends any number of
+    /// values then receives one response, and a bidirectional call does whatever the method
+    /// calls for. Dropping the sender ends the client's side of the stream; dropping the
+    /// receiver cancels the call. If the call has a deadline, the receiver fails with
+    /// `IpcError::DeadlineExceeded` once it passes.
+    pub async fn open<Tx, Rx>(&self, method: &str) -> Result<(RpcSender<Tx, C>, RpcReceiver<Rx, C>)>
+    where
+        C: Encoder<Tx> + Decoder<Rx> + Clone,
+    {
+        let (items_tx, items_rx) = mpsc::unbounded();
+        let call_id = self.shared.register(Waiter::Stream(items_tx))?;
+        let mut guard = self.guard(call_id);
+        let timeout = self.call_timeout();
+        let deadline = deadline_after(timeout);
+
+        let frame = Frame::Open {
+            call_id,
+            method: method.to_owned(),
+            timeout,
+        };
+
+        if let Err(err) = self.shared.writer.write(&frame).await {
+            guard.finish();
+            return Err(err);
+        }
+
+        let sender = RpcSender::new(
+            self.shared.writer.clone(),
+            call_id,
+            self.codec.clone(),
+            Arc::new(AtomicBool::new(false)),
+            true,
+        );
+
+        let receiver = RpcReceiver::new(items_rx, self.codec.clone(), Some(guard), deadline);
+
+        Ok((sender, receiver))
+    }
+
+    /// Calls a server-streaming `method` with `request`, returning a receiver for the values
+    /// it responds with.
+    pub async fn call_streaming<Req, Item>(&self, method: &str, request: &Req) -> Result<RpcReceiver<Item, C>>
+    where
+        C: Encoder<Req> + Decoder<Item> + Clone,
+    {
+        let (sender, receiver) = self.open(method).await?;
+
+        sender.send(request).await?;
+        sender.finish().await?;
+
+        Ok(receiver)
+    }
+}
+
+/// When a call made now with `timeout` must complete by. Taken before the call is sent, so the
+/// client always gives up no later than the server.
+fn deadline_after(timeout: Option<Duration>) -> Option<tokio::time::Instant> {
+    timeout.map(|timeout| tokio::time::Instant::now() + timeout)
+}
+
+impl<C> Drop for RpcClient<C> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_responses(shared: Arc<ClientShared>) {
+    let err = loop {
+        let frame = match shared.connection.read().await.and_then(|data| Frame::decode(&data)) {
+            Ok(frame) => frame,
+            Err(err) => break err,
+        };
+
+        if let Err(err) = dispatch_frame(&shared, frame) {
+            break err;
+        }
+    };
+
+    trace!(%err, "RPC client connection failed");
+
+    let pending = shared.pending.lock().unwrap().take();
+
+    for (_, waiter) in pending.into_iter().flatten() {
+        match waiter {
+            Waiter::Unary(response_tx) => {
+                let _ = response_tx.send(Err(err.duplicate()));
+            }
+            Waiter::Stream(items_tx) => {
+                let _ = items_tx.unbounded_send(Err(err.duplicate()));
+            }
+        };
+    }
+}
+
+/// Hands a frame from the server to the call it belongs to. Fails if the server has broken
+/// the protocol, which ends the connection.
+fn dispatch_frame(shared: &ClientShared, frame: Frame) -> Result<()> {
+    match frame {
+        Frame::Data { call_id, body } => forward_data(shared, call_id, body),
+        Frame::Request { .. } | Frame::Open { .. } | Frame::Cancel { .. } => {
+            Err(IpcError::protocol_mismatch("RPC server sent a request"))
+        }
+        Frame::Response { call_id, .. } | Frame::Error { call_id, .. } | Frame::End { call_id } => {
+            match shared.unregister(call_id) {
+                Some(waiter) => complete_call(waiter, frame),
+                None => {
+                    trace!(call_id, "Dropping response to a call that is no longer pending");
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+fn forward_data(shared: &ClientShared, call_id: u64, body: Vec<u8>) -> Result<()> {
+    let mut pending = shared.pending.lock().unwrap();
+    let pending = match pending.as_mut() {
+        Some(pending) => pending,
+        None => return Ok(()),
+    };
+
+    match pending.get(&call_id) {
+        Some(Waiter::Stream(items_tx)) => {
+            // The receiver may have been dropped, in which case the rest of the stream is
+            // discarded.
+            if items_tx.unbounded_send(Ok(Some(body))).is_err() {
+                pending.remove(&call_id);
+            }
+
+            Ok(())
+        }
+        Some(Waiter::Unary(_)) => Err(IpcError::protocol_mismatch("RPC server sent stream data for a unary call")),
+        None => {
+            trace!(call_id, "Dropping stream data for a call that is no longer pending");
+            Ok(())
+        }
+    }
+}
+
+/// Completes a call with the frame the server finished it with.
+fn complete_call(waiter: Waiter, frame: Frame) -> Result<()> {
+    match (waiter, frame) {
+        (Waiter::Unary(response_tx), Frame::Response { body, .. }) => {
+            let _ = response_tx.send(Ok(body));
+        }
+        (Waiter::Unary(response_tx), Frame::Error { description, .. }) => {
+            let _ = response_tx.send(Err(IpcError::Remote(description)));
+        }
+        (Waiter::Stream(items_tx), Frame::End { .. }) => {
+            let _ = items_tx.unbounded_send(Ok(None));
+        }
+        (Waiter::Stream(items_tx), Frame::Error { description, .. }) => {
+            let _ = items_tx.unbounded_send(Err(IpcError::Remote(description)));
+        }
+        _ => {
+            return Err(IpcError::protocol_mismatch("RPC server sent a frame that doesn't suit the call"));
+        }
+    };
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use crate::error::{IpcError, Result};
+
+use futures::future::{self, Either};
+use tokio::sync::Notify;
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static CURRENT_CALL: CallContext;
+}
+
+struct TokenState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Signals that a call has been abandoned, either because the client cancelled it or because
+/// its deadline passed. Clones share the same state.
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+impl CancellationToken {
+    pub(crate) fn new() -> CancellationToken {
+        CancellationToken {
+            state: Arc::new(TokenState {
+                cancelled: AtomicBool::new(false),
+                notify: Notify::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        self.state.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the call has been cancelled.
+    pub async fn cancelled(&self) {
+        let notified = self.state.notify.notified();
+        futures::pin_mut!(notified);
+
+        // Register for the notification before checking the flag, so a cancellation between
+        // the two isn't missed.
+        notified.as_mut().enable();
+
+        if self.is_cancelled() {
+            return;
+        }
+
+        notified.await;
+    }
+}
+
+impl std::fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("CancellationToken").field("cancelled", &self.is_cancelled()).finish()
+    }
+}
+
+/// What a server knows about the call it's handling: when the client will give up on it and
+/// whether it already has.
+///
+/// The server stops polling a handler once its call is cancelled or its deadline passes, so
+/// handlers only need the token to stop work they've handed off elsewhere, e.g. to a blocking
+/// thread.
+#[derive(Clone, Debug)]
+pub struct CallContext {
+    deadline: Option<Instant>,
+    token: CancellationToken,
+}
+
+impl CallContext {
+    pub(crate) fn new(timeout: Option<Duration>) -> CallContext {
+        CallContext {
+            deadline: timeout.and_then(|timeout| Instant::now().checked_add(timeout)),
+            token: CancellationToken::new(),
+        }
+    }
+
+    /// The context of the call the current task is handling, if any. Only available to the
+    /// handler's own task, not tasks it spawns.
+    pub fn current() -> Option<CallContext> {
+        CURRENT_CALL.try_with(|context| context.clone()).ok()
+    }
+
+    /// When the client will give up on the call, if it set a deadline.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// How much longer the client will wait for the call, if it set a deadline. Calls made
+    /// while handling this one inherit this as their timeout.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Runs `future` as the handler of this call. Fails with `Cancelled` or
+    /// `DeadlineExceeded`, dropping the future, if the call is abandoned first.
+    pub(crate) async fn run<F: Future>(self, future: F) -> Result<F::Output> {
+        let token = self.token.clone();
+        let deadline = self.deadline;
+
+        let abandoned = async move {
+            let cancelled = token.cancelled();
+            futures::pin_mut!(cancelled);
+
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => {
+                    cancelled.await;
+                    return IpcError::Cancelled;
+                }
+            };
+
+            let expired = tokio::time::sleep_until(deadline.into());
+            futures::pin_mut!(expired);
+
+            match future::select(cancelled, expired).await {
+                Either::Left(_) => IpcError::Cancelled,
+                Either::Right(_) => {
+                    token.cancel();
+                    IpcError::DeadlineExceeded
+                }
+            }
+        };
+
+        let handled = CURRENT_CALL.scope(self, future);
+
+        futures::pin_mut!(abandoned);
+        futures::pin_mut!(handled);
+
+        match future::select(handled, abandoned).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right((err, _)) => Err(err),
+        }
+    }
+}
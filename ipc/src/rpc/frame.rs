@@ -0,0 +1,261 @@
+use crate::error::{IpcError, Result};
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+const KIND_REQUEST: u8 = 0;
+const KIND_RESPONSE: u8 = 1;
+const KIND_ERROR: u8 = 2;
+const KIND_OPEN: u8 = 3;
+const KIND_DATA: u8 = 4;
+const KIND_END: u8 = 5;
+const KIND_CANCEL: u8 = 6;
+
+/// The encoded timeout of a call without one.
+const NO_TIMEOUT: u64 = u64::MAX;
+
+/// One RPC message. Every frame starts with a kind byte and the little-endian u64 id of the
+/// call it belongs to, followed by a kind-specific payload.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    /// Starts a call. The payload is the little-endian u16 length of the method name, the name,
+    /// the call's metadata, then the encoded request. The metadata is the little-endian u64
+    /// number of microseconds the caller will wait for the call, or u64::MAX if it has no
+    /// deadline.
+    Request {
+        call_id: u64,
+        method: String,
+        timeout: Option<Duration>,
+        body: Vec<u8>,
+    },
+
+    /// Completes a call successfully. The payload is the encoded response.
+    Response {
+        call_id: u64,
+        body: Vec<u8>,
+    },
+
+    /// Completes a call, or the server's side of a stream, with a failure. The payload is a
+    /// UTF-8 description of the error.
+    Error {
+        call_id: u64,
+        description: String,
+    },
+
+    /// Starts a streaming call. The payload is the method name and metadata, encoded as in
+    /// `Request`.
+    Open {
+        call_id: u64,
+        method: String,
+        timeout: Option<Duration>,
+    },
+
+    /// Carries one message of a stream in either direction. The payload is the encoded message.
+    Data {
+        call_id: u64,
+        body: Vec<u8>,
+    },
+
+    /// Ends the sender's side of a stream. There's no payload.
+    End {
+        call_id: u64,
+    },
+
+    /// Tells the server the client has given up on a call, so it can stop working on it.
+    /// There's no payload.
+    Cancel {
+        call_id: u64,
+    },
+}
+
+fn encode_method(method: &str, data: &mut Vec<u8>) -> Result<()> {
+    let name_len = u16::try_from(method.len())
+        .map_err(|_| IpcError::protocol_mismatch("Method name is too long"))?;
+
+    data.extend_from_slice(&name_len.to_le_bytes());
+    data.extend_from_slice(method.as_bytes());
+
+    Ok(())
+}
+
+fn encode_timeout(timeout: Option<Duration>, data: &mut Vec<u8>) {
+    let micros = timeout.map_or(NO_TIMEOUT, |timeout| u64::try_from(timeout.as_micros()).unwrap_or(NO_TIMEOUT - 1));
+
+    data.extend_from_slice(&micros.to_le_bytes());
+}
+
+/// Decodes a call's timeout from the start of `payload`, returning it and the rest of the
+/// payload.
+fn decode_timeout(payload: &[u8]) -> Result<(Option<Duration>, &[u8])> {
+    if payload.len() < 8 {
+        return Err(IpcError::protocol_mismatch("RPC frame is missing its metadata"));
+    }
+
+    let mut micros = [0; 8];
+    micros.copy_from_slice(&payload[..8]);
+
+    let timeout = match u64::from_le_bytes(micros) {
+        NO_TIMEOUT => None,
+        micros => Some(Duration::from_micros(micros)),
+    };
+
+    Ok((timeout, &payload[8..]))
+}
+
+/// Decodes a method name from the start of `payload`, returning it and the rest of the payload.
+fn decode_method(payload: &[u8]) -> Result<(String, &[u8])> {
+    if payload.len() < 2 {
+        return Err(IpcError::protocol_mismatch("RPC frame is missing its method name"));
+    }
+
+    let name_len = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+
+    if payload.len() < 2 + name_len {
+        return Err(IpcError::protocol_mismatch("RPC frame method name is truncated"));
+    }
+
+    let method = String::from_utf8(payload[2..2 + name_len].to_vec())
+        .map_err(|_| IpcError::protocol_mismatch("RPC frame method name isn't UTF-8"))?;
+
+    Ok((method, &payload[2 + name_len..]))
+}
+
+impl Frame {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut data = vec![];
+
+        match self {
+            Frame::Request { call_id, method, timeout, body } => {
+                data.push(KIND_REQUEST);
+                data.extend_from_slice(&call_id.to_le_bytes());
+                encode_method(method, &mut data)?;
+                encode_timeout(*timeout, &mut data);
+                data.extend_from_slice(body);
+            }
+            Frame::Response { call_id, body } => {
+                data.push(KIND_RESPONSE);
+                data.extend_from_slice(&call_id.to_le_bytes());
+                data.extend_from_slice(body);
+            }
+            Frame::Error { call_id, description } => {
+                data.push(KIND_ERROR);
+                data.extend_from_slice(&call_id.to_le_bytes());
+                data.extend_from_slice(description.as_bytes());
+            }
+            Frame::Open { call_id, method, timeout } => {
+                data.push(KIND_OPEN);
+                data.extend_from_slice(&call_id.to_le_bytes());
+                encode_method(method, &mut data)?;
+                encode_timeout(*timeout, &mut data);
+            }
+            Frame::Data { call_id, body } => {
+                data.push(KIND_DATA);
+                data.extend_from_slice(&call_id.to_le_bytes());
+                data.extend_from_slice(body);
+            }
+            Frame::End { call_id } => {
+                data.push(KIND_END);
+                data.extend_from_slice(&call_id.to_le_bytes());
+            }
+            Frame::Cancel { call_id } => {
+                data.push(KIND_CANCEL);
+                data.extend_from_slice(&call_id.to_le_bytes());
+            }
+        }
+
+        Ok(data)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Frame> {
+        if data.len() < 9 {
+            return Err(IpcError::protocol_mismatch("RPC frame is too short"));
+        }
+
+        let mut call_id_bytes = [0; 8];
+        call_id_bytes.copy_from_slice(&data[1..9]);
+
+        let call_id = u64::from_le_bytes(call_id_bytes);
+        let payload = &data[9..];
+
+        match data[0] {
+            KIND_REQUEST => {
+                let (method, rest) = decode_method(payload)?;
+                let (timeout, body) = decode_timeout(rest)?;
+
+                Ok(Frame::Request {
+                    call_id,
+                    method,
+                    timeout,
+                    body: body.to_vec(),
+                })
+            }
+            KIND_RESPONSE => Ok(Frame::Response {
+                call_id,
+                body: payload.to_vec(),
+            }),
+            KIND_ERROR => Ok(Frame::Error {
+                call_id,
+                description: String::from_utf8_lossy(payload).into_owned(),
+            }),
+            KIND_OPEN => {
+                let (method, rest) = decode_method(payload)?;
+                let (timeout, rest) = decode_timeout(rest)?;
+
+                if !rest.is_empty() {
+                    return Err(IpcError::protocol_mismatch("RPC open frame has trailing data"));
+                }
+
+                Ok(Frame::Open { call_id, method, timeout })
+            }
+            KIND_DATA => Ok(Frame::Data {
+                call_id,
+                body: payload.to_vec(),
+            }),
+            KIND_END if payload.is_empty() => Ok(Frame::End { call_id }),
+            KIND_END => Err(IpcError::protocol_mismatch("RPC end frame has trailing data")),
+            KIND_CANCEL if payload.is_empty() => Ok(Frame::Cancel { call_id }),
+            KIND_CANCEL => Err(IpcError::protocol_mismatch("RPC cancel frame has trailing data")),
+            kind => Err(IpcError::protocol_mismatch(format!("Unknown RPC frame kind {}", kind))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Frame;
+    use crate::error::IpcError;
+
+    use std::time::Duration;
+
+    fn assert_round_trips(frame: Frame) {
+        let data = frame.encode().unwrap();
+
+        assert_eq!(Frame::decode(&data).unwrap(), frame);
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        assert_round_trips(Frame::Request { call_id: 1, method: "get".to_owned(), timeout: None, body: vec![1, 2, 3] });
+        assert_round_trips(Frame::Request {
+            call_id: u64::MAX,
+            method: String::new(),
+            timeout: Some(Duration::from_millis(1500)),
+            body: vec![],
+        });
+        assert_round_trips(Frame::Response { call_id: 2, body: vec![4, 5] });
+        assert_round_trips(Frame::Error { call_id: 3, description: "no hay".to_owned() });
+        assert_round_trips(Frame::Open { call_id: 4, method: "tail".to_owned(), timeout: Some(Duration::from_secs(3)) });
+        assert_round_trips(Frame::Data { call_id: 5, body: vec![6] });
+        assert_round_trips(Frame::End { call_id: 6 });
+        assert_round_trips(Frame::Cancel { call_id: 7 });
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert!(matches!(Frame::decode(&[0; 4]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[9; 12]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[0, 1, 0, 0, 0, 0, 0, 0, 0, 5, 0]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]), Err(IpcError::ProtocolMismatch(_))));
+        assert!(matches!(Frame::decode(&[5, 1, 0, 0, 0, 0, 0, 0, 0, 1]), Err(IpcError::ProtocolMismatch(_))));
+    }
+}
@@ -0,0 +1,537 @@
+//! Request/response calls over a message connection.
+//!
+//! Every message is a frame tagged with the id of the call it belongs to, so a client may have
+//! many calls in flight at once and the server may answer them in any order.
+//!
+//! Besides unary calls, a call may open a stream carrying any number of messages in each
+//! direction. Each side ends its half of the stream with an end frame, or, on the server, an
+//! error frame.
+//!
+//! Calls carry their deadline to the server, and clients send a cancel frame for any call they
+//! give up on, so servers don't keep working on calls no one is waiting for.
+
+mod client;
+mod context;
+mod frame;
+mod server;
+mod stream;
+mod writer;
+
+pub use self::client::RpcClient;
+pub use self::context::{CallContext, CancellationToken};
+pub use self::server::RpcServer;
+pub use self::stream::{RpcReceiver, RpcSender};
+
+#[cfg(test)]
+mod tests {
+    use super::frame::Frame;
+    use super::{CallContext, RpcClient, RpcReceiver, RpcServer};
+    use crate::error::IpcError;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::options::ClientOptions;
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+    use tokio::sync::Notify;
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Notifies whoever's waiting when a handler's future is dropped.
+    struct NotifyOnDrop(Arc<Notify>);
+
+    impl Drop for NotifyOnDrop {
+        fn drop(&mut self) {
+            self.0.notify_one();
+        }
+    }
+
+    /// A server whose "hang" method never completes, recording the time left on each call and
+    /// notifying `dropped` when the server stops polling it.
+    fn hanging_server(remaining: Arc<Mutex<Option<Duration>>>, dropped: Arc<Notify>) -> RpcServer {
+        let mut rpc_server: RpcServer = RpcServer::new();
+
+        rpc_server.register("hang", move |_: ()| {
+            let remaining = remaining.clone();
+            let dropped = dropped.clone();
+
+            async move {
+                let _dropped = NotifyOnDrop(dropped);
+                let context = CallContext::current().unwrap();
+
+                assert!(!context.cancellation_token().is_cancelled());
+                *remaining.lock().unwrap() = context.remaining();
+
+                context.cancellation_token().cancelled().await;
+                Ok(())
+            }
+        });
+
+        rpc_server
+    }
+
+    #[crate::service(crate = crate)]
+    trait Storage {
+        async fn get(&self, key: String) -> Option<Vec<u8>>;
+        async fn put(&self, key: String, value: Vec<u8>);
+    }
+
+    #[derive(Default)]
+    struct MemoryStorage {
+        values: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl Storage for MemoryStorage {
+        async fn get(&self, key: String) -> crate::Result<Option<Vec<u8>>> {
+            Ok(self.values.lock().unwrap().get(&key).cloned())
+        }
+
+        async fn put(&self, key: String, value: Vec<u8>) -> crate::Result<()> {
+            if key.is_empty() {
+                return Err(IpcError::Remote("Keys can't be empty".to_owned()));
+            }
+
+            self.values.lock().unwrap().insert(key, value);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn calls_complete_out_of_order() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let released = Arc::new(Notify::new());
+            let mut rpc_server: RpcServer = RpcServer::new();
+
+            let wait_released = released.clone();
+            rpc_server.register("wait", move |id: u32| {
+                let released = wait_released.clone();
+
+                async move {
+                    released.notified().await;
+                    Ok(id)
+                }
+            });
+
+            let release_released = released.clone();
+            rpc_server.register("release", move |id: u32| {
+                let released = release_released.clone();
+
+                async move {
+                    released.notify_one();
+                    Ok(id)
+                }
+            });
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            // The first call only finishes once the second one runs, so this deadlocks unless
+            // both are in flight together.
+            let calls = async {
+                futures::join!(
+                    client.call::<u32, u32>("wait", &1),
+                    client.call::<u32, u32>("release", &2)
+                )
+            };
+
+            let (waited, released) = tokio::time::timeout(Duration::from_secs(10), calls).await.unwrap();
+
+            assert_eq!(waited.unwrap(), 1);
+            assert_eq!(released.unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn unknown_methods_fail_remotely() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let mut rpc_server: RpcServer = RpcServer::new();
+            rpc_server.register("fail", |_: ()| async { Err::<(), _>(IpcError::Remote("no hay".to_owned())) });
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            match client.call::<(), ()>("gallop", &()).await {
+                Err(IpcError::Remote(description)) => assert_eq!(description, "Unknown method gallop"),
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            match client.call::<(), ()>("fail", &()).await {
+                Err(IpcError::Remote(description)) => assert_eq!(description, "no hay"),
+                result => panic!("Unexpected result {:?}", result),
+            };
+        });
+    }
+
+    #[test]
+    fn pending_calls_fail_when_server_disconnects() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let server_task = tokio::spawn(async move {
+                // Read the request, then hang up without answering it.
+                connection.read().await.unwrap();
+            });
+
+            let result = tokio::time::timeout(Duration::from_secs(10), client.call::<(), ()>("get", &())).await.unwrap();
+
+            match result {
+                Err(IpcError::PeerDisconnected) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            server_task.await.unwrap();
+
+            match client.call::<(), ()>("get", &()).await {
+                Err(IpcError::PeerDisconnected) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+        });
+    }
+
+    #[test]
+    fn service_clients_call_through_to_implementations() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: StorageClient = StorageClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let storage_server: StorageServer = StorageServer::new(MemoryStorage::default());
+
+            tokio::spawn(async move { storage_server.serve(connection).await.unwrap() });
+
+            assert_eq!(client.get("horse".to_owned()).await.unwrap(), None);
+
+            client.put("horse".to_owned(), vec![1, 2, 3]).await.unwrap();
+
+            assert_eq!(client.get("horse".to_owned()).await.unwrap(), Some(vec![1, 2, 3]));
+
+            match client.put(String::new(), vec![]).await {
+                Err(IpcError::Remote(description)) => assert_eq!(description, "Keys can't be empty"),
+                result => panic!("Unexpected result {:?}", result),
+            };
+        });
+    }
+
+    #[test]
+    fn server_streams_end_with_end_or_error_frames() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let mut rpc_server: RpcServer = RpcServer::new();
+            rpc_server.register("double", |n: u32| async move { Ok(n * 2) });
+            rpc_server.register_server_streaming("count", |n: u32, sender| async move {
+                for i in 0..n {
+                    sender.send(&i).await?;
+                }
+
+                if n > 3 {
+                    return Err(IpcError::Remote("Counted too high".to_owned()));
+                }
+
+                Ok(())
+            });
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            let mut counted = client.call_streaming::<u32, u32>("count", &3).await.unwrap();
+
+            // Unary calls carry on while the stream is open.
+            assert_eq!(client.call::<u32, u32>("double", &4).await.unwrap(), 8);
+
+            assert_eq!(counted.recv().await.unwrap(), Some(0));
+            assert_eq!(counted.recv().await.unwrap(), Some(1));
+            assert_eq!(counted.recv().await.unwrap(), Some(2));
+            assert_eq!(counted.recv().await.unwrap(), None);
+            assert_eq!(counted.recv().await.unwrap(), None);
+
+            let mut counted = client.call_streaming::<u32, u32>("count", &4).await.unwrap();
+
+            for i in 0..4 {
+                assert_eq!(counted.recv().await.unwrap(), Some(i));
+            }
+
+            match counted.recv().await {
+                Err(IpcError::Remote(description)) => assert_eq!(description, "Counted too high"),
+                result => panic!("Unexpected result {:?}", result),
+            };
+        });
+    }
+
+    #[test]
+    fn client_and_bidirectional_streams() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let mut rpc_server: RpcServer = RpcServer::new();
+            rpc_server.register_client_streaming("sum", |mut receiver: RpcReceiver<u32>| async move {
+                let mut sum = 0;
+
+                while let Some(n) = receiver.recv().await? {
+                    sum += n;
+                }
+
+                Ok(sum)
+            });
+            rpc_server.register_streaming("shout", |mut receiver: RpcReceiver<String>, sender| async move {
+                while let Some(text) = receiver.recv().await? {
+                    sender.send(&text.to_uppercase()).await?;
+                }
+
+                Ok(())
+            });
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            let (numbers, mut sum) = client.open::<u32, u32>("sum").await.unwrap();
+
+            for n in 1..=4 {
+                numbers.send(&n).await.unwrap();
+            }
+
+            numbers.finish().await.unwrap();
+
+            assert_eq!(sum.recv().await.unwrap(), Some(10));
+            assert_eq!(sum.recv().await.unwrap(), None);
+
+            let (words, mut shouts) = client.open::<String, String>("shout").await.unwrap();
+
+            words.send(&"neigh".to_owned()).await.unwrap();
+            assert_eq!(shouts.recv().await.unwrap(), Some("NEIGH".to_owned()));
+
+            words.send(&"whinny".to_owned()).await.unwrap();
+            assert_eq!(shouts.recv().await.unwrap(), Some("WHINNY".to_owned()));
+
+            // Dropping the sender ends the client's side, so the handler returns.
+            drop(words);
+            assert_eq!(shouts.recv().await.unwrap(), None);
+
+            let (_, mut unknown) = client.open::<(), ()>("gallop").await.unwrap();
+
+            match unknown.recv().await {
+                Err(IpcError::Remote(description)) => assert_eq!(description, "Unknown method gallop"),
+                result => panic!("Unexpected result {:?}", result),
+            };
+        });
+    }
+
+    #[test]
+    fn dropped_calls_are_cancelled_on_the_server() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let remaining = Arc::new(Mutex::new(Some(Duration::from_secs(1))));
+            let dropped = Arc::new(Notify::new());
+            let rpc_server = hanging_server(remaining.clone(), dropped.clone());
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            let result = tokio::time::timeout(Duration::from_millis(100), client.call::<(), ()>("hang", &())).await;
+
+            assert!(result.is_err());
+
+            tokio::time::timeout(Duration::from_secs(10), dropped.notified()).await.unwrap();
+
+            assert_eq!(*remaining.lock().unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn calls_dropped_while_writing_leave_the_connection_intact() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let mut rpc_server: RpcServer = RpcServer::new();
+            rpc_server.register("len", |data: Vec<u8>| async move { Ok(data.len()) });
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            // Far more than the OS buffers, so the call is dropped partway through writing it.
+            let large = bincode::serialize(&vec![7u8; 4 * 1024 * 1024]).unwrap();
+            let result = tokio::time::timeout(Duration::from_millis(1), client.call_raw("len", &large)).await;
+
+            assert!(result.is_err());
+
+            let len = tokio::time::timeout(Duration::from_secs(10), client.call::<_, usize>("len", &vec![1u8; 3]))
+                .await
+                .unwrap();
+
+            assert_eq!(len.unwrap(), 3);
+        });
+    }
+
+    #[test]
+    fn calls_that_fail_to_send_arent_cancelled() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let options = ClientOptions::new().max_message_size(64);
+            let client: RpcClient = RpcClient::new(MessageIpcClient::with_options(&server_name, &options).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            match client.call::<_, ()>("store", &vec![0u8; 100]).await {
+                Err(IpcError::FrameTooLarge { .. }) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            let ping = tokio::spawn(async move { client.call::<(), ()>("ping", &()).await });
+
+            // The server never heard of the failed call, so the next thing it sees is the ping.
+            match Frame::decode(&connection.read().await.unwrap()).unwrap() {
+                Frame::Request { method, .. } => assert_eq!(method, "ping"),
+                frame => panic!("Unexpected frame {:?}", frame),
+            };
+
+            ping.abort();
+        });
+    }
+
+    #[test]
+    fn deadlines_propagate_to_the_server() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap())
+                .unwrap()
+                .with_timeout(Duration::from_millis(200));
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let remaining = Arc::new(Mutex::new(None));
+            let dropped = Arc::new(Notify::new());
+            let rpc_server = hanging_server(remaining.clone(), dropped.clone());
+
+            tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+            match client.call::<(), ()>("hang", &()).await {
+                Err(IpcError::DeadlineExceeded) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            tokio::time::timeout(Duration::from_secs(10), dropped.notified()).await.unwrap();
+
+            let remaining = remaining.lock().unwrap().unwrap();
+
+            assert!(remaining > Duration::ZERO && remaining <= Duration::from_millis(200));
+        });
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn calls_continue_the_clients_trace() {
+        use opentelemetry::trace::{TraceContextExt, TracerProvider};
+        use opentelemetry_sdk::trace::SdkTracerProvider;
+        use tracing::Instrument;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        install_logger();
+
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("ipc")));
+
+        let server_name = get_server_name();
+
+        // The subscriber is only the default on this thread, so the server's tasks must run here.
+        let pool = runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            pool.block_on(async {
+                let server = MessageIpcServer::new(&server_name).unwrap();
+                let client: RpcClient = RpcClient::new(MessageIpcClient::new(&server_name).unwrap()).unwrap();
+
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+                let mut rpc_server: RpcServer = RpcServer::new();
+                rpc_server.register("trace", |_: ()| async {
+                    let context = tracing::Span::current().context();
+
+                    Ok(context.span().span_context().trace_id().to_string())
+                });
+
+                tokio::spawn(async move { rpc_server.serve(connection).await.unwrap() });
+
+                let send = tracing::info_span!("send");
+                let sent_from = send.context().span().span_context().trace_id();
+
+                let handled_in: String = client.call("trace", &()).instrument(send).await.unwrap();
+
+                assert_eq!(handled_in, sent_from.to_string());
+            });
+        });
+    }
+}
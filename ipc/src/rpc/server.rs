@@ -0,0 +1,338 @@
+use super::context::{CallContext, CancellationToken};
+use super::frame::Frame;
+use super::stream::{OpenedStream, RpcReceiver, RpcSender, StreamItemSender};
+use super::writer::FrameWriter;
+use crate::codec::{BincodeCodec, Decoder, Encoder};
+use crate::error::{IpcError, Result};
+use crate::ipc::MessageIpcConnection;
+use crate::message::Headers;
+
+use futures::channel::mpsc;
+use futures::future::{self, BoxFuture, Either};
+use tokio::runtime::Handle;
+use tokio::task::JoinSet;
+use tracing::{trace, Instrument, Span};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+type Handler = Arc<dyn Fn(Vec<u8>) -> BoxFuture<'static, Result<Vec<u8>>> + Send + Sync>;
+
+type StreamHandler = Arc<dyn Fn(OpenedStream) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Dispatches calls from `RpcClient`s to registered handlers. Each call runs in its own task,
+/// so a slow call doesn't hold up others on the same connection.
+pub struct RpcServer<C = BincodeCodec> {
+    handlers: HashMap<String, Handler>,
+    stream_handlers: HashMap<String, StreamHandler>,
+    codec: C,
+}
+
+impl<C: Default> Default for RpcServer<C> {
+    fn default() -> Self {
+        RpcServer::with_codec(C::default())
+    }
+}
+
+impl<C: Default> RpcServer<C> {
+    /// Creates a server that decodes requests with the default instance of codec `C`.
+    pub fn new() -> RpcServer<C> {
+        RpcServer::default()
+    }
+}
+
+impl<C> RpcServer<C> {
+    /// Creates a server that decodes requests with `codec`.
+    pub fn with_codec(codec: C) -> RpcServer<C> {
+        RpcServer {
+            handlers: HashMap::new(),
+            stream_handlers: HashMap::new(),
+            codec,
+        }
+    }
+
+    /// Registers a handler for `method` that works on encoded requests and responses.
+    pub fn register_raw<F, Fut>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<u8>>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |request| Box::pin(handler(request)));
+
+        self.handlers.insert(method.to_owned(), handler);
+        self
+    }
+
+    /// Registers a handler for `method`, decoding requests and encoding responses with this
+    /// server's codec. Errors the handler returns are sent to the client, where they surface
+    /// as `IpcError::Remote`.
+    pub fn register<Req, Resp, F, Fut>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        C: Encoder<Resp> + Decoder<Req> + Clone + Send + Sync + 'static,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Resp>> + Send + 'static,
+        Req: Send + 'static,
+        Resp: Send + 'static,
+    {
+        let codec = self.codec.clone();
+        let handler = Arc::new(handler);
+
+        self.register_raw(method, move |request| {
+            let codec = codec.clone();
+            let handler = handler.clone();
+
+            async move {
+                let request = codec.decode(&request).map_err(IpcError::Decode)?;
+                let response = handler(request).await?;
+
+                codec.encode(&response).map_err(IpcError::Encode)
+            }
+        })
+    }
+
+    /// Registers a handler for the streaming `method`, which receives the client's stream of
+    /// `In` values and sends a stream of `Out` values back. The server's side of the stream
+    /// ends when the handler returns, with the error it returns if it fails.
+    pub fn register_streaming<In, Out, F, Fut>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        C: Encoder<Out> + Decoder<In> + Clone + Send + Sync + 'static,
+        F: Fn(RpcReceiver<In, C>, RpcSender<Out, C>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+        In: 'static,
+        Out: 'static,
+    {
+        let codec = self.codec.clone();
+
+        let handler: StreamHandler = Arc::new(move |stream| {
+            let (sender, receiver) = stream.into_halves(codec.clone());
+
+            Box::pin(handler(receiver, sender))
+        });
+
+        self.stream_handlers.insert(method.to_owned(), handler);
+        self
+    }
+
+    /// Registers a handler for the server-streaming `method`, which takes one request and
+    /// sends a stream of `Item` values back. Call it with `RpcClient::call_streaming`.
+    pub fn register_server_streaming<Req, Item, F, Fut>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        C: Encoder<Item> + Decoder<Req> + Clone + Send + Sync + 'static,
+        F: Fn(Req, RpcSender<Item, C>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+        Req: Send + 'static,
+        Item: 'static,
+    {
+        let handler = Arc::new(handler);
+
+        self.register_streaming(method, move |mut receiver: RpcReceiver<Req, C>, sender| {
+            let handler = handler.clone();
+
+            async move {
+                let request = receiver.recv().await?
+                    .ok_or_else(|| IpcError::protocol_mismatch("Stream ended before its request"))?;
+
+                handler(request, sender).await
+            }
+        })
+    }
+
+    /// Registers a handler for the client-streaming `method`, which receives a stream of
+    /// `Item` values and responds once. Call it with `RpcClient::open`.
+    pub fn register_client_streaming<Item, Resp, F, Fut>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        C: Encoder<Resp> + Decoder<Item> + Clone + Send + Sync + 'static,
+        F: Fn(RpcReceiver<Item, C>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Resp>> + Send + 'static,
+        Item: 'static,
+        Resp: Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+
+        self.register_streaming(method, move |receiver, sender: RpcSender<Resp, C>| {
+            let handler = handler.clone();
+
+            async move {
+                let response = handler(receiver).await?;
+
+                sender.send(&response).await
+            }
+        })
+    }
+
+    /// Serves calls on `connection` until the client disconnects. Calls still running when
+    /// this returns are cancelled.
+    ///
+    /// Each call is handled in an `ipc.call` span. With the `opentelemetry` feature, its parent
+    /// is the span the client made the call from.
+    ///
+    /// Handlers can find their call's deadline and cancellation token with
+    /// `CallContext::current`. A handler stops being polled once the client cancels its call
+    /// or the call's deadline passes. Either way the call goes unanswered, since the client
+    /// has already stopped waiting for it.
+    pub async fn serve(&self, connection: MessageIpcConnection) -> Result<()> {
+        let connection = Arc::new(connection);
+        let writer = FrameWriter::spawn(&Handle::current(), connection.clone());
+        let mut calls = JoinSet::new();
+
+        // The cancellation tokens of the calls in flight.
+        let mut tokens: HashMap<u64, CancellationToken> = HashMap::new();
+
+        // Where to send the messages the client streams to each streaming call in flight.
+        let mut streams: HashMap<u64, StreamItemSender> = HashMap::new();
+
+        // Reading a message isn't cancel safe, so the read in progress is kept across calls
+        // finishing and only replaced once it completes.
+        let mut read = Box::pin(connection.read_message());
+
+        loop {
+            // Reap calls as they finish, so neither the set nor the maps of calls in flight
+            // grow without bound while the client is quiet.
+            let message = loop {
+                if calls.is_empty() {
+                    break (&mut read).await;
+                }
+
+                match future::select(&mut read, Box::pin(calls.join_next())).await {
+                    Either::Left((message, _)) => break message,
+                    Either::Right((Some(Ok(call_id)), _)) => {
+                        tokens.remove(&call_id);
+                        streams.remove(&call_id);
+                    }
+                    Either::Right(_) => {}
+                }
+            };
+
+            read.set(connection.read_message());
+
+            let message = match message {
+                Ok(message) => message,
+                Err(IpcError::PeerDisconnected) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            match Frame::decode(&message.body)? {
+                Frame::Request { call_id, method, timeout, body } => {
+                    let handler = self.handlers.get(&method).cloned();
+                    let writer = writer.clone();
+                    let context = CallContext::new(timeout);
+                    let span = call_span(&method, call_id, &message.headers);
+
+                    tokens.insert(call_id, context.cancellation_token().clone());
+
+                    calls.spawn(async move {
+                        let result = match handler {
+                            Some(handler) => context.run(handler(body)).await,
+                            None => Ok(Err(unknown_method(&method))),
+                        };
+
+                        let frame = match result {
+                            // The client has given up, so there's no one to answer.
+                            Err(IpcError::Cancelled) | Err(IpcError::DeadlineExceeded) => return call_id,
+                            Err(err) | Ok(Err(err)) => Frame::Error {
+                                call_id,
+                                description: err.into_remote_description(),
+                            },
+                            Ok(Ok(body)) => Frame::Response { call_id, body },
+                        };
+
+                        send_result(&writer, frame).await;
+                        call_id
+                    }.instrument(span));
+                }
+                Frame::Open { call_id, method, timeout } => {
+                    let handler = self.stream_handlers.get(&method).cloned();
+                    let context = CallContext::new(timeout);
+                    let span = call_span(&method, call_id, &message.headers);
+
+                    tokens.insert(call_id, context.cancellation_token().clone());
+                    let (items_tx, items) = mpsc::unbounded();
+                    let ended = Arc::new(AtomicBool::new(false));
+
+                    if handler.is_some() {
+                        streams.insert(call_id, items_tx);
+                    }
+
+                    let stream = OpenedStream {
+                        writer: writer.clone(),
+                        call_id,
+                        items,
+                        ended: ended.clone(),
+                    };
+
+                    let writer = writer.clone();
+
+                    calls.spawn(async move {
+                        let result = match handler {
+                            Some(handler) => context.run(handler(stream)).await,
+                            None => Ok(Err(unknown_method(&method))),
+                        };
+
+                        let frame = match result {
+                            Err(IpcError::Cancelled) | Err(IpcError::DeadlineExceeded) => return call_id,
+                            Err(err) | Ok(Err(err)) => Frame::Error {
+                                call_id,
+                                description: err.into_remote_description(),
+                            },
+                            Ok(Ok(())) if ended.swap(true, Ordering::SeqCst) => return call_id,
+                            Ok(Ok(())) => Frame::End { call_id },
+                        };
+
+                        send_result(&writer, frame).await;
+                        call_id
+                    }.instrument(span));
+                }
+                Frame::Data { call_id, body } => {
+                    // The handler may have stopped receiving, in which case the rest of the
+                    // stream is discarded.
+                    let delivered = streams
+                        .get(&call_id)
+                        .is_some_and(|items_tx| items_tx.unbounded_send(Ok(Some(body))).is_ok());
+
+                    if !delivered {
+                        streams.remove(&call_id);
+                    }
+                }
+                Frame::End { call_id } => {
+                    if let Some(items_tx) = streams.remove(&call_id) {
+                        let _ = items_tx.unbounded_send(Ok(None));
+                    }
+                }
+                Frame::Cancel { call_id } => {
+                    streams.remove(&call_id);
+
+                    if let Some(token) = tokens.remove(&call_id) {
+                        token.cancel();
+                    }
+                }
+                Frame::Response { .. } | Frame::Error { .. } => {
+                    return Err(IpcError::protocol_mismatch("RPC client sent a response"));
+                }
+            };
+        }
+    }
+}
+
+/// A span for handling one call, continuing the client's trace if the request carries one.
+#[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+fn call_span(method: &str, call_id: u64, headers: &Headers) -> Span {
+    let span = tracing::info_span!("ipc.call", method, call_id);
+
+    #[cfg(feature = "opentelemetry")]
+    headers.continue_trace(&span);
+
+    span
+}
+
+fn unknown_method(method: &str) -> IpcError {
+    IpcError::Remote(format!("Unknown method {}", method))
+}
+
+/// Sends the frame that completes a call, which only fails if the client has gone away.
+async fn send_result(writer: &FrameWriter, frame: Frame) {
+    if let Err(err) = writer.write(&frame).await {
+        trace!(%err, "Failed to send the result of a call");
+    }
+}
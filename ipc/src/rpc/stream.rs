@@ -0,0 +1,170 @@
+use super::client::CallGuard;
+use super::frame::Frame;
+use super::writer::FrameWriter;
+use crate::codec::{BincodeCodec, Decoder, Encoder};
+use crate::error::{IpcError, Result};
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// What a stream's receiver gets for each frame addressed to it: a message, the end of the
+/// stream, or the error that ended it.
+pub(crate) type StreamItem = Result<Option<Vec<u8>>>;
+
+pub(crate) type StreamItemSender = mpsc::UnboundedSender<StreamItem>;
+
+/// Sends values of type `T` on one side of a streaming call.
+pub struct RpcSender<T, C = BincodeCodec> {
+    writer: FrameWriter,
+    call_id: u64,
+    codec: C,
+    ended: Arc<AtomicBool>,
+    end_on_drop: bool,
+    _types: PhantomData<fn(T)>,
+}
+
+impl<T, C> RpcSender<T, C> {
+    pub(crate) fn new(
+        writer: FrameWriter,
+        call_id: u64,
+        codec: C,
+        ended: Arc<AtomicBool>,
+        end_on_drop: bool,
+    ) -> RpcSender<T, C> {
+        RpcSender {
+            writer,
+            call_id,
+            codec,
+            ended,
+            end_on_drop,
+            _types: PhantomData,
+        }
+    }
+
+    /// Ends this side of the stream. The peer's receiver returns `None` once it has received
+    /// everything sent before this.
+    pub async fn finish(self) -> Result<()> {
+        self.ended.store(true, Ordering::SeqCst);
+
+        self.writer.write(&Frame::End { call_id: self.call_id }).await
+    }
+}
+
+impl<T, C: Encoder<T>> RpcSender<T, C> {
+    /// Encodes `value` and sends it to the peer's receiver.
+    pub async fn send(&self, value: &T) -> Result<()> {
+        let body = self.codec.encode(value).map_err(IpcError::Encode)?;
+
+        self.writer.write(&Frame::Data { call_id: self.call_id, body }).await
+    }
+}
+
+impl<T, C> Drop for RpcSender<T, C> {
+    fn drop(&mut self) {
+        if !self.end_on_drop || self.ended.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // Dropping a sender without finishing it still ends the stream, so the peer doesn't
+        // wait forever.
+        self.writer.write_detached(&Frame::End { call_id: self.call_id });
+    }
+}
+
+/// Receives values of type `T` on one side of a streaming call.
+pub struct RpcReceiver<T, C = BincodeCodec> {
+    items: mpsc::UnboundedReceiver<StreamItem>,
+    codec: C,
+    finished: bool,
+
+    /// On the client, cancels the call if the receiver is dropped before the stream ends.
+    call: Option<CallGuard>,
+
+    /// On the client, when the call's deadline passes.
+    deadline: Option<tokio::time::Instant>,
+    _types: PhantomData<fn() -> T>,
+}
+
+impl<T, C> RpcReceiver<T, C> {
+    pub(crate) fn new(
+        items: mpsc::UnboundedReceiver<StreamItem>,
+        codec: C,
+        call: Option<CallGuard>,
+        deadline: Option<tokio::time::Instant>,
+    ) -> RpcReceiver<T, C> {
+        RpcReceiver {
+            items,
+            codec,
+            finished: false,
+            call,
+            deadline,
+            _types: PhantomData,
+        }
+    }
+
+    fn finish(&mut self) {
+        self.finished = true;
+
+        if let Some(call) = self.call.as_mut() {
+            call.finish();
+        }
+    }
+}
+
+impl<T, C: Decoder<T>> RpcReceiver<T, C> {
+    /// Receives the next value, or `None` once the peer has ended its side of the stream. An
+    /// error the peer ended the stream with surfaces as `IpcError::Remote`, and a call whose
+    /// deadline passes fails with `IpcError::DeadlineExceeded`.
+    pub async fn recv(&mut self) -> Result<Option<T>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let next = match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, self.items.next()).await,
+            None => Ok(self.items.next().await),
+        };
+
+        let item = match next {
+            Ok(Some(item)) => item,
+            Ok(None) => Err(IpcError::PeerDisconnected),
+            // The server abandons the call at its deadline too, so there's nothing to cancel.
+            Err(_) => Err(IpcError::DeadlineExceeded),
+        };
+
+        match item {
+            Ok(Some(body)) => self.codec.decode(&body).map(Some).map_err(IpcError::Decode),
+            Ok(None) => {
+                self.finish();
+                Ok(None)
+            }
+            Err(err) => {
+                self.finish();
+                Err(err)
+            }
+        }
+    }
+}
+
+/// The server's end of a stream a client has just opened, before the handler's codec is known.
+pub(crate) struct OpenedStream {
+    pub(crate) writer: FrameWriter,
+    pub(crate) call_id: u64,
+    pub(crate) items: mpsc::UnboundedReceiver<StreamItem>,
+
+    /// Set once the handler finishes its side of the stream, so the server doesn't end it twice.
+    pub(crate) ended: Arc<AtomicBool>,
+}
+
+impl OpenedStream {
+    pub(crate) fn into_halves<Tx, Rx, C: Clone>(self, codec: C) -> (RpcSender<Tx, C>, RpcReceiver<Rx, C>) {
+        let sender = RpcSender::new(self.writer, self.call_id, codec.clone(), self.ended, false);
+        let receiver = RpcReceiver::new(self.items, codec, None, None);
+
+        (sender, receiver)
+    }
+}
@@ -0,0 +1,75 @@
+use super::frame::Frame;
+use crate::error::{IpcError, Result};
+use crate::ipc::MessageIpcConnection;
+
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
+use tokio::runtime::Handle;
+use tracing::{trace, Instrument, Span};
+
+use std::sync::Arc;
+
+/// An encoded frame waiting to be written, the span it was queued in, and where to report how
+/// writing it went.
+type QueuedFrame = (Vec<u8>, Span, Option<oneshot::Sender<Result<()>>>);
+
+/// Writes frames to a connection from a task of its own, in the order they're queued.
+///
+/// Writing a message isn't cancel-safe: a write dropped partway leaves half a frame on the
+/// connection. Callers here wait for their frame to be written, but dropping one that's
+/// waiting doesn't interrupt the write, so cancelling a call can't corrupt the calls after it.
+/// Frames are written in the span they were queued in, so they carry their caller's trace.
+#[derive(Clone)]
+pub(crate) struct FrameWriter {
+    frames: mpsc::UnboundedSender<QueuedFrame>,
+}
+
+impl FrameWriter {
+    /// Starts a task on `runtime` writing frames to `connection`. It runs until every clone
+    /// of the writer has been dropped and the frames already queued have been written.
+    pub(crate) fn spawn(runtime: &Handle, connection: Arc<MessageIpcConnection>) -> FrameWriter {
+        let (frames, mut queue) = mpsc::unbounded::<QueuedFrame>();
+
+        runtime.spawn(async move {
+            while let Some((data, span, written)) = queue.next().await {
+                let result = connection.write(&data).instrument(span).await;
+
+                match written {
+                    Some(written) => {
+                        let _ = written.send(result);
+                    }
+                    None => {
+                        if let Err(err) = result {
+                            trace!(%err, "Failed to write RPC frame");
+                        }
+                    }
+                }
+            }
+        });
+
+        FrameWriter { frames }
+    }
+
+    /// Writes `frame`, returning once it has been written.
+    pub(crate) async fn write(&self, frame: &Frame) -> Result<()> {
+        let (written_tx, written_rx) = oneshot::channel();
+
+        self.queue(frame, Some(written_tx))?;
+
+        written_rx.await.unwrap_or(Err(IpcError::PeerDisconnected))
+    }
+
+    /// Queues `frame` to be written without waiting for it, for where we can't wait, such as
+    /// in `Drop`.
+    pub(crate) fn write_detached(&self, frame: &Frame) {
+        if let Err(err) = self.queue(frame, None) {
+            trace!(%err, "Failed to queue RPC frame");
+        }
+    }
+
+    fn queue(&self, frame: &Frame, written: Option<oneshot::Sender<Result<()>>>) -> Result<()> {
+        let data = frame.encode()?;
+
+        self.frames.unbounded_send((data, Span::current(), written)).map_err(|_| IpcError::PeerDisconnected)
+    }
+}
@@ -0,0 +1,178 @@
+use crate::error::{IpcError, Result};
+use crate::ipc::{MessageIpcClient, MessageIpcConnection, MessageIpcServer};
+use crate::name::IntoIpcName;
+use crate::options::{ClientOptions, ServerOptions};
+
+use tracing::trace;
+
+use std::time::Duration;
+
+/// How many times to try becoming, or connecting to, the primary before giving up. Only
+/// needed when the primary starts or exits while we're looking for it.
+const ATTEMPTS: u32 = 10;
+
+/// How long to wait between attempts.
+const RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// How long a secondary waits for a busy primary to accept its connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Makes sure only one instance of an application runs at a time, built on servers'
+/// first-instance exclusivity. The first instance to acquire a name becomes the primary and
+/// gets the server; later ones connect to it, typically to forward their arguments and exit.
+///
+/// ```no_run
+/// # use ipc::SingleInstance;
+/// # async fn run() -> ipc::Result<()> {
+/// let args = std::env::args().collect::<Vec<_>>().join("\0");
+///
+/// match SingleInstance::acquire("my_app").await? {
+///     SingleInstance::Primary(mut server) => loop {
+///         let (connection, next_server) = server.wait_for_connection().await?;
+///         println!("Forwarded {:?}", connection.read().await?);
+///         server = next_server;
+///     },
+///     SingleInstance::Secondary(connection) => connection.write(args.as_bytes()).await,
+/// }
+/// # }
+/// ```
+// Acquired once per process and matched on straight away, so its size doesn't matter.
+#[allow(clippy::large_enum_variant)]
+pub enum SingleInstance {
+    /// No other instance owns the name, so we do.
+    Primary(MessageIpcServer),
+
+    /// Another instance owns the name, and this is our connection to it.
+    Secondary(MessageIpcConnection),
+}
+
+impl SingleInstance {
+    /// Becomes the primary instance for `name`, or connects to the existing primary.
+    pub async fn acquire(name: impl IntoIpcName) -> Result<SingleInstance> {
+        let client_options = ClientOptions::new().timeout(CONNECT_TIMEOUT);
+
+        SingleInstance::acquire_with_options(name, &ServerOptions::new(), &client_options).await
+    }
+
+    /// Like `acquire`, with the options used to create the server if we become the primary
+    /// and to connect to the primary otherwise. First-instance exclusivity is always enabled.
+    /// Secondaries authenticate if `client_options` has a pre-shared key, so the primary must
+    /// be waiting for connections for them to connect.
+    pub async fn acquire_with_options(
+        name: impl IntoIpcName,
+        server_options: &ServerOptions,
+        client_options: &ClientOptions,
+    ) -> Result<SingleInstance> {
+        let name = name.into_ipc_name()?;
+        let server_options = server_options.clone().first_instance(true);
+        let mut attempt = 1;
+
+        loop {
+            let err = match MessageIpcServer::with_options(&name, &server_options) {
+                Ok(server) => return Ok(SingleInstance::Primary(server)),
+                Err(IpcError::AddrInUse) => match MessageIpcClient::connect(&name, client_options).await {
+                    Ok(connection) => return Ok(SingleInstance::Secondary(connection)),
+                    // The primary is there, but won't have us.
+                    Err(IpcError::AuthenticationFailed) => return Err(IpcError::AuthenticationFailed),
+                    Err(err) => err,
+                },
+                Err(err) => return Err(err),
+            };
+
+            // The primary exited after we found it, or hasn't started listening yet.
+            if attempt == ATTEMPTS {
+                return Err(err);
+            }
+
+            trace!(%err, attempt, "Failed to connect to the primary instance");
+
+            attempt += 1;
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    /// Becomes the primary instance for `name`, returning its server, or sends `payload` to
+    /// the existing primary and returns `None`.
+    pub async fn acquire_or_send(name: impl IntoIpcName, payload: &[u8]) -> Result<Option<MessageIpcServer>> {
+        match SingleInstance::acquire(name).await? {
+            SingleInstance::Primary(server) => Ok(Some(server)),
+            SingleInstance::Secondary(connection) => {
+                connection.write(payload).await?;
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Whether we're the primary instance.
+    pub fn is_primary(&self) -> bool {
+        matches!(self, SingleInstance::Primary(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SingleInstance;
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+
+    #[test]
+    fn later_instances_forward_to_the_primary() {
+        install_logger();
+
+        let name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = match SingleInstance::acquire(&name).await.unwrap() {
+                SingleInstance::Primary(server) => server,
+                SingleInstance::Secondary(_) => panic!("Expected to be the primary"),
+            };
+
+            assert!(!SingleInstance::acquire(&name).await.unwrap().is_primary());
+
+            let (_connection, server) = server.wait_for_connection().await.unwrap();
+
+            assert!(SingleInstance::acquire_or_send(&name, b"--open file.txt").await.unwrap().is_none());
+
+            let (connection, server) = server.wait_for_connection().await.unwrap();
+            assert_eq!(connection.read().await.unwrap(), b"--open file.txt");
+
+            // Once the primary exits, the next instance takes over.
+            drop(server);
+
+            assert!(SingleInstance::acquire(&name).await.unwrap().is_primary());
+        });
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn secondaries_authenticate_with_a_pre_shared_key() {
+        use crate::options::{ClientOptions, ServerOptions};
+
+        install_logger();
+
+        let name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server_options = ServerOptions::new().pre_shared_key(b"secret");
+            let client_options = ClientOptions::new().pre_shared_key(b"secret");
+
+            let server = match SingleInstance::acquire_with_options(&name, &server_options, &client_options).await.unwrap() {
+                SingleInstance::Primary(server) => server,
+                SingleInstance::Secondary(_) => panic!("Expected to be the primary"),
+            };
+
+            let accepted = tokio::spawn(async move { server.wait_for_connection().await.unwrap().0.read().await.unwrap() });
+
+            match SingleInstance::acquire_with_options(&name, &server_options, &client_options).await.unwrap() {
+                SingleInstance::Secondary(connection) => connection.write(b"--open file.txt").await.unwrap(),
+                SingleInstance::Primary(_) => panic!("Expected to be a secondary"),
+            };
+
+            assert_eq!(accepted.await.unwrap(), b"--open file.txt");
+        });
+    }
+}
@@ -0,0 +1,461 @@
+//! Traffic statistics for connections and servers.
+//!
+//! Every connection counts what passes through it, and every server totals the connections it
+//! accepted. `stats()` on either returns a snapshot. With the `metrics` feature, the same
+//! figures are also reported through the `metrics` crate, labelled with the endpoint's name.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A distribution of values in power-of-two buckets, e.g. message sizes in bytes or latencies
+/// in microseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+
+    /// Bucket `i` counts the values below 2^i and not in an earlier bucket.
+    buckets: [u64; 65],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            count: 0,
+            sum: 0,
+            min: 0,
+            max: 0,
+            buckets: [0; 65],
+        }
+    }
+}
+
+impl Histogram {
+    pub(crate) fn record(&mut self, value: u64) {
+        self.min = if self.count == 0 { value } else { self.min.min(value) };
+        self.max = self.max.max(value);
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.buckets[64 - value.leading_zeros() as usize] += 1;
+    }
+
+    /// The number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// The smallest value recorded, or 0 if there are none.
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    /// The largest value recorded, or 0 if there are none.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The mean of the values recorded, or 0 if there are none.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+
+        self.sum as f64 / self.count as f64
+    }
+
+    /// An upper bound on the value at quantile `q`, between 0 and 1, accurate to within a
+    /// factor of two.
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+
+            if seen >= rank {
+                return Histogram::upper_bound(i).min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// The non-empty buckets, as the largest value each can hold and how many values it has.
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| (Histogram::upper_bound(i), *count))
+    }
+
+    fn upper_bound(bucket: usize) -> u64 {
+        match bucket {
+            0 => 0,
+            64 => u64::MAX,
+            i => (1 << i) - 1,
+        }
+    }
+}
+
+/// What has passed through a connection, or through all of a server's connections.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// Bytes read from the OS, including framing.
+    pub bytes_in: u64,
+
+    /// Bytes written to the OS, including framing.
+    pub bytes_out: u64,
+
+    pub messages_in: u64,
+    pub messages_out: u64,
+
+    /// The sizes of the messages read, in bytes, including any headers.
+    pub frame_sizes_in: Histogram,
+
+    /// The sizes of the messages written, in bytes, including any headers.
+    pub frame_sizes_out: Histogram,
+
+    /// How long messages took to read once they started arriving, in microseconds.
+    pub read_latency_us: Histogram,
+
+    /// How long messages took to write, in microseconds, including waiting for other writes
+    /// to the same connection.
+    pub write_latency_us: Histogram,
+}
+
+/// A server's connections, along with the totals of everything that passed through them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerStats {
+    /// The number of connections the server has accepted.
+    pub accepted: u64,
+
+    /// The number of accepted connections still open.
+    pub active_connections: u64,
+
+    /// How long ago the server was created.
+    pub uptime: Duration,
+
+    /// The totals of every connection the server has accepted, open or not.
+    pub connections: ConnectionStats,
+}
+
+impl ServerStats {
+    /// The mean number of connections accepted per second since the server was created.
+    pub fn accept_rate(&self) -> f64 {
+        let seconds = self.uptime.as_secs_f64();
+
+        if seconds == 0.0 {
+            return 0.0;
+        }
+
+        self.accepted as f64 / seconds
+    }
+}
+
+/// Counts what passes through a connection.
+#[derive(Default)]
+struct Counters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+
+    /// Everything else, which changes once per message rather than per transfer.
+    messages: Mutex<ConnectionStats>,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ConnectionStats {
+        let mut stats = self.messages.lock().unwrap().clone();
+
+        stats.bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        stats.bytes_out = self.bytes_out.load(Ordering::Relaxed);
+
+        stats
+    }
+}
+
+/// Collects a server's statistics. Shared with the connections it accepts.
+pub(crate) struct ServerRecorder {
+    #[cfg(feature = "metrics")]
+    name: String,
+    created: Instant,
+    accepted: AtomicU64,
+    active: AtomicU64,
+    connections: Counters,
+}
+
+impl ServerRecorder {
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn new(name: &str) -> Arc<ServerRecorder> {
+        Arc::new(ServerRecorder {
+            #[cfg(feature = "metrics")]
+            name: name.to_owned(),
+            created: Instant::now(),
+            accepted: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+            connections: Counters::default(),
+        })
+    }
+
+    /// Records a newly accepted connection, returning the recorder for it.
+    pub fn accepted(self: &Arc<Self>) -> ConnectionRecorder {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("ipc_connections_accepted_total", "name" => self.name.clone()).increment(1);
+            metrics::gauge!("ipc_active_connections", "name" => self.name.clone()).increment(1.0);
+        }
+
+        ConnectionRecorder {
+            #[cfg(feature = "metrics")]
+            name: self.name.clone(),
+            counters: Counters::default(),
+            server: Some(self.clone()),
+        }
+    }
+
+    pub fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            active_connections: self.active.load(Ordering::Relaxed),
+            uptime: self.created.elapsed(),
+            connections: self.connections.snapshot(),
+        }
+    }
+}
+
+/// Collects a connection's statistics, adding them to its server's totals as it goes.
+pub(crate) struct ConnectionRecorder {
+    /// The endpoint's name, for labelling metrics.
+    #[cfg(feature = "metrics")]
+    name: String,
+    counters: Counters,
+    server: Option<Arc<ServerRecorder>>,
+}
+
+impl ConnectionRecorder {
+    /// A recorder for a client's connection, which has no server.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn client(name: &str) -> ConnectionRecorder {
+        ConnectionRecorder {
+            #[cfg(feature = "metrics")]
+            name: name.to_owned(),
+            counters: Counters::default(),
+            server: None,
+        }
+    }
+
+    /// Calls `update` with this connection's counters, then its server's.
+    fn each(&self, update: impl Fn(&Counters)) {
+        update(&self.counters);
+
+        if let Some(server) = &self.server {
+            update(&server.connections);
+        }
+    }
+
+    pub fn read(&self, bytes: u32) {
+        self.each(|counters| {
+            counters.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        });
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("ipc_bytes_received_total", "name" => self.name.clone()).increment(bytes as u64);
+    }
+
+    pub fn wrote(&self, bytes: u32) {
+        self.each(|counters| {
+            counters.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        });
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("ipc_bytes_sent_total", "name" => self.name.clone()).increment(bytes as u64);
+    }
+
+    pub fn received_message(&self, size: u64, latency: Duration) {
+        let latency_us = latency.as_micros() as u64;
+
+        self.each(|counters| {
+            let mut stats = counters.messages.lock().unwrap();
+
+            stats.messages_in += 1;
+            stats.frame_sizes_in.record(size);
+            stats.read_latency_us.record(latency_us);
+        });
+
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("ipc_messages_received_total", "name" => self.name.clone()).increment(1);
+            metrics::histogram!("ipc_frame_size_bytes", "name" => self.name.clone(), "direction" => "in").record(size as f64);
+            metrics::histogram!("ipc_read_duration_seconds", "name" => self.name.clone()).record(latency.as_secs_f64());
+        }
+    }
+
+    pub fn sent_message(&self, size: u64, latency: Duration) {
+        let latency_us = latency.as_micros() as u64;
+
+        self.each(|counters| {
+            let mut stats = counters.messages.lock().unwrap();
+
+            stats.messages_out += 1;
+            stats.frame_sizes_out.record(size);
+            stats.write_latency_us.record(latency_us);
+        });
+
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("ipc_messages_sent_total", "name" => self.name.clone()).increment(1);
+            metrics::histogram!("ipc_frame_size_bytes", "name" => self.name.clone(), "direction" => "out").record(size as f64);
+            metrics::histogram!("ipc_write_duration_seconds", "name" => self.name.clone()).record(latency.as_secs_f64());
+        }
+    }
+
+    pub fn snapshot(&self) -> ConnectionStats {
+        self.counters.snapshot()
+    }
+}
+
+impl Drop for ConnectionRecorder {
+    fn drop(&mut self) {
+        if let Some(server) = &self.server {
+            server.active.fetch_sub(1, Ordering::Relaxed);
+
+            #[cfg(feature = "metrics")]
+            metrics::gauge!("ipc_active_connections", "name" => self.name.clone()).decrement(1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogram;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+
+    #[test]
+    fn histograms_bucket_by_powers_of_two() {
+        let mut histogram = Histogram::default();
+
+        assert_eq!(histogram.quantile(0.5), 0);
+        assert_eq!(histogram.mean(), 0.0);
+
+        for value in &[0, 1, 5, 6, 7, 100, u64::MAX] {
+            histogram.record(*value);
+        }
+
+        assert_eq!(histogram.count(), 7);
+        assert_eq!(histogram.min(), 0);
+        assert_eq!(histogram.max(), u64::MAX);
+        assert_eq!(
+            histogram.buckets().collect::<Vec<_>>(),
+            vec![(0, 1), (1, 1), (7, 3), (127, 1), (u64::MAX, 1)]
+        );
+
+        assert_eq!(histogram.quantile(0.0), 0);
+        assert_eq!(histogram.quantile(0.5), 7);
+        assert_eq!(histogram.quantile(0.8), 127);
+        assert_eq!(histogram.quantile(1.0), u64::MAX);
+    }
+
+    #[test]
+    fn connections_and_servers_count_traffic() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&server_name).unwrap();
+            let client = MessageIpcClient::new(&server_name).unwrap();
+            let (connection, server) = server.wait_for_connection().await.unwrap();
+
+            client.write(b"hello").await.unwrap();
+            client.write(&[0; 1000]).await.unwrap();
+            connection.read().await.unwrap();
+            connection.read().await.unwrap();
+            connection.write(b"bye").await.unwrap();
+            client.read().await.unwrap();
+
+            let stats = client.stats();
+
+            // Each message has an 8 byte size prefix.
+            assert_eq!(stats.bytes_out, 1021);
+            assert_eq!(stats.bytes_in, 11);
+            assert_eq!(stats.messages_out, 2);
+            assert_eq!(stats.messages_in, 1);
+            assert_eq!(stats.frame_sizes_out.max(), 1000);
+            assert_eq!(stats.write_latency_us.count(), 2);
+
+            let stats = connection.stats();
+
+            assert_eq!(stats.bytes_in, 1021);
+            assert_eq!(stats.messages_in, 2);
+            assert_eq!(stats.frame_sizes_in.sum(), 1005);
+            assert_eq!(stats.read_latency_us.count(), 2);
+
+            let stats = server.stats();
+
+            assert_eq!(stats.accepted, 1);
+            assert_eq!(stats.active_connections, 1);
+            assert_eq!(stats.connections, connection.stats());
+
+            // Closed connections still count towards the server's totals.
+            drop(connection);
+
+            let stats = server.stats();
+
+            assert_eq!(stats.active_connections, 0);
+            assert_eq!(stats.connections.messages_in, 2);
+            assert!(stats.accept_rate() > 0.0);
+        });
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn stats_are_exported_to_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        install_logger();
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        metrics::with_local_recorder(&recorder, || {
+            pool.block_on(async {
+                let server = MessageIpcServer::new(&server_name).unwrap();
+                let client = MessageIpcClient::new(&server_name).unwrap();
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+                client.write(b"hello").await.unwrap();
+                connection.read().await.unwrap();
+            });
+        });
+
+        let metrics = snapshotter.snapshot().into_vec();
+        let value = |name: &str| {
+            metrics
+                .iter()
+                .find(|(key, _, _, _)| key.key().name() == name)
+                .map(|(_, _, _, value)| value)
+        };
+
+        assert_eq!(value("ipc_connections_accepted_total"), Some(&DebugValue::Counter(1)));
+        assert_eq!(value("ipc_messages_sent_total"), Some(&DebugValue::Counter(1)));
+        assert_eq!(value("ipc_bytes_received_total"), Some(&DebugValue::Counter(13)));
+        assert!(matches!(value("ipc_read_duration_seconds"), Some(DebugValue::Histogram(_))));
+    }
+}
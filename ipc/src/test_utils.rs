@@ -13,5 +13,6 @@ pub fn install_logger() {
 }
 
 pub fn get_server_name() -> String {
-    format!("horsey_test_server{}", IPC_SERVER_COUNT.fetch_add(1, Ordering::SeqCst))
+    // Include the process id so concurrent test runs don't fight over names.
+    format!("horsey_test_server{}_{}", std::process::id(), IPC_SERVER_COUNT.fetch_add(1, Ordering::SeqCst))
 }
\ No newline at end of file
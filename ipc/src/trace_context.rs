@@ -0,0 +1,153 @@
+//! W3C trace context propagation through message headers, so a trace continues across the
+//! connection.
+
+use crate::message::{Headers, Message};
+
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use std::collections::BTreeMap;
+
+/// The metadata key of the W3C trace parent.
+pub const TRACEPARENT: &str = "traceparent";
+
+struct MetadataInjector<'a>(&'a mut BTreeMap<String, String>);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_owned(), value);
+    }
+}
+
+struct MetadataExtractor<'a>(&'a BTreeMap<String, String>);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_str).collect()
+    }
+}
+
+impl Headers {
+    /// Adds the current span's trace context as `traceparent` and `tracestate` metadata,
+    /// unless the headers already carry a trace context. Does nothing outside a span
+    /// OpenTelemetry knows about.
+    ///
+    /// Connections call this for every message they send.
+    pub fn inject_trace_context(&mut self) {
+        if self.metadata.contains_key(TRACEPARENT) {
+            return;
+        }
+
+        let context = tracing::Span::current().context();
+
+        TraceContextPropagator::new().inject_context(&context, &mut MetadataInjector(&mut self.metadata));
+    }
+
+    /// The trace context the message was sent from, if it carries one.
+    pub fn trace_context(&self) -> Option<opentelemetry::Context> {
+        if !self.metadata.contains_key(TRACEPARENT) {
+            return None;
+        }
+
+        let context = TraceContextPropagator::new()
+            .extract_with_context(&opentelemetry::Context::new(), &MetadataExtractor(&self.metadata));
+
+        Some(context)
+    }
+
+    /// Makes `span` a child of the span the message was sent from, if it carries a trace
+    /// context. Only takes effect before `span` starts, i.e. before it's first entered or has
+    /// children.
+    pub(crate) fn continue_trace(&self, span: &tracing::Span) {
+        if let Some(context) = self.trace_context() {
+            // Only fails if no OpenTelemetry layer is listening, in which case there's no
+            // trace to continue, or the span has already started.
+            let _ = span.set_parent(context);
+        }
+    }
+
+    /// Links `span` to the span the message was sent from, for spans that started before the
+    /// message's headers were known.
+    pub(crate) fn link_trace(&self, span: &tracing::Span) {
+        if let Some(context) = self.trace_context() {
+            span.add_link(context.span().span_context().clone());
+        }
+    }
+}
+
+impl Message {
+    /// A span for handling this message, whose parent is the span the message was sent from.
+    /// Messages without a trace context get a child of the current span instead.
+    pub fn span(&self) -> tracing::Span {
+        let span = tracing::info_span!(
+            "ipc.message",
+            message_type = self.headers.message_type.as_deref(),
+            bytes = self.body.len(),
+        );
+
+        self.headers.continue_trace(&span);
+
+        span
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TRACEPARENT;
+    use crate::ipc::{MessageIpcClient, MessageIpcServer};
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tokio::runtime;
+    use tracing::Instrument;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn traces_continue_across_connections() {
+        install_logger();
+
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("ipc")));
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            pool.block_on(async {
+                let server = MessageIpcServer::new(&server_name).unwrap();
+                let client = MessageIpcClient::new(&server_name).unwrap();
+                let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+                // Outside a span there's nothing to propagate.
+                client.write(b"untraced").await.unwrap();
+
+                let message = connection.read_message().await.unwrap();
+                assert!(message.headers.is_empty());
+
+                let send = tracing::info_span!("send");
+                let sent_from = send.context().span().span_context().clone();
+
+                client.write(b"traced").instrument(send).await.unwrap();
+
+                let message = connection.read_message().await.unwrap();
+                assert_eq!(message.body, b"traced");
+                assert!(message.headers.metadata.contains_key(TRACEPARENT));
+
+                let received = message.span().context().span().span_context().clone();
+
+                assert!(sent_from.is_valid());
+                assert_eq!(received.trace_id(), sent_from.trace_id());
+                assert_ne!(received.span_id(), sent_from.span_id());
+            });
+        });
+    }
+}
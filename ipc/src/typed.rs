@@ -0,0 +1,251 @@
+use crate::codec::{BincodeCodec, Decoder, Encoder};
+use crate::error::{IpcError, Result};
+use crate::ipc::{MessageIpcClient, MessageIpcConnection, MessageIpcServer};
+use crate::name::IntoIpcName;
+use crate::options::{ClientOptions, ServerOptions};
+
+use std::marker::PhantomData;
+
+/// A server whose connections exchange serde values rather than raw messages.
+pub struct TypedIpcServer<Tx, Rx, C = BincodeCodec> {
+    server: MessageIpcServer,
+    codec: C,
+    _types: PhantomData<fn(Tx) -> Rx>,
+}
+
+impl<Tx, Rx, C> TypedIpcServer<Tx, Rx, C>
+where
+    C: Encoder<Tx> + Decoder<Rx> + Clone + Default,
+{
+    pub fn new(name: impl IntoIpcName) -> Result<TypedIpcServer<Tx, Rx, C>> {
+        TypedIpcServer::with_options(name, &ServerOptions::default())
+    }
+
+    pub fn with_options(name: impl IntoIpcName, options: &ServerOptions) -> Result<TypedIpcServer<Tx, Rx, C>> {
+        let server = MessageIpcServer::with_options(name, options)?;
+
+        Ok(TypedIpcServer::from_server(server, C::default()))
+    }
+}
+
+impl<Tx, Rx, C> TypedIpcServer<Tx, Rx, C>
+where
+    C: Encoder<Tx> + Decoder<Rx> + Clone,
+{
+    /// Wraps an existing message server, encoding values on its connections with `codec`.
+    pub fn from_server(server: MessageIpcServer, codec: C) -> TypedIpcServer<Tx, Rx, C> {
+        TypedIpcServer {
+            server,
+            codec,
+            _types: PhantomData,
+        }
+    }
+
+    pub async fn wait_for_connection(self) -> Result<(TypedIpcConnection<Tx, Rx, C>, TypedIpcServer<Tx, Rx, C>)> {
+        let (connection, server) = self.server.wait_for_connection().await?;
+
+        let new_connection = TypedIpcConnection::with_codec(connection, self.codec.clone());
+        let new_server = TypedIpcServer::from_server(server, self.codec);
+
+        Ok((new_connection, new_server))
+    }
+}
+
+/// A connection that sends values of type `Tx` and receives values of type `Rx`, encoding
+/// each one as a single message with codec `C`.
+pub struct TypedIpcConnection<Tx, Rx, C = BincodeCodec> {
+    connection: MessageIpcConnection,
+    codec: C,
+    _types: PhantomData<fn(Tx) -> Rx>,
+}
+
+impl<Tx, Rx, C> TypedIpcConnection<Tx, Rx, C>
+where
+    C: Encoder<Tx> + Decoder<Rx>,
+{
+    /// Wraps an existing message connection using the default instance of codec `C`.
+    pub fn new(connection: MessageIpcConnection) -> TypedIpcConnection<Tx, Rx, C>
+    where
+        C: Default,
+    {
+        TypedIpcConnection::with_codec(connection, C::default())
+    }
+
+    /// Wraps an existing message connection, encoding values with `codec`.
+    pub fn with_codec(connection: MessageIpcConnection, codec: C) -> TypedIpcConnection<Tx, Rx, C> {
+        TypedIpcConnection {
+            connection,
+            codec,
+            _types: PhantomData,
+        }
+    }
+
+    /// Encodes `value` and sends it as one message, even if it encodes to nothing. Fails with
+    /// `IpcError::Encode` if the codec can't represent the value.
+    pub async fn send(&self, value: &Tx) -> Result<()> {
+        let data = self.codec.encode(value).map_err(IpcError::Encode)?;
+
+        self.connection.write_body(&data).await
+    }
+
+    /// Receives the next message and decodes it. Fails with `IpcError::Decode` if the
+    /// message isn't a valid `Rx`; the connection remains usable afterwards.
+    pub async fn recv(&self) -> Result<Rx> {
+        let data = self.connection.read().await?;
+
+        self.codec.decode(&data).map_err(IpcError::Decode)
+    }
+
+    /// The underlying message connection.
+    pub fn get_ref(&self) -> &MessageIpcConnection {
+        &self.connection
+    }
+
+    /// Unwraps the underlying message connection.
+    pub fn into_inner(self) -> MessageIpcConnection {
+        self.connection
+    }
+}
+
+pub struct TypedIpcClient {}
+
+impl TypedIpcClient {
+    pub fn new<Tx, Rx, C>(name: impl IntoIpcName) -> Result<TypedIpcConnection<Tx, Rx, C>>
+    where
+        C: Encoder<Tx> + Decoder<Rx> + Default,
+    {
+        TypedIpcClient::with_options(name, &ClientOptions::default())
+    }
+
+    pub fn with_options<Tx, Rx, C>(name: impl IntoIpcName, options: &ClientOptions) -> Result<TypedIpcConnection<Tx, Rx, C>>
+    where
+        C: Encoder<Tx> + Decoder<Rx> + Default,
+    {
+        let connection = MessageIpcClient::with_options(name, options)?;
+
+        Ok(TypedIpcConnection::new(connection))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TypedIpcClient, TypedIpcConnection, TypedIpcServer};
+    use crate::codec::BincodeCodec;
+    use crate::error::IpcError;
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use serde::{Deserialize, Serialize};
+    use tokio::runtime;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Request {
+        id: u32,
+        key: String,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Response {
+        Found(Vec<u8>),
+        Missing,
+    }
+
+    #[test]
+    fn can_send_typed_messages() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = TypedIpcServer::<Response, Request>::new(&server_name).unwrap();
+            let client: TypedIpcConnection<Request, Response> = TypedIpcClient::new(&server_name).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            client.send(&Request { id: 7, key: "horse".to_owned() }).await.unwrap();
+
+            let request = connection.recv().await.unwrap();
+
+            assert_eq!(request, Request { id: 7, key: "horse".to_owned() });
+
+            connection.send(&Response::Found(vec![1, 2, 3])).await.unwrap();
+            connection.send(&Response::Missing).await.unwrap();
+
+            assert_eq!(client.recv().await.unwrap(), Response::Found(vec![1, 2, 3]));
+            assert_eq!(client.recv().await.unwrap(), Response::Missing);
+        });
+    }
+
+    #[test]
+    fn can_send_values_that_encode_to_nothing() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = TypedIpcServer::<(), (), BincodeCodec>::new(&server_name).unwrap();
+            let client: TypedIpcConnection<(), ()> = TypedIpcClient::new(&server_name).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            client.send(&()).await.unwrap();
+            client.send(&()).await.unwrap();
+
+            connection.recv().await.unwrap();
+            connection.recv().await.unwrap();
+
+            connection.send(&()).await.unwrap();
+            client.recv().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn decode_errors_are_distinct_from_transport_errors() {
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = TypedIpcServer::<(), Response, BincodeCodec>::new(&server_name).unwrap();
+            let client: TypedIpcConnection<Request, ()> = TypedIpcClient::new(&server_name).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            // A Request doesn't decode as a Response, but the connection survives it.
+            client.send(&Request { id: 7, key: "horse".to_owned() }).await.unwrap();
+            client.get_ref().write(&[1, 0, 0, 0]).await.unwrap();
+
+            match connection.recv().await {
+                Err(IpcError::Decode(_)) => {}
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            assert_eq!(connection.recv().await.unwrap(), Response::Missing);
+        });
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn can_select_codec_per_connection() {
+        use crate::codec::JsonCodec;
+        use crate::ipc::MessageIpcClient;
+
+        install_logger();
+
+        let server_name = get_server_name();
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = TypedIpcServer::<(), Request, JsonCodec>::new(&server_name).unwrap();
+            let client = MessageIpcClient::new(&server_name).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            client.write(br#"{"id":7,"key":"horse"}"#).await.unwrap();
+
+            assert_eq!(connection.recv().await.unwrap(), Request { id: 7, key: "horse".to_owned() });
+        });
+    }
+}
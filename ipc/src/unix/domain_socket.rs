@@ -0,0 +1,662 @@
+use crate::credentials::PeerCredentials;
+use crate::error::{IpcError, Result};
+use crate::instances::{InstanceCounter, InstanceGuard};
+use crate::name::IpcName;
+use crate::options::{ClientOptions, ServerOptions};
+
+use super::endpoint::{lock_path, Endpoint};
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::net::{UnixListener, UnixStream};
+
+use tracing::trace;
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+const LISTEN_BACKLOG: i32 = 128;
+
+/// How often a client waiting for room in a server's backlog tries again.
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static NEXT_STAGING_DIR: AtomicU32 = AtomicU32::new(0);
+
+/// Fails with an error if no tokio runtime is running on this thread. Tokio's socket types
+/// panic when created outside a runtime, which is a poor experience for a constructor that
+/// already returns a Result.
+fn ensure_runtime() -> Result<()> {
+    tokio::runtime::Handle::try_current()
+        .map(|_| ())
+        .map_err(|_| IpcError::Io(std::io::Error::other("Unix domain sockets must be created within a tokio runtime")))
+}
+
+/// Removes the socket file when the server that created it goes away.
+struct SocketFile {
+    path: PathBuf,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        trace!(path = ?self.path, "Removing socket file");
+
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// An exclusive lock on `<socket path>.lock`, held by the server that owns the socket for as
+/// long as it runs. The OS releases the lock if the server crashes, so a socket whose lock file
+/// isn't locked is stale, and the next server to lock it may replace the socket.
+struct LockFile {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl LockFile {
+    /// Locks the lock file for the socket at `socket_path`, failing with `AddrInUse` if a live
+    /// server holds it.
+    fn acquire(socket_path: &Path) -> Result<LockFile> {
+        let path = lock_path(socket_path);
+
+        loop {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o600)
+                .open(&path)?;
+
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(fs::TryLockError::WouldBlock) => return Err(IpcError::AddrInUse),
+                Err(fs::TryLockError::Error(err)) => return Err(err.into()),
+            }
+
+            // The previous owner removes the file as it shuts down, so we may have locked a
+            // file that's no longer there. Only the file at the path counts.
+            let locked = file.metadata()?;
+
+            match fs::metadata(&path) {
+                Ok(current) if (current.dev(), current.ino()) == (locked.dev(), locked.ino()) => {
+                    // Forget a crashed server's pid. Ours is written once we're listening.
+                    file.set_len(0)?;
+
+                    return Ok(LockFile { path, file });
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Writes our pid to the file once our socket is listening. Until then the file is empty,
+    /// which tells `wait_for_endpoint` the socket isn't ready.
+    fn record_owner(&self) -> Result<()> {
+        self.file.set_len(0)?;
+        (&self.file).write_all(process::id().to_string().as_bytes())?;
+
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // Removed while still locked, so no one can lock it between us unlocking and removing
+        // it and then believe they own the name.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A directory only we can access, where sockets are set up before being moved into place.
+/// Removed along with its contents on drop.
+struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    fn new(parent: &Path) -> Result<StagingDir> {
+        loop {
+            let id = NEXT_STAGING_DIR.fetch_add(1, Ordering::Relaxed);
+            let path = parent.join(format!(".ipc-{}-{}", process::id(), id));
+
+            match fs::DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(StagingDir { path }),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Binds `socket` to `path`, with the mode, owner and group `options` ask for. The caller
+/// must hold the path's lock file, so anything already at `path` is a stale socket left by a
+/// server that crashed.
+///
+/// The socket is bound and set up in a private directory next to `path`, then moved into
+/// place, so clients can never reach it before its permissions are set.
+fn bind(socket: &Socket, path: &Path, options: &ServerOptions) -> Result<SocketFile> {
+    let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+    let staging_dir = StagingDir::new(parent)?;
+    let staged_path = staging_dir.path.join("socket");
+
+    socket.bind(&SockAddr::unix(&staged_path)?)?;
+
+    if let Some(mode) = options.mode {
+        fs::set_permissions(&staged_path, fs::Permissions::from_mode(mode))?;
+    }
+
+    if options.owner.is_some() || options.group.is_some() {
+        std::os::unix::fs::chown(&staged_path, options.owner, options.group)?;
+    }
+
+    // Renaming replaces a stale socket file in one step, so there's no moment when the path
+    // is missing or another server could take it.
+    fs::rename(&staged_path, path)?;
+
+    Ok(SocketFile { path: path.to_owned() })
+}
+
+pub struct DomainSocketServer {
+    listener: UnixListener,
+
+    // Sockets in the abstract namespace have no files. The socket is declared before the
+    // lock so it's removed while we still own the name.
+    _files: Option<(SocketFile, LockFile)>,
+    instances: InstanceCounter,
+    options: ServerOptions,
+}
+
+impl DomainSocketServer {
+    /// Creates a new socket server listening on the socket `name` resolves to, creating any
+    /// missing parent directories. Fails with `AddrInUse` if another live server owns the
+    /// name, and replaces the socket file of one that crashed.
+    pub fn new(name: &IpcName, options: &ServerOptions) -> Result<DomainSocketServer> {
+        ensure_runtime()?;
+
+        let endpoint = Endpoint::resolve(name)?;
+
+        trace!(?endpoint, "Creating domain socket");
+
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+
+        let files = match &endpoint {
+            Endpoint::Path { path, .. } => {
+                endpoint.create_parent_dirs(options.directory_mode)?;
+
+                let lock_file = LockFile::acquire(path)?;
+
+                // Take ownership of the file as soon as it exists so we clean up after
+                // ourselves if anything below fails.
+                Some((bind(&socket, path, options)?, lock_file))
+            }
+            Endpoint::Abstract(_) => {
+                socket.bind(&endpoint.address()?)?;
+                None
+            }
+        };
+
+        socket.listen(LISTEN_BACKLOG)?;
+        socket.set_nonblocking(true)?;
+
+        if let Some((_, lock_file)) = &files {
+            lock_file.record_owner()?;
+        }
+
+        let listener = UnixListener::from_std(net::UnixListener::from(socket))?;
+
+        Ok(DomainSocketServer {
+            listener,
+            _files: files,
+            instances: InstanceCounter::new(options.max_instances),
+            options: options.clone(),
+        })
+    }
+
+    /// Blocks the current task until a client connects.
+    pub async fn wait_for_connection(
+        self,
+    ) -> Result<(DomainSocketConnection, DomainSocketServer)> {
+        self.instances.wait_for_capacity().await;
+
+        trace!("Waiting for connection on domain socket");
+
+        let (stream, _) = self.listener.accept().await?;
+
+        trace!("Got a connection");
+
+        let socket = socket2::SockRef::from(&stream);
+
+        if self.options.in_buffer_size > 0 {
+            socket.set_recv_buffer_size(self.options.in_buffer_size as usize)?;
+        }
+
+        if self.options.out_buffer_size > 0 {
+            socket.set_send_buffer_size(self.options.out_buffer_size as usize)?;
+        }
+
+        let instance = self.instances.acquire();
+
+        Ok((DomainSocketConnection::new(stream, Some(instance))?, self))
+    }
+}
+
+pub struct DomainSocketConnection {
+    stream: UnixStream,
+    peer_credentials: PeerCredentials,
+    _instance: Option<InstanceGuard>,
+}
+
+impl DomainSocketConnection {
+    fn new(stream: UnixStream, instance: Option<InstanceGuard>) -> Result<DomainSocketConnection> {
+        let credentials = stream.peer_cred()?;
+
+        let peer_credentials = PeerCredentials {
+            pid: credentials.pid().map(|pid| pid as u32),
+            uid: Some(credentials.uid()),
+            gid: Some(credentials.gid()),
+            session_id: None,
+        };
+
+        trace!(?peer_credentials, "Connected to peer");
+
+        Ok(DomainSocketConnection {
+            stream,
+            peer_credentials,
+            _instance: instance,
+        })
+    }
+
+    pub fn peer_credentials(&self) -> &PeerCredentials {
+        &self.peer_credentials
+    }
+
+    /// Reads data on the socket connection, blocking the current task until data exists.
+    /// Fails with `BrokenPipe` if the peer has closed the connection, matching the
+    /// behavior of named pipes.
+    pub async fn read(&self, data: &mut [u8]) -> Result<u32> {
+        loop {
+            self.stream.readable().await?;
+
+            match self.stream.try_read(data) {
+                Ok(0) if !data.is_empty() => {
+                    return Err(IpcError::PeerDisconnected);
+                }
+                Ok(bytes_read) => {
+                    return Ok(bytes_read as u32);
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => {
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+
+    /// Writes the specified data to the socket connection. The resulting task blocks until
+    /// at least some of the data is written.
+    pub async fn write(&self, data: &[u8]) -> Result<u32> {
+        loop {
+            self.stream.writable().await?;
+
+            match self.stream.try_write(data) {
+                Ok(bytes_written) => {
+                    return Ok(bytes_written as u32);
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => {
+                    trace!(?err, "Failed to write data");
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+}
+
+pub struct DomainSocketClient {}
+
+impl DomainSocketClient {
+    /// Creates a socket connection to the socket `name` resolves to. If the server's backlog
+    /// is full, fails with `WouldBlock` or, if the options have a timeout, blocks the current
+    /// thread until there's room or the timeout elapses.
+    pub fn new(name: &IpcName, options: &ClientOptions) -> Result<DomainSocketConnection> {
+        let address = DomainSocketClient::resolve(name)?;
+
+        let stream = match options.timeout {
+            Some(timeout) => DomainSocketClient::connect_blocking(&address, timeout),
+            None => DomainSocketClient::try_connect(&address),
+        };
+
+        DomainSocketClient::connected(stream)
+    }
+
+    /// Like `new`, but waits for room in a full backlog without blocking the thread.
+    pub async fn connect(name: &IpcName, options: &ClientOptions) -> Result<DomainSocketConnection> {
+        let address = DomainSocketClient::resolve(name)?;
+
+        let stream = match options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, DomainSocketClient::wait_to_connect(&address))
+                .await
+                .unwrap_or_else(|_| Err(std::io::Error::new(ErrorKind::TimedOut, "The server didn't accept the connection in time"))),
+            None => DomainSocketClient::try_connect(&address),
+        };
+
+        DomainSocketClient::connected(stream)
+    }
+
+    fn resolve(name: &IpcName) -> Result<SockAddr> {
+        ensure_runtime()?;
+
+        let endpoint = Endpoint::resolve(name)?;
+        endpoint.check_private_dir()?;
+
+        trace!(?endpoint, "Connecting to domain socket");
+
+        endpoint.address()
+    }
+
+    fn connected(stream: std::io::Result<net::UnixStream>) -> Result<DomainSocketConnection> {
+        let stream = stream.map_err(|err| {
+            trace!(?err, "Failed to connect to domain socket");
+            err
+        })?;
+
+        stream.set_nonblocking(true)?;
+
+        DomainSocketConnection::new(UnixStream::from_std(stream)?, None)
+    }
+
+    /// Connects without blocking, failing with `WouldBlock` if the server's backlog is full.
+    fn try_connect(address: &SockAddr) -> std::io::Result<net::UnixStream> {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+
+        socket.set_nonblocking(true)?;
+        socket.connect(address)?;
+
+        Ok(net::UnixStream::from(socket))
+    }
+
+    /// A blocking connect on a Unix socket waits while the server's backlog is full, bounded by
+    /// the send timeout.
+    fn connect_blocking(address: &SockAddr, timeout: Duration) -> std::io::Result<net::UnixStream> {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+
+        socket.set_write_timeout(Some(timeout))?;
+        socket.connect(address)?;
+        socket.set_write_timeout(None)?;
+
+        Ok(net::UnixStream::from(socket))
+    }
+
+    /// Connects once the server's backlog has room. A full backlog fails a non-blocking connect
+    /// straight away rather than leaving it in progress, so there's nothing to wait on and
+    /// this polls.
+    async fn wait_to_connect(address: &SockAddr) -> std::io::Result<net::UnixStream> {
+        loop {
+            let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+            socket.set_nonblocking(true)?;
+
+            match socket.connect(address) {
+                Ok(()) => return Ok(net::UnixStream::from(socket)),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    tokio::time::sleep(CONNECT_POLL_INTERVAL).await;
+                }
+                // Some platforms do leave the connect in progress, and complete it once the
+                // socket is writable.
+                Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {
+                    let stream = UnixStream::from_std(net::UnixStream::from(socket))?;
+                    stream.writable().await?;
+
+                    if let Some(err) = stream.take_error()? {
+                        return Err(err);
+                    }
+
+                    return stream.into_std();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DomainSocketClient, DomainSocketServer};
+    use super::super::endpoint::Endpoint;
+    use crate::error::IpcError;
+    use crate::name::IpcName;
+    use crate::options::{ClientOptions, ServerOptions};
+    use crate::test_utils::{get_server_name, install_logger};
+
+    use tokio::runtime;
+
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    fn test_name() -> IpcName {
+        IpcName::new(&get_server_name()).unwrap()
+    }
+
+    fn socket_path(name: &IpcName) -> PathBuf {
+        match Endpoint::resolve(name).unwrap() {
+            Endpoint::Path { path, .. } => path,
+            endpoint => panic!("Unexpected endpoint {:?}", endpoint),
+        }
+    }
+
+    #[test]
+    fn server_removes_socket_file_on_drop() {
+        install_logger();
+
+        let pool = runtime::Runtime::new().unwrap();
+        let name = test_name();
+
+        pool.block_on(async {
+            let server = DomainSocketServer::new(&name, &ServerOptions::new()).unwrap();
+            let path = socket_path(&name);
+
+            assert!(path.exists());
+
+            drop(server);
+
+            assert!(!path.exists());
+        });
+    }
+
+    #[test]
+    fn sockets_are_created_with_the_requested_permissions() {
+        install_logger();
+
+        let pool = runtime::Runtime::new().unwrap();
+        let directory = get_server_name();
+        let name = IpcName::new(&format!("{}/nested/socket", directory)).unwrap();
+
+        pool.block_on(async {
+            // Owning the socket ourselves is the only ownership change we're allowed to make.
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+
+            let options = ServerOptions::new().mode(0o600).owner(uid).group(gid);
+            let server = DomainSocketServer::new(&name, &options).unwrap();
+            let path = socket_path(&name);
+
+            let metadata = fs::metadata(&path).unwrap();
+            assert!(metadata.file_type().is_socket());
+            assert_eq!(metadata.mode() & 0o777, 0o600);
+            assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+
+            let parent = fs::metadata(path.parent().unwrap()).unwrap();
+            assert_eq!(parent.mode() & 0o777, 0o700);
+
+            // Nothing but the socket and its lock file is left behind from setting it up.
+            let entries = fs::read_dir(path.parent().unwrap()).unwrap().count();
+            assert_eq!(entries, 2);
+
+            let _client = DomainSocketClient::new(&name, &ClientOptions::new()).unwrap();
+            let (_connection, server) = server.wait_for_connection().await.unwrap();
+
+            assert!(matches!(DomainSocketServer::new(&name, &options), Err(IpcError::AddrInUse)));
+
+            drop(server);
+
+            fs::remove_dir_all(socket_path(&IpcName::new(&directory).unwrap())).unwrap();
+        });
+    }
+
+    #[test]
+    fn stale_socket_files_are_replaced() {
+        install_logger();
+
+        let pool = runtime::Runtime::new().unwrap();
+        let name = test_name();
+
+        pool.block_on(async {
+            // A crashed server leaves its socket and lock file behind, but not its lock.
+            let path = socket_path(&name);
+            Endpoint::resolve(&name).unwrap().create_parent_dirs(0o700).unwrap();
+            drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+            fs::write(format!("{}.lock", path.display()), b"").unwrap();
+
+            let server = DomainSocketServer::new(&name, &ServerOptions::new()).unwrap();
+
+            assert!(matches!(DomainSocketServer::new(&name, &ServerOptions::new()), Err(IpcError::AddrInUse)));
+
+            let _client = DomainSocketClient::new(&name, &ClientOptions::new()).unwrap();
+            let (_connection, server) = server.wait_for_connection().await.unwrap();
+
+            drop(server);
+
+            assert!(!path.exists());
+            assert!(!Path::new(&format!("{}.lock", path.display())).exists());
+
+            // Once the server is gone, the name is free again.
+            DomainSocketServer::new(&name, &ServerOptions::new()).unwrap();
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_sockets_have_no_files() {
+        install_logger();
+
+        let pool = runtime::Runtime::new().unwrap();
+        let name = test_name().in_abstract_namespace();
+
+        pool.block_on(async {
+            let server = DomainSocketServer::new(&name, &ServerOptions::new()).unwrap();
+
+            assert!(server._files.is_none());
+            assert!(!socket_path(&IpcName::new(name.name()).unwrap()).exists());
+            assert!(matches!(DomainSocketServer::new(&name, &ServerOptions::new()), Err(IpcError::AddrInUse)));
+
+            // The same name outside the abstract namespace is a different endpoint.
+            let _file_server = DomainSocketServer::new(&IpcName::new(name.name()).unwrap(), &ServerOptions::new()).unwrap();
+
+            let _client = DomainSocketClient::new(&name, &ClientOptions::new()).unwrap();
+            server.wait_for_connection().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn read_fails_when_peer_disconnects() {
+        install_logger();
+
+        let pool = runtime::Runtime::new().unwrap();
+        let name = test_name();
+
+        pool.block_on(async {
+            let server = DomainSocketServer::new(&name, &ServerOptions::new()).unwrap();
+            let client = DomainSocketClient::new(&name, &ClientOptions::new()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            drop(client);
+
+            let mut data = [0; 16];
+            let err = connection.read(&mut data).await.unwrap_err();
+
+            assert!(matches!(err, IpcError::PeerDisconnected));
+        });
+    }
+
+    #[test]
+    fn waits_for_capacity_when_max_instances_reached() {
+        install_logger();
+
+        let pool = runtime::Runtime::new().unwrap();
+        let name = test_name();
+
+        pool.block_on(async {
+            let server = DomainSocketServer::new(&name, &ServerOptions::new().max_instances(1)).unwrap();
+            let _client_1 = DomainSocketClient::new(&name, &ClientOptions::new()).unwrap();
+            let _client_2 = DomainSocketClient::new(&name, &ClientOptions::new()).unwrap();
+
+            let (connection, server) = server.wait_for_connection().await.unwrap();
+
+            let second_connection = tokio::spawn(server.wait_for_connection());
+
+            tokio::task::yield_now().await;
+            assert!(!second_connection.is_finished());
+
+            drop(connection);
+
+            let (_connection, server) = second_connection.await.unwrap().unwrap();
+
+            assert_eq!(server.instances.count(), 1);
+        });
+    }
+
+    #[test]
+    fn clients_of_a_busy_server_fail_or_wait_without_blocking() {
+        install_logger();
+
+        let pool = runtime::Runtime::new().unwrap();
+        let name = test_name();
+
+        pool.block_on(async {
+            let server = DomainSocketServer::new(&name, &ServerOptions::new()).unwrap();
+
+            // Fill the backlog with connections the server hasn't accepted.
+            let mut pending = Vec::new();
+
+            let err = loop {
+                match DomainSocketClient::new(&name, &ClientOptions::new()) {
+                    Ok(connection) => pending.push(connection),
+                    Err(err) => break err,
+                }
+
+                assert!(pending.len() <= 2 * super::LISTEN_BACKLOG as usize, "The backlog never filled");
+            };
+
+            assert!(matches!(err, IpcError::Io(err) if err.kind() == ErrorKind::WouldBlock));
+
+            let waiting = ClientOptions::new().timeout(Duration::from_millis(50));
+
+            match DomainSocketClient::connect(&name, &waiting).await {
+                Err(IpcError::Io(err)) => assert_eq!(err.kind(), ErrorKind::TimedOut),
+                result => panic!("Unexpected result {:?}", result.map(|_| ())),
+            };
+
+            // Accepting a connection makes room for a waiting client.
+            let waiting = ClientOptions::new().timeout(Duration::from_secs(5));
+            let (connected, accepted) = futures::join!(DomainSocketClient::connect(&name, &waiting), server.wait_for_connection());
+
+            connected.unwrap();
+            accepted.unwrap();
+        });
+    }
+}
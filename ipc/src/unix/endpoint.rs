@@ -0,0 +1,397 @@
+use crate::credentials::PeerCredentials;
+use crate::discovery::EndpointInfo;
+use crate::error::{IpcError, Result};
+use crate::name::{IpcName, Scope};
+
+use socket2::SockAddr;
+
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Where a name's socket lives.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    /// A socket file. `private_dir` is a directory we chose for the current user, which must
+    /// belong to them and nobody else.
+    Path {
+        path: PathBuf,
+        private_dir: Option<PathBuf>,
+    },
+
+    /// A name in Linux's abstract socket namespace, without the leading NUL.
+    Abstract(Vec<u8>),
+}
+
+impl Endpoint {
+    pub fn resolve(name: &IpcName) -> Result<Endpoint> {
+        if name.is_abstract() {
+            return Ok(Endpoint::Abstract(abstract_name(name).into_bytes()));
+        }
+
+        let (mut path, private_dir) = directory(name.scope())?;
+        path.extend(name.segments());
+
+        Ok(Endpoint::Path { path, private_dir })
+    }
+
+    pub fn address(&self) -> Result<SockAddr> {
+        let address = match self {
+            Endpoint::Path { path, .. } => SockAddr::unix(path)?,
+            Endpoint::Abstract(name) => {
+                let mut bytes = vec![0];
+                bytes.extend_from_slice(name);
+
+                SockAddr::unix(OsString::from_vec(bytes))?
+            }
+        };
+
+        Ok(address)
+    }
+
+    /// Creates any missing directories above a socket file: the private directory with mode
+    /// 0o700, and any others with `mode`.
+    pub fn create_parent_dirs(&self, mode: u32) -> Result<()> {
+        let (path, private_dir) = match self {
+            Endpoint::Path { path, private_dir } => (path, private_dir),
+            Endpoint::Abstract(_) => return Ok(()),
+        };
+
+        if let Some(dir) = private_dir {
+            match fs::DirBuilder::new().mode(0o700).create(dir) {
+                Err(err) if err.kind() != ErrorKind::AlreadyExists => return Err(err.into()),
+                _ => {}
+            }
+        }
+
+        self.check_private_dir()?;
+
+        if let Some(parent) = path.parent() {
+            fs::DirBuilder::new().recursive(true).mode(mode).create(parent)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a server is listening on the endpoint, judged without connecting to it. A
+    /// socket file only counts if the pid in its lock file is running, so the file of a
+    /// server that crashed, or one that isn't listening yet, doesn't.
+    pub fn is_listening(&self) -> Result<bool> {
+        let path = match self {
+            Endpoint::Path { path, .. } => path,
+            #[cfg(target_os = "linux")]
+            Endpoint::Abstract(name) => return Ok(abstract_listeners()?.iter().any(|listener| listener.as_bytes() == &name[..])),
+            #[cfg(not(target_os = "linux"))]
+            Endpoint::Abstract(_) => return Ok(false),
+        };
+
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {}
+            Ok(_) => return Ok(false),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+
+        match fs::read_to_string(lock_path(path)) {
+            Ok(pid) => Ok(pid.parse().is_ok_and(is_running)),
+            // Another user's server, whose lock file we can't read.
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fails if the directory chosen for the current user exists but someone else could have
+    /// put a socket in it.
+    pub fn check_private_dir(&self) -> Result<()> {
+        let dir = match self {
+            Endpoint::Path { private_dir: Some(dir), .. } => dir,
+            _ => return Ok(()),
+        };
+
+        let metadata = match fs::metadata(dir) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        if metadata.uid() != current_uid() || metadata.mode() & 0o077 != 0 {
+            return Err(IpcError::Io(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} belongs to another user or isn't private", dir.display()),
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// The directory holding sockets with the given scope, along with the private directory we
+/// chose for the user if `$XDG_RUNTIME_DIR` isn't set.
+pub(crate) fn directory(scope: Scope) -> Result<(PathBuf, Option<PathBuf>)> {
+    if scope == Scope::Global {
+        return Ok((env::temp_dir(), None));
+    }
+
+    let (mut directory, private_dir) = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => (PathBuf::from(dir), None),
+        None => {
+            let dir = env::temp_dir().join(format!("ipc-{}", current_uid()));
+
+            (dir.clone(), Some(dir))
+        }
+    };
+
+    if scope == Scope::Session {
+        directory.push(format!("session-{}", current_session()));
+    }
+
+    Ok((directory, private_dir))
+}
+
+/// The abstract namespace is shared by every user, so scoped names are prefixed with the
+/// user, and the session, they belong to.
+fn abstract_name(name: &IpcName) -> String {
+    match name.scope() {
+        Scope::Global => name.name().to_owned(),
+        Scope::User => format!("ipc-{}/{}", current_uid(), name),
+        Scope::Session => format!("ipc-{}/session-{}/{}", current_uid(), current_session(), name),
+    }
+}
+
+/// Whether a process exists with the given pid, by sending it the null signal.
+fn is_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+
+    let result = unsafe { libc::kill(pid, 0) };
+
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn current_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+/// The login session's id from `$XDG_SESSION_ID`, or the id of our process session outside of
+/// a login manager.
+fn current_session() -> String {
+    env::var("XDG_SESSION_ID").unwrap_or_else(|_| unsafe { libc::getsid(0) }.to_string())
+}
+
+/// Lists the endpoints in every scope whose names start with `prefix`.
+pub fn list_endpoints(prefix: &str) -> Result<Vec<EndpointInfo>> {
+    let mut endpoints = Vec::new();
+    let scopes = [Scope::Global, Scope::User, Scope::Session];
+    let directories = scopes.iter().map(|&scope| directory(scope)).collect::<Result<Vec<_>>>()?;
+
+    for (&scope, (dir, _)) in scopes.iter().zip(&directories) {
+        // Scopes' directories may be nested, so each scope skips the others'.
+        let others = directories.iter().map(|(other, _)| other).filter(|&other| other != dir).collect::<Vec<_>>();
+
+        find_sockets(dir, "", scope, prefix, &others, &mut endpoints)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    find_abstract_sockets(prefix, &mut endpoints)?;
+
+    Ok(endpoints)
+}
+
+fn find_sockets(
+    dir: &Path,
+    parent_name: &str,
+    scope: Scope,
+    prefix: &str,
+    skip: &[&PathBuf],
+    endpoints: &mut Vec<EndpointInfo>,
+) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound || err.kind() == ErrorKind::PermissionDenied => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+
+        // Lock files and staging directories start with '.' or end with ".lock", which no
+        // name does.
+        let name = match entry.file_name().into_string() {
+            Ok(file_name) if parent_name.is_empty() => file_name,
+            Ok(file_name) => format!("{}/{}", parent_name, file_name),
+            Err(_) => continue,
+        };
+
+        let name = match IpcName::with_scope(&name, scope) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        let path = entry.path();
+
+        // Entries may be removed while we look through the directory, by servers shutting
+        // down or other programs cleaning up.
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        if file_type.is_dir() {
+            // Other sessions' directories aren't user-scoped names.
+            let other_session = scope == Scope::User && parent_name.is_empty() && name.name().starts_with("session-");
+            let child_prefix = format!("{}/", name);
+            let might_match = prefix.starts_with(&child_prefix) || child_prefix.starts_with(prefix);
+
+            if might_match && !other_session && !skip.contains(&&path) {
+                find_sockets(&path, name.name(), scope, prefix, skip, endpoints)?;
+            }
+        } else if file_type.is_socket() && name.name().starts_with(prefix) {
+            // Our servers create a lock file next to their socket, and other programs' sockets,
+            // which the temporary directory especially is full of, have none.
+            let pid = match fs::read_to_string(lock_path(&path)) {
+                Ok(pid) => pid.parse().ok(),
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(_) => None,
+            };
+
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            endpoints.push(EndpointInfo {
+                name,
+                owner: PeerCredentials {
+                    pid,
+                    uid: Some(metadata.uid()),
+                    gid: Some(metadata.gid()),
+                    session_id: None,
+                },
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// The names of listening sockets in the abstract namespace, which Linux lists in
+/// `/proc/net/unix`.
+#[cfg(target_os = "linux")]
+fn abstract_listeners() -> Result<Vec<String>> {
+    const SO_ACCEPTCON: u32 = 0x10000;
+
+    let mut listeners = Vec::new();
+
+    // Each line is "Num RefCount Protocol Flags Type St Inode Path", with abstract paths
+    // starting with '@'.
+    for line in fs::read_to_string("/proc/net/unix")?.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        let (flags, path) = match fields.as_slice() {
+            [_, _, _, flags, _, _, _, path] => (flags, path),
+            _ => continue,
+        };
+
+        let listening = u32::from_str_radix(flags, 16).is_ok_and(|flags| flags & SO_ACCEPTCON != 0);
+
+        match path.strip_prefix('@') {
+            Some(path) if listening => listeners.push(path.to_owned()),
+            _ => {}
+        }
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(target_os = "linux")]
+fn find_abstract_sockets(prefix: &str, endpoints: &mut Vec<EndpointInfo>) -> Result<()> {
+    let user_prefix = format!("ipc-{}/", current_uid());
+    let session_prefix = format!("{}session-{}/", user_prefix, current_session());
+
+    for path in abstract_listeners()? {
+        let (name, scope) = if let Some(name) = path.strip_prefix(&session_prefix) {
+            (name, Scope::Session)
+        } else if let Some(name) = path.strip_prefix(&user_prefix) {
+            if name.starts_with("session-") {
+                continue;
+            }
+
+            (name, Scope::User)
+        } else if path.starts_with("ipc-") {
+            // Another user's.
+            continue;
+        } else {
+            (&*path, Scope::Global)
+        };
+
+        if !name.starts_with(prefix) {
+            continue;
+        }
+
+        if let Ok(name) = IpcName::with_scope(name, scope) {
+            let uid = if scope == Scope::Global { None } else { Some(current_uid()) };
+
+            endpoints.push(EndpointInfo {
+                name: name.in_abstract_namespace(),
+                owner: PeerCredentials { uid, ..PeerCredentials::default() },
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// The lock file a server holds for as long as it owns the socket at `socket_path`.
+pub(crate) fn lock_path(socket_path: &Path) -> PathBuf {
+    let mut path = socket_path.as_os_str().to_owned();
+    path.push(".lock");
+
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_session, current_uid, directory, Endpoint};
+    use crate::name::{IpcName, Scope};
+
+    use std::env;
+
+    #[test]
+    fn names_resolve_by_scope() {
+        let path = |name: &IpcName| match Endpoint::resolve(name).unwrap() {
+            Endpoint::Path { path, .. } => path,
+            endpoint => panic!("Unexpected endpoint {:?}", endpoint),
+        };
+
+        let (user_dir, _) = directory(Scope::User).unwrap();
+
+        assert_eq!(path(&IpcName::global("vendor/app").unwrap()), env::temp_dir().join("vendor/app"));
+        assert_eq!(path(&IpcName::new("vendor/app").unwrap()), user_dir.join("vendor/app"));
+        assert_eq!(
+            path(&IpcName::session("app").unwrap()),
+            user_dir.join(format!("session-{}", current_session())).join("app")
+        );
+
+        // Another user's processes resolve the same name somewhere else.
+        assert!(user_dir != env::temp_dir());
+
+        if cfg!(target_os = "linux") {
+            let name = IpcName::new("app").unwrap().in_abstract_namespace();
+
+            assert_eq!(
+                Endpoint::resolve(&name).unwrap(),
+                Endpoint::Abstract(format!("ipc-{}/app", current_uid()).into_bytes())
+            );
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use super::domain_socket::{DomainSocketClient, DomainSocketConnection, DomainSocketServer};
+use crate::credentials::PeerCredentials;
+use crate::error::Result;
+use crate::name::IpcName;
+use crate::options::{ClientOptions, ServerOptions};
+
+pub struct IpcServerWrapper {
+    socket: DomainSocketServer,
+}
+
+impl IpcServerWrapper {
+    pub fn new(name: &IpcName, options: &ServerOptions) -> Result<IpcServerWrapper> {
+        Ok(IpcServerWrapper {
+            socket: DomainSocketServer::new(name, options)?,
+        })
+    }
+
+    fn from(server: DomainSocketServer) -> IpcServerWrapper {
+        IpcServerWrapper { socket: server }
+    }
+
+    pub async fn wait_for_connection(
+        self,
+    ) -> Result<(IpcConnectionWrapper, IpcServerWrapper)> {
+        let (socket_connection, server) = self.socket.wait_for_connection().await?;
+
+        Ok((
+            IpcConnectionWrapper::new(socket_connection),
+            IpcServerWrapper::from(server),
+        ))
+    }
+}
+
+pub struct IpcConnectionWrapper {
+    socket_connection: DomainSocketConnection,
+}
+
+impl IpcConnectionWrapper {
+    pub fn new(socket_connection: DomainSocketConnection) -> IpcConnectionWrapper {
+        IpcConnectionWrapper {
+            socket_connection,
+        }
+    }
+
+    pub async fn read(&self, data: &mut [u8]) -> Result<u32> {
+        self.socket_connection.read(data).await
+    }
+
+    pub async fn write(&self, data: &[u8]) -> Result<u32> {
+        self.socket_connection.write(data).await
+    }
+
+    pub fn peer_credentials(&self) -> &PeerCredentials {
+        self.socket_connection.peer_credentials()
+    }
+}
+
+pub struct IpcClientWrapper {}
+
+impl IpcClientWrapper {
+    pub fn new(name: &IpcName, options: &ClientOptions) -> Result<IpcConnectionWrapper> {
+        let socket_connection = DomainSocketClient::new(name, options)?;
+
+        Ok(IpcConnectionWrapper {
+            socket_connection,
+        })
+    }
+
+    /// Like `new`, but waits for a busy server without blocking the thread.
+    pub async fn connect(name: &IpcName, options: &ClientOptions) -> Result<IpcConnectionWrapper> {
+        let socket_connection = DomainSocketClient::connect(name, options).await?;
+
+        Ok(IpcConnectionWrapper {
+            socket_connection,
+        })
+    }
+}
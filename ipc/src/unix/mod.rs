@@ -0,0 +1,12 @@
+mod domain_socket;
+pub(crate) mod endpoint;
+mod ipc;
+mod watch;
+
+pub use self::ipc::{
+    IpcClientWrapper,
+    IpcConnectionWrapper,
+    IpcServerWrapper
+};
+pub use self::endpoint::list_endpoints;
+pub use self::watch::wait_for_endpoint;
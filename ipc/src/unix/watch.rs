@@ -0,0 +1,123 @@
+use super::endpoint::Endpoint;
+use crate::error::Result;
+use crate::name::IpcName;
+
+use std::time::Duration;
+
+/// How often to check for endpoints we can't watch for.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Waits until a server is listening on `name`'s endpoint. On Linux, socket files are watched
+/// for with inotify, while abstract names, and sockets on other platforms, are polled for.
+pub async fn wait_for_endpoint(name: &IpcName) -> Result<()> {
+    let endpoint = Endpoint::resolve(name)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Endpoint::Path { path, .. } = &endpoint {
+            return inotify::wait_for_socket(&endpoint, path).await;
+        }
+    }
+
+    while !endpoint.is_listening()? {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::super::endpoint::Endpoint;
+    use crate::error::Result;
+
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::Interest;
+
+    use std::ffi::CString;
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::Path;
+
+    /// Creating, linking or moving a file into the directory, which covers the socket and
+    /// any missing directory above it, writing the lock file, and the directory itself going
+    /// away.
+    const EVENTS: u32 = libc::IN_CREATE
+        | libc::IN_MOVED_TO
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MODIFY
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF
+        | libc::IN_ONLYDIR;
+
+    /// Waits for the socket at `path`, watching the deepest of its directories that exists.
+    /// Any change there, such as the next directory down being created, starts the wait
+    /// over.
+    pub async fn wait_for_socket(endpoint: &Endpoint, path: &Path) -> Result<()> {
+        loop {
+            let watcher = match path.ancestors().skip(1).find(|dir| dir.is_dir()).map(Watcher::new) {
+                Some(Ok(watcher)) => watcher,
+                // The directory was removed before we could watch it.
+                Some(Err(err)) if err.kind() == ErrorKind::NotFound => continue,
+                Some(Err(err)) => return Err(err.into()),
+                None => return Err(Error::new(ErrorKind::NotFound, "No directory to watch").into()),
+            };
+
+            // Only check once we're watching, so nothing can happen unseen in between.
+            if endpoint.is_listening()? {
+                return Ok(());
+            }
+
+            watcher.changed().await?;
+        }
+    }
+
+    /// An inotify instance watching a single directory.
+    struct Watcher {
+        fd: AsyncFd<OwnedFd>,
+    }
+
+    impl Watcher {
+        fn new(dir: &Path) -> std::io::Result<Watcher> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let dir = CString::new(dir.as_os_str().as_bytes())?;
+
+            if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), EVENTS) } < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            // The fd is owned by the OwnedFd we hand over, so it stays open as long as the
+            // AsyncFd does.
+            let fd = unsafe { AsyncFd::register_with_interest(fd, Interest::READABLE)? };
+
+            Ok(Watcher { fd })
+        }
+
+        /// Waits for the next events, and discards them.
+        async fn changed(&self) -> std::io::Result<()> {
+            let mut events = [0u8; 4096];
+
+            loop {
+                let mut guard = self.fd.readable().await?;
+
+                let result = guard.try_io(|fd| {
+                    match unsafe { libc::read(fd.as_raw_fd(), events.as_mut_ptr() as *mut _, events.len()) } {
+                        -1 => Err(Error::last_os_error()),
+                        read => Ok(read),
+                    }
+                });
+
+                if let Ok(result) = result {
+                    return result.map(|_| ());
+                }
+            }
+        }
+    }
+}
@@ -28,7 +28,7 @@ use winapi::{
 use super::handle::{Handle};
 use super::overlapped::{Overlapped, OverlappedCompletionInfo};
 
-use log::error;
+use tracing::error;
 
 use std::io::ErrorKind;
 use std::mem;
@@ -192,7 +192,7 @@ impl CompletionPort {
             bytes_transferred: bytes_transferred
         }) {
             Ok(_) => {},
-            Err(e) => error!("Failed to notify completion {:?}", e)
+            Err(e) => error!(err = ?e, "Failed to notify completion")
         };
     }
 }
\ No newline at end of file
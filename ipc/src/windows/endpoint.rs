@@ -0,0 +1,197 @@
+use winapi::{
+    shared::{minwindef::FALSE, sddl::ConvertSidToStringSidW, winerror::ERROR_SEM_TIMEOUT},
+    um::{
+        errhandlingapi::GetLastError,
+        fileapi::{FindClose, FindFirstFileW, FindNextFileW},
+        handleapi::INVALID_HANDLE_VALUE,
+        minwinbase::WIN32_FIND_DATAW,
+        namedpipeapi::WaitNamedPipeW,
+        processthreadsapi::{GetCurrentProcess, GetCurrentProcessId, OpenProcessToken, ProcessIdToSessionId},
+        securitybaseapi::GetTokenInformation,
+        winbase::LocalFree,
+        winnt::{TokenUser, TOKEN_QUERY, TOKEN_USER},
+    },
+};
+
+use super::handle::Handle;
+use crate::credentials::PeerCredentials;
+use crate::discovery::EndpointInfo;
+use crate::error::Result;
+use crate::name::{IpcName, Scope};
+
+use std::ffi::{OsStr, OsString};
+use std::mem;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::ptr;
+use std::time::Duration;
+
+/// How often to check for a pipe being waited for.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The pipe name for `name`, relative to `\\.\pipe\`. Scoped names are prefixed with the
+/// current user's SID, and the session they belong to.
+pub fn pipe_name(name: &IpcName) -> Result<String> {
+    let path = name.segments().collect::<Vec<_>>().join(r"\");
+
+    let pipe_name = match name.scope() {
+        Scope::Global => path,
+        Scope::User => format!(r"{}\{}", current_user_sid()?, path),
+        Scope::Session => format!(r"{}\session-{}\{}", current_user_sid()?, current_session()?, path),
+    };
+
+    Ok(pipe_name)
+}
+
+/// The current user's SID, e.g. `S-1-5-21-...`.
+pub(crate) fn current_user_sid() -> Result<String> {
+    let mut token = ptr::null_mut();
+
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == FALSE {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let token = Handle::new(token);
+
+    // TOKEN_USER is followed by the SID it points to, so ask how much room they need. The
+    // buffer is made of u64s to keep it suitably aligned.
+    let mut size = 0;
+    unsafe { GetTokenInformation(token.value, TokenUser, ptr::null_mut(), 0, &mut size) };
+
+    let mut buffer = vec![0u64; (size as usize).div_ceil(mem::size_of::<u64>())];
+
+    if unsafe { GetTokenInformation(token.value, TokenUser, buffer.as_mut_ptr() as *mut _, size, &mut size) } == FALSE {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let user = unsafe { &*(buffer.as_ptr() as *const TOKEN_USER) };
+    let mut sid = ptr::null_mut();
+
+    if unsafe { ConvertSidToStringSidW(user.User.Sid, &mut sid) } == FALSE {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let len = (0..).take_while(|&i| unsafe { *sid.offset(i) } != 0).count();
+    let string = OsString::from_wide(unsafe { std::slice::from_raw_parts(sid, len) });
+    let _ = unsafe { LocalFree(sid as *mut _) };
+
+    Ok(string.to_string_lossy().into_owned())
+}
+
+/// The id of the Terminal Services session our process runs in.
+pub(crate) fn current_session() -> Result<u32> {
+    let mut session = 0;
+
+    if unsafe { ProcessIdToSessionId(GetCurrentProcessId(), &mut session) } == FALSE {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(session)
+}
+
+/// Lists the pipes in every scope whose names start with `prefix`.
+pub fn list_endpoints(prefix: &str) -> Result<Vec<EndpointInfo>> {
+    let user_prefix = format!(r"{}\", current_user_sid()?);
+    let session = current_session()?;
+    let session_prefix = format!(r"{}session-{}\", user_prefix, session);
+
+    let mut endpoints = Vec::new();
+
+    for pipe in list_pipes()? {
+        let (path, scope) = if let Some(path) = pipe.strip_prefix(&session_prefix) {
+            (path, Scope::Session)
+        } else if let Some(path) = pipe.strip_prefix(&user_prefix) {
+            if path.starts_with("session-") {
+                continue;
+            }
+
+            (path, Scope::User)
+        } else if pipe.starts_with("S-1-") {
+            // Another user's.
+            continue;
+        } else {
+            (&*pipe, Scope::Global)
+        };
+
+        let name = match IpcName::with_scope(&path.replace('\\', "/"), scope) {
+            Ok(name) if name.name().starts_with(prefix) => name,
+            _ => continue,
+        };
+
+        let session_id = if scope == Scope::Session { Some(session) } else { None };
+
+        endpoints.push(EndpointInfo {
+            name,
+            owner: PeerCredentials { session_id, ..PeerCredentials::default() },
+        });
+    }
+
+    // A pipe with several instances is listed once per instance.
+    endpoints.dedup_by(|a, b| a.name == b.name);
+
+    Ok(endpoints)
+}
+
+/// The names of every pipe on the machine, relative to `\\.\pipe\`.
+fn list_pipes() -> Result<Vec<String>> {
+    let pattern = OsStr::new(r"\\.\pipe\*").encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let mut data: WIN32_FIND_DATAW = unsafe { mem::zeroed() };
+
+    let find = unsafe { FindFirstFileW(pattern.as_ptr(), &mut data) };
+
+    if find == INVALID_HANDLE_VALUE {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut pipes = Vec::new();
+
+    loop {
+        let len = data.cFileName.iter().position(|&c| c == 0).unwrap_or(data.cFileName.len());
+        pipes.push(OsString::from_wide(&data.cFileName[..len]).to_string_lossy().into_owned());
+
+        if unsafe { FindNextFileW(find, &mut data) } == FALSE {
+            break;
+        }
+    }
+
+    let _ = unsafe { FindClose(find) };
+
+    pipes.sort();
+
+    Ok(pipes)
+}
+
+/// Waits until a server has created `name`'s pipe. Windows has no notifications for the pipe
+/// namespace, so this polls.
+pub async fn wait_for_endpoint(name: &IpcName) -> Result<()> {
+    let path = format!(r"\\.\pipe\{}", pipe_name(name)?);
+    let path = OsStr::new(&path).encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+
+    loop {
+        // Fails straight away if there's no such pipe, and with ERROR_SEM_TIMEOUT once the
+        // millisecond is up if there is but all its instances are busy.
+        if unsafe { WaitNamedPipeW(path.as_ptr(), 1) } != FALSE || unsafe { GetLastError() } == ERROR_SEM_TIMEOUT {
+            return Ok(());
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_session, current_user_sid, pipe_name};
+    use crate::name::IpcName;
+
+    #[test]
+    fn scoped_names_are_prefixed_with_the_user() {
+        let sid = current_user_sid().unwrap();
+        assert!(sid.starts_with("S-1-"));
+
+        assert_eq!(pipe_name(&IpcName::global("vendor/app").unwrap()).unwrap(), r"vendor\app");
+        assert_eq!(pipe_name(&IpcName::new("vendor/app").unwrap()).unwrap(), format!(r"{}\vendor\app", sid));
+        assert_eq!(
+            pipe_name(&IpcName::session("app").unwrap()).unwrap(),
+            format!(r"{}\session-{}\app", sid, current_session().unwrap())
+        );
+    }
+}
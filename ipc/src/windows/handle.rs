@@ -9,7 +9,7 @@ static HANDLE_ID: AtomicUsize = AtomicUsize::new(0);
 #[cfg(debug_assertions)]
 static NUM_HANDLES: AtomicUsize = AtomicUsize::new(0);
 
-use log::trace;
+use tracing::trace;
 
 #[derive(Debug)]
 pub struct Handle {
@@ -24,7 +24,7 @@ impl Handle {
     pub fn new(handle: HANDLE) -> Handle {
         let id = HANDLE_ID.fetch_add(1, Ordering::SeqCst);
         NUM_HANDLES.fetch_add(1, Ordering::SeqCst);
-        trace!("Handle: created {}", id);
+        trace!(handle = id, "Created handle");
 
         Handle { value: handle, id: id }
     }
@@ -47,7 +47,7 @@ impl Drop for Handle {
     fn drop(&mut self) {
         NUM_HANDLES.fetch_sub(1, Ordering::SeqCst);
 
-        trace!("Handle: closed {}", self.id);
+        trace!(handle = self.id, "Closed handle");
 
         let _ = unsafe { CloseHandle(self.value) };
     }
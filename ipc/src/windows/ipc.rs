@@ -3,15 +3,19 @@
 // use futures::io::{AsyncRead, AsyncWrite};
 
 use super::named_pipe::{NamedPipeClient, NamedPipeConnection, NamedPipeServer};
+use crate::credentials::PeerCredentials;
+use crate::error::Result;
+use crate::name::IpcName;
+use crate::options::{ClientOptions, ServerOptions};
 
 pub struct IpcServerWrapper {
     pipe: NamedPipeServer,
 }
 
 impl IpcServerWrapper {
-    pub fn new(name: &str) -> std::io::Result<IpcServerWrapper> {
+    pub fn new(name: &IpcName, options: &ServerOptions) -> Result<IpcServerWrapper> {
         Ok(IpcServerWrapper {
-            pipe: NamedPipeServer::new(name)?,
+            pipe: NamedPipeServer::new(name, options)?,
         })
     }
 
@@ -21,7 +25,7 @@ impl IpcServerWrapper {
 
     pub async fn wait_for_connection(
         self,
-    ) -> std::io::Result<(IpcConnectionWrapper, IpcServerWrapper)> {
+    ) -> Result<(IpcConnectionWrapper, IpcServerWrapper)> {
         let (pipe_connection, server) = self.pipe.wait_for_connection().await?;
 
         Ok((
@@ -42,23 +46,33 @@ impl IpcConnectionWrapper {
         }
     }
 
-    pub async fn read<'a>(&'a self, data: &'a mut [u8]) -> std::io::Result<u32> {
+    pub async fn read<'a>(&'a self, data: &'a mut [u8]) -> Result<u32> {
         self.pipe_connection.read(data).await
     }
 
-    pub async fn write<'a>(&'a self, data: &'a [u8]) -> std::io::Result<u32> {
+    pub async fn write<'a>(&'a self, data: &'a [u8]) -> Result<u32> {
         self.pipe_connection.write(data).await
     }
+
+    pub fn peer_credentials(&self) -> &PeerCredentials {
+        self.pipe_connection.peer_credentials()
+    }
 }
 
 pub struct IpcClientWrapper {}
 
 impl IpcClientWrapper {
-    pub fn new(pipe_name: &str) -> std::io::Result<IpcConnectionWrapper> {
-        let pipe_connection = NamedPipeClient::new(pipe_name)?;
+    pub fn new(name: &IpcName, options: &ClientOptions) -> Result<IpcConnectionWrapper> {
+        let pipe_connection = NamedPipeClient::new(name, options)?;
 
         Ok(IpcConnectionWrapper {
             pipe_connection: pipe_connection,
         })
     }
+
+    /// The same as `new`. Waiting for a free pipe instance blocks the thread, as Windows has
+    /// no way to wait for one asynchronously.
+    pub async fn connect(name: &IpcName, options: &ClientOptions) -> Result<IpcConnectionWrapper> {
+        IpcClientWrapper::new(name, options)
+    }
 }
@@ -1,4 +1,5 @@
 mod completion_port;
+mod endpoint;
 mod handle;
 mod ipc;
 mod named_pipe;
@@ -9,3 +10,4 @@ pub use self::ipc::{
     IpcConnectionWrapper,
     IpcServerWrapper
 };
+pub use self::endpoint::{list_endpoints, wait_for_endpoint};
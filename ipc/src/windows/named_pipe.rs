@@ -1,36 +1,49 @@
 use winapi::{
     shared::{
-        minwindef::TRUE,
-        winerror::{ERROR_IO_PENDING, ERROR_NO_DATA, ERROR_PIPE_CONNECTED},
+        minwindef::{BOOL, FALSE, PULONG, TRUE},
+        sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1},
+        winerror::{ERROR_ACCESS_DENIED, ERROR_IO_PENDING, ERROR_NO_DATA, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED},
     },
     um::{
         fileapi::{CreateFileW, ReadFile, WriteFile, OPEN_EXISTING},
         handleapi::INVALID_HANDLE_VALUE,
-        namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW},
+        minwinbase::SECURITY_ATTRIBUTES,
+        namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW, WaitNamedPipeW},
         winbase::{
-            FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, PIPE_ACCESS_DUPLEX,
+            GetNamedPipeClientProcessId, GetNamedPipeClientSessionId, GetNamedPipeServerProcessId,
+            GetNamedPipeServerSessionId, LocalFree, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, PIPE_ACCESS_DUPLEX,
             PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
             PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
         },
-        winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, GENERIC_WRITE},
+        winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, GENERIC_WRITE, HANDLE, PSECURITY_DESCRIPTOR},
     },
 };
 
 use super::completion_port::{CompletionPort};
+use super::endpoint::pipe_name;
 use super::handle::Handle;
 use super::overlapped::Overlapped;
+use crate::credentials::PeerCredentials;
+use crate::error::{IpcError, Result};
+use crate::instances::{InstanceCounter, InstanceGuard};
+use crate::name::IpcName;
+use crate::options::{ClientOptions, ServerOptions};
 
-use log::trace;
+use tracing::trace;
 
+use std::convert::TryFrom;
 use std::ffi::{c_void, OsStr, OsString};
 use std::os::windows::ffi::OsStrExt;
 use std::mem;
 use std::ptr;
 use std::sync::{Arc};
+use std::time::{Instant};
 
 pub struct NamedPipeServer {
     handle: Handle,
     name: OsString,
+    instances: InstanceCounter,
+    options: ServerOptions,
 }
 
 const PIPE_PREFIX: &str = r"\\.\pipe\";
@@ -41,24 +54,89 @@ fn make_pipe_name(osstr: &OsStr) -> Vec<u16> {
     name.encode_wide().collect::<Vec<u16>>()
 }
 
+/// A security descriptor parsed from an SDDL string, freed on drop.
+struct SecurityDescriptor {
+    value: PSECURITY_DESCRIPTOR,
+}
+
+impl SecurityDescriptor {
+    fn from_sddl(sddl: &str) -> Result<SecurityDescriptor> {
+        let sddl_bytes = make_pipe_name(OsStr::new(sddl));
+        let mut value: PSECURITY_DESCRIPTOR = ptr::null_mut();
+
+        let result = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl_bytes.as_ptr(),
+                SDDL_REVISION_1 as u32,
+                &mut value,
+                ptr::null_mut(),
+            )
+        };
+
+        if result == FALSE {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(SecurityDescriptor { value })
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        let _ = unsafe { LocalFree(self.value) };
+    }
+}
+
 impl NamedPipeServer {
-    /// Creates a new pipe server on \\.\pipe\<name>.
-    pub fn new(name: &str) -> std::io::Result<NamedPipeServer> {
-        let pipe = NamedPipeServer::create(&OsString::from(PIPE_PREFIX.to_owned() + name), true)?;
+    /// Creates a new pipe server on \\.\pipe\<name>, with the name scoped as described by
+    /// `IpcName`.
+    pub fn new(name: &IpcName, options: &ServerOptions) -> Result<NamedPipeServer> {
+        let pipe_name = OsString::from(PIPE_PREFIX.to_owned() + &pipe_name(name)?);
+        let handle = NamedPipeServer::create(&pipe_name, options.first_instance, options)?;
 
-        Ok(pipe)
+        Ok(NamedPipeServer {
+            handle,
+            name: pipe_name,
+            instances: InstanceCounter::new(options.max_instances),
+            options: options.clone(),
+        })
     }
 
-    fn create(name: &OsStr, first: bool) -> std::io::Result<NamedPipeServer> {
+    fn create(name: &OsStr, first: bool, options: &ServerOptions) -> Result<Handle> {
         let first_instance = if first {
             FILE_FLAG_FIRST_PIPE_INSTANCE
         } else {
             0
         };
-        let pipe_name = name.to_owned();
-        let pipe_name_bytes = make_pipe_name(&pipe_name);
+        let pipe_name_bytes = make_pipe_name(name);
+
+        // The OS counts the instance waiting for a client in addition to the connected ones
+        // we limit to max_instances.
+        let max_instances = match options.max_instances {
+            Some(max) => (max + 1).min(PIPE_UNLIMITED_INSTANCES),
+            None => PIPE_UNLIMITED_INSTANCES,
+        };
+
+        let default_timeout = options.default_timeout
+            .map(|timeout| u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX))
+            .unwrap_or(0);
+
+        let security_descriptor = match &options.security_descriptor {
+            Some(sddl) => Some(SecurityDescriptor::from_sddl(sddl)?),
+            None => None,
+        };
+
+        let mut security_attributes = security_descriptor.as_ref().map(|descriptor| SECURITY_ATTRIBUTES {
+            nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor.value,
+            bInheritHandle: FALSE,
+        });
 
-        trace!("Creating named pipe as {:?}", pipe_name);
+        let security_attributes_ptr = security_attributes
+            .as_mut()
+            .map_or(ptr::null_mut(), |attributes| attributes as *mut SECURITY_ATTRIBUTES);
+
+        trace!(?name, "Creating named pipe");
 
         let handle = unsafe {
             // SECURITY: Reject remote clients, as this presents potential security ramifications for consumers
@@ -67,32 +145,37 @@ impl NamedPipeServer {
                 pipe_name_bytes.as_ptr(),
                 PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED | first_instance,
                 PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
-                PIPE_UNLIMITED_INSTANCES,
-                0,
-                0,
-                0,
-                ptr::null_mut(),
+                max_instances,
+                options.out_buffer_size,
+                options.in_buffer_size,
+                default_timeout,
+                security_attributes_ptr,
             )
         };
 
         if handle == INVALID_HANDLE_VALUE {
-            return Err(std::io::Error::last_os_error());
+            let err = std::io::Error::last_os_error();
+
+            // FILE_FLAG_FIRST_PIPE_INSTANCE fails with access denied when another server
+            // already has an instance of this pipe.
+            if first && err.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) {
+                return Err(IpcError::AddrInUse);
+            }
+
+            return Err(err.into());
         }
 
         let handle = Handle::new(handle);
 
         CompletionPort::get()?.add_file_handle(&handle)?;
 
-        Ok(NamedPipeServer {
-            handle: handle,
-            name: pipe_name,
-        })
+        Ok(handle)
     }
 
     /// Blocks the current task until a client connects.
     pub async fn wait_for_connection(
         self,
-    ) -> std::io::Result<(NamedPipeConnection, NamedPipeServer)> {
+    ) -> Result<(NamedPipeConnection, NamedPipeServer)> {
         let (connection, server) = self.wait_for_connection_internal().await?;
 
         Ok((connection, server))
@@ -100,10 +183,17 @@ impl NamedPipeServer {
 
     async fn wait_for_connection_internal(
         self,
-    ) -> std::io::Result<(NamedPipeConnection, NamedPipeServer)> {
+    ) -> Result<(NamedPipeConnection, NamedPipeServer)> {
+        self.instances.wait_for_capacity().await;
+
         let (overlapped, overlapped_awaiter) = Overlapped::new()?;
 
-        let new_pipe = NamedPipeServer::create(&self.name, false)?;
+        let new_pipe = NamedPipeServer {
+            handle: NamedPipeServer::create(&self.name, false, &self.options)?,
+            name: self.name,
+            instances: self.instances,
+            options: self.options,
+        };
 
         let overlapped = Arc::new(overlapped);
 
@@ -119,10 +209,12 @@ impl NamedPipeServer {
             match err.raw_os_error().unwrap() as u32 {
                 ERROR_IO_PENDING => { }
                 ERROR_PIPE_CONNECTED => {
-                    return Ok((NamedPipeConnection::new(self.handle), new_pipe));
+                    let instance = new_pipe.instances.acquire();
+
+                    return Ok((NamedPipeConnection::accepted(self.handle, instance)?, new_pipe));
                 }
                 _ => {
-                    return Err(err);
+                    return Err(err.into());
                 }
             }
         };
@@ -131,7 +223,8 @@ impl NamedPipeServer {
 
         trace!("Got a connection");
 
-        let connection = NamedPipeConnection::new(self.handle);
+        let instance = new_pipe.instances.acquire();
+        let connection = NamedPipeConnection::accepted(self.handle, instance)?;
 
         Ok((connection, new_pipe))
     }
@@ -139,16 +232,57 @@ impl NamedPipeServer {
 
 pub struct NamedPipeConnection {
     handle: Handle,
+    peer_credentials: PeerCredentials,
+    _instance: Option<InstanceGuard>,
 }
 
 impl NamedPipeConnection {
-    /// Creates a new named pipe connection.
-    pub fn new(handle: Handle) -> NamedPipeConnection {
-        NamedPipeConnection { handle: handle }
+    /// Creates a new named pipe connection. Server-side connections hold an instance of
+    /// their server until dropped.
+    pub fn new(handle: Handle, peer_credentials: PeerCredentials, instance: Option<InstanceGuard>) -> NamedPipeConnection {
+        trace!(?peer_credentials, "Connected to peer");
+
+        NamedPipeConnection { handle: handle, peer_credentials, _instance: instance }
+    }
+
+    /// Creates the server side of a connection, identifying the client that connected.
+    fn accepted(handle: Handle, instance: InstanceGuard) -> Result<NamedPipeConnection> {
+        let peer_credentials = NamedPipeConnection::query_peer(
+            &handle,
+            GetNamedPipeClientProcessId,
+            GetNamedPipeClientSessionId,
+        )?;
+
+        Ok(NamedPipeConnection::new(handle, peer_credentials, Some(instance)))
+    }
+
+    /// Asks Windows for the peer's process and session ids, using either the client or the
+    /// server variants of the query functions depending on which end we are.
+    fn query_peer(
+        handle: &Handle,
+        process_id: unsafe extern "system" fn(HANDLE, PULONG) -> BOOL,
+        session_id: unsafe extern "system" fn(HANDLE, PULONG) -> BOOL,
+    ) -> Result<PeerCredentials> {
+        let mut pid = 0;
+        let mut session = 0;
+
+        if unsafe { process_id(handle.value, &mut pid) } != TRUE || unsafe { session_id(handle.value, &mut session) } != TRUE {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(PeerCredentials {
+            pid: Some(pid),
+            session_id: Some(session),
+            ..PeerCredentials::default()
+        })
+    }
+
+    pub fn peer_credentials(&self) -> &PeerCredentials {
+        &self.peer_credentials
     }
 
     /// Reads data on named pipe connection, blocking the current task until data exists.
-    pub async fn read<'a>(&'a self, data: &'a mut [u8]) -> std::io::Result<u32> {
+    pub async fn read<'a>(&'a self, data: &'a mut [u8]) -> Result<u32> {
         let mut bytes_read: u32 = 0;
 
         while bytes_read == 0 {
@@ -158,7 +292,7 @@ impl NamedPipeConnection {
         Ok(bytes_read)
     }
 
-    async fn read_internal<'a>(&'a self, data: &'a mut [u8]) -> std::io::Result<u32> {
+    async fn read_internal<'a>(&'a self, data: &'a mut [u8]) -> Result<u32> {
         let (overlapped, overlapped_future) = Overlapped::new()?;
         let mut bytes_read: u32 = 0;
 
@@ -181,7 +315,7 @@ impl NamedPipeConnection {
                 ERROR_IO_PENDING => {}, // Expected, as we're not blocking on I/O
                 ERROR_NO_DATA => { return Ok(0); }, // If we have no data
                 _ => {
-                    return Err(err);
+                    return Err(err.into());
                 }
             }
         } else {
@@ -195,7 +329,7 @@ impl NamedPipeConnection {
 
     /// Writes the specified data to the named pipe connection. The resulting task blocks
     /// until this completes.
-    pub async fn write<'a>(&'a self, data: &'a [u8]) -> std::io::Result<u32> {
+    pub async fn write<'a>(&'a self, data: &'a [u8]) -> Result<u32> {
         let (overlapped, overlapped_awaiter) = Overlapped::new()?;
         let mut bytes_written: u32 = 0;
 
@@ -217,8 +351,8 @@ impl NamedPipeConnection {
             match err.raw_os_error().unwrap() as u32 {
                 ERROR_IO_PENDING => { }, // Expected, as we're not blocking on I/O
                 _ => {
-                    trace!("Failed to write data: {:?}", err);
-                    return Err(err);
+                    trace!(?err, "Failed to write data");
+                    return Err(err.into());
                 }
             }
         } else {
@@ -235,34 +369,65 @@ pub struct NamedPipeClient {
 }
 
 impl NamedPipeClient {
-    /// Creates a named pipe connection to \\.\pipe\<pipe_name>
-    pub fn new(pipe_name: &str) -> std::io::Result<NamedPipeConnection> {
-        let pipe_name_bytes = make_pipe_name(&OsString::from(PIPE_PREFIX.to_owned() + pipe_name));
+    /// Creates a named pipe connection to \\.\pipe\<name>. If the server is busy and
+    /// the options specify a timeout, blocks the current thread until an instance frees up
+    /// or the timeout elapses.
+    pub fn new(name: &IpcName, options: &ClientOptions) -> Result<NamedPipeConnection> {
+        let pipe_name = pipe_name(name)?;
+        let pipe_name_bytes = make_pipe_name(&OsString::from(PIPE_PREFIX.to_owned() + &pipe_name));
+        let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+
+        trace!(%pipe_name, "Connecting to named pipe");
+
+        let handle = loop {
+            let handle = unsafe {
+                CreateFileW(
+                    pipe_name_bytes.as_ptr(),
+                    GENERIC_READ | GENERIC_WRITE,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE,
+                    ptr::null_mut(),
+                    OPEN_EXISTING,
+                    FILE_FLAG_OVERLAPPED,
+                    ptr::null_mut(),
+                )
+            };
+
+            if handle != INVALID_HANDLE_VALUE {
+                break handle;
+            }
 
-        trace!("Connecting to named pipe {}", pipe_name);
+            let err = std::io::Error::last_os_error();
 
-        let handle = unsafe {
-            CreateFileW(
-                pipe_name_bytes.as_ptr(),
-                GENERIC_READ | GENERIC_WRITE,
-                FILE_SHARE_READ | FILE_SHARE_WRITE,
-                ptr::null_mut(),
-                OPEN_EXISTING,
-                FILE_FLAG_OVERLAPPED,
-                ptr::null_mut(),
-            )
-        };
+            trace!(?err, "Failed to connect to named pipe");
 
-        if handle == INVALID_HANDLE_VALUE {
-            trace!("Failed to connect to named pipe: {:?}", std::io::Error::last_os_error());
-            return Err(std::io::Error::last_os_error());
-        }
+            let remaining = match deadline {
+                Some(deadline) if err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {
+                    deadline.saturating_duration_since(Instant::now())
+                }
+                _ => {
+                    return Err(err.into());
+                }
+            };
+
+            // A timeout of 0 means NMPWAIT_USE_DEFAULT_WAIT, so round up to at least 1ms.
+            let remaining_ms = u32::try_from(remaining.as_millis()).unwrap_or(u32::MAX - 1).max(1);
+
+            if remaining.as_millis() == 0 || unsafe { WaitNamedPipeW(pipe_name_bytes.as_ptr(), remaining_ms) } == FALSE {
+                return Err(err.into());
+            }
+        };
 
         let handle = Handle::new(handle);
 
         CompletionPort::get()?.add_file_handle(&handle)?;
 
-        Ok(NamedPipeConnection::new(handle))
+        let peer_credentials = NamedPipeConnection::query_peer(
+            &handle,
+            GetNamedPipeServerProcessId,
+            GetNamedPipeServerSessionId,
+        )?;
+
+        Ok(NamedPipeConnection::new(handle, peer_credentials, None))
     }
 }
 
@@ -270,10 +435,13 @@ impl NamedPipeClient {
 mod tests {
     //use super::{Handle}; // Uncomment when asserting handles get freed
     use super::{NamedPipeClient, NamedPipeServer};
+    use crate::error::Result;
+    use crate::name::IpcName;
+    use crate::options::{ClientOptions, ServerOptions};
     use crate::test_utils::{install_logger};
 
     use tokio::runtime;
-    use log::{info};
+    use tracing::info;
 
     use std::sync::mpsc::{channel, Receiver, Sender};
     use std::thread;
@@ -303,8 +471,8 @@ mod tests {
             async fn run_server(
                 start_tx: Sender<()>,
                 connect_tx: Sender<()>,
-            ) -> std::io::Result<()> {
-                let server = NamedPipeServer::new("horse")?;
+            ) -> Result<()> {
+                let server = NamedPipeServer::new(&IpcName::new("horse").unwrap(), &ServerOptions::new())?;
                 start_tx.send(()).unwrap();
 
                 let (_conection, _server) = server.wait_for_connection().await?;
@@ -331,8 +499,8 @@ mod tests {
         let client_thread = thread::spawn(move || {
             let pool = runtime::Runtime::new().unwrap();
 
-            async fn run_client(connect_rx: Receiver<()>) -> std::io::Result<()> {
-                let _client = NamedPipeClient::new("horse")?;
+            async fn run_client(connect_rx: Receiver<()>) -> Result<()> {
+                let _client = NamedPipeClient::new(&IpcName::new("horse").unwrap(), &ClientOptions::new())?;
 
                 connect_rx.recv().unwrap();
 
@@ -382,8 +550,8 @@ mod tests {
             async fn run_server(
                 start_tx: Sender<()>,
                 pong_rx: Receiver<()>,
-            ) -> std::io::Result<()> {
-                let server = NamedPipeServer::new("cow")?;
+            ) -> Result<()> {
+                let server = NamedPipeServer::new(&IpcName::new("cow").unwrap(), &ServerOptions::new())?;
                 start_tx.send(()).unwrap();
 
                 let (connection, _server) = server.wait_for_connection().await?;
@@ -430,8 +598,8 @@ mod tests {
         {
             let pool = runtime::Runtime::new().unwrap();
 
-            async fn run_client(pong_tx: Sender<()>) -> std::io::Result<()> {
-                let client = NamedPipeClient::new("cow")?;
+            async fn run_client(pong_tx: Sender<()>) -> Result<()> {
+                let client = NamedPipeClient::new(&IpcName::new("cow").unwrap(), &ClientOptions::new())?;
 
                 let mut data: Vec<u8> = vec![];
 
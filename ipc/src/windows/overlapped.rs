@@ -9,6 +9,8 @@ use winapi::{
     },
 };
 
+use crate::error::{IpcError, Result};
+
 use futures::channel::oneshot::{self, Sender, Receiver};
 
 use std::future::Future;
@@ -54,9 +56,9 @@ impl Overlapped {
         Ok((overlapped_wrapper, future))
     }
 
-    pub fn resolve(self, info: OverlappedCompletionInfo) -> io::Result<()> {
+    pub fn resolve(self, info: OverlappedCompletionInfo) -> Result<()> {
         self.tx_info.send(info)
-            .map_err(|_| { io::Error::new(io::ErrorKind::Interrupted, "Oneshot cancelled") })?;
+            .map_err(|_| IpcError::Cancelled)?;
 
         Ok(())
     }
@@ -67,7 +69,7 @@ pub struct OverlappedFuture {
 }
 
 impl Future for OverlappedFuture {
-    type Output = io::Result<u32>;
+    type Output = Result<u32>;
 
     fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
         futures::future::Future::poll(Pin::new(&mut self.rx_info), ctx)
@@ -75,14 +77,14 @@ impl Future for OverlappedFuture {
                 match info_result {
                     Ok(info) => {
                         if info.error != ERROR_SUCCESS as i32 {
-                            Err(std::io::Error::from_raw_os_error(info.error))
+                            Err(std::io::Error::from_raw_os_error(info.error).into())
                         } else {
                             Ok(info.bytes_transferred)
                         }
                         
                     },
                     Err(_) => {
-                        Err(std::io::Error::new(io::ErrorKind::Interrupted, "Oneshot cancelled"))
+                        Err(IpcError::Cancelled)
                     }
                 }
             })
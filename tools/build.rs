@@ -2,28 +2,37 @@ use zip::read::ZipFile;
 use zip::ZipArchive;
 
 use std::env;
-use std::fs::{create_dir, File};
+use std::fs::{create_dir_all, File};
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 const DIRECTORY: u32 = 2 << 13;
 const FILE: u32 = 2 << 14;
 
 fn main() {
-    let protoc_zip_path = if cfg!(windows) {
-        env::current_dir().unwrap().join("protoc-3.6.1-win32.zip")
-    } else {
-        panic!("TODO: support other OSs")
-    };
+    println!("cargo:rerun-if-changed=build.rs");
 
-    let out_dir = env::current_dir().unwrap().join("out");
+    // We only bundle a Windows build of protoc. On other OSs, users provide their own through
+    // $PROTOC or their PATH.
+    if !cfg!(windows) {
+        return;
+    }
 
-    create_dir(&out_dir);
+    let protoc_zip_path = env::current_dir().unwrap().join("protoc-3.6.1-win32.zip");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
+    println!("cargo:rerun-if-changed={}", protoc_zip_path.to_string_lossy());
     println!("Reading {:?}", protoc_zip_path);
 
-    unzip(&protoc_zip_path, &out_dir.join("protoc"));
+    let protoc_dir = out_dir.join("protoc");
+
+    unzip(&protoc_zip_path, &protoc_dir);
+
+    println!(
+        "cargo:rustc-env=TOOLS_BUNDLED_PROTOC={}",
+        protoc_dir.join("bin").join("protoc.exe").to_string_lossy()
+    );
 }
 
 fn unzip(zip_path: &Path, destination: &Path) {
@@ -31,34 +40,29 @@ fn unzip(zip_path: &Path, destination: &Path) {
     let reader = BufReader::new(zip_file);
     let mut archive = ZipArchive::new(reader).expect("Failed to read zip file.");
 
-    create_dir(&destination);
-
-    println!("{:?} {:?}", FILE, DIRECTORY);
-
-    println!("cargo:rerun-if-changed={}", destination.to_string_lossy());
+    create_dir_all(destination).expect("Failed to create protoc directory.");
 
     for i in 0..archive.len() {
         let mut file = archive.by_index(i).unwrap();
 
-        println!("{}, {:?}", file.name(), is_dir(&file));
-
         let file_destination = destination.join(file.name());
 
         if is_dir(&file) {
             println!("Creating directory {:?}", &file_destination);
-            create_dir(&file_destination);
+            create_dir_all(&file_destination)
+                .unwrap_or_else(|_| panic!("Failed to create {:?}", file_destination));
         } else if is_file(&file) {
             println!("Extracting {:?}", &file_destination);
             let out_file = File::create(&file_destination)
-                .expect(&format!("Failed to create {:?}", file_destination));
+                .unwrap_or_else(|_| panic!("Failed to create {:?}", file_destination));
             let mut writer = BufWriter::new(out_file);
             let mut data: Vec<u8> = vec![];
 
-            file.read_to_end(&mut data);
-
-            // TODO: set real permissions on Unix.
+            file.read_to_end(&mut data)
+                .unwrap_or_else(|_| panic!("Failed to read {:?}", file.name()));
 
-            writer.write(&data);
+            writer.write_all(&data)
+                .unwrap_or_else(|_| panic!("Failed to write {:?}", file_destination));
         }
     }
 }
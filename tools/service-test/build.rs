@@ -0,0 +1,12 @@
+use std::env;
+
+fn main() {
+    println!("cargo:rerun-if-changed=proto/storage.proto");
+
+    // Fall back on a vendored protoc, so the test runs without one installed.
+    if env::var_os("PROTOC").is_none() {
+        env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    }
+
+    tools::compile_protos(&["proto/storage.proto"], &["proto"]).unwrap();
+}
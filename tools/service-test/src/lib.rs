@@ -0,0 +1,73 @@
+//! Compiles a `.proto` service with `tools::compile_protos`, and calls it through the
+//! generated client and server.
+
+pub mod storage {
+    include!(concat!(env!("OUT_DIR"), "/storage.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::storage::{GetRequest, GetResponse, PutRequest, PutResponse, Storage, StorageClient, StorageServer};
+
+    use ipc::{IpcError, MessageIpcClient, MessageIpcServer};
+    use tokio::runtime;
+
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemoryStorage {
+        values: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl Storage for MemoryStorage {
+        async fn get(&self, request: GetRequest) -> ipc::Result<GetResponse> {
+            Ok(GetResponse { value: self.values.lock().unwrap().get(&request.key).cloned() })
+        }
+
+        async fn put(&self, request: PutRequest) -> ipc::Result<PutResponse> {
+            if request.key.is_empty() {
+                return Err(IpcError::Remote("Keys can't be empty".to_owned()));
+            }
+
+            self.values.lock().unwrap().insert(request.key, request.value);
+
+            Ok(PutResponse {})
+        }
+    }
+
+    #[test]
+    fn calls_go_through_the_generated_stubs() {
+        let name = format!("service_test{}", std::process::id());
+        let pool = runtime::Runtime::new().unwrap();
+
+        pool.block_on(async {
+            let server = MessageIpcServer::new(&name).unwrap();
+            let client = StorageClient::new(MessageIpcClient::new(&name).unwrap()).unwrap();
+
+            let (connection, _server) = server.wait_for_connection().await.unwrap();
+
+            let served = tokio::spawn(async move {
+                StorageServer::new(MemoryStorage::default()).serve(connection).await
+            });
+
+            let missing = client.get(GetRequest { key: "horse".to_owned() }).await.unwrap();
+            assert_eq!(missing.value, None);
+
+            // An empty response encodes to nothing, but still arrives.
+            let put = PutRequest { key: "horse".to_owned(), value: b"neigh".to_vec() };
+            assert_eq!(client.put(put).await.unwrap(), PutResponse {});
+
+            let found = client.get(GetRequest { key: "horse".to_owned() }).await.unwrap();
+            assert_eq!(found.value, Some(b"neigh".to_vec()));
+
+            match client.put(PutRequest::default()).await {
+                Err(IpcError::Remote(description)) => assert_eq!(description, "Keys can't be empty"),
+                result => panic!("Unexpected result {:?}", result),
+            };
+
+            drop(client);
+            served.await.unwrap().unwrap();
+        });
+    }
+}
@@ -1 +1,34 @@
+//! Build-time helpers for projects using the `ipc` crate.
+//!
+//! Call [`compile_protos`] from a build script to generate prost messages plus typed IPC
+//! client and server stubs for every service in a set of `.proto` files. The generated code
+//! needs the `ipc` crate with its `protobuf` feature enabled, and `prost`.
 
+mod service_generator;
+
+pub use self::service_generator::IpcServiceGenerator;
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Finds the protoc used to compile `.proto` files. $PROTOC takes precedence, then the copy
+/// bundled with this crate on Windows, then whatever protoc is on the PATH.
+pub fn protoc_path() -> PathBuf {
+    if let Some(protoc) = env::var_os("PROTOC") {
+        return PathBuf::from(protoc);
+    }
+
+    match option_env!("TOOLS_BUNDLED_PROTOC") {
+        Some(protoc) => PathBuf::from(protoc),
+        None => PathBuf::from("protoc"),
+    }
+}
+
+/// Compiles `protos` into Rust modules in $OUT_DIR, one per protobuf package. Include them
+/// with `include!(concat!(env!("OUT_DIR"), "/<package>.rs"))`.
+pub fn compile_protos<P: AsRef<Path>>(protos: &[P], includes: &[P]) -> std::io::Result<()> {
+    prost_build::Config::new()
+        .protoc_executable(protoc_path())
+        .service_generator(Box::new(IpcServiceGenerator::new()))
+        .compile_protos(protos, includes)
+}
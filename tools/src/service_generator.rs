@@ -0,0 +1,225 @@
+use prost_build::{Method, Service, ServiceGenerator};
+
+use std::fmt::Write;
+
+/// Generates, for each protobuf service `Foo`:
+///
+/// * `FooClient`, which calls the service over an `ipc::MessageIpcConnection`.
+/// * A `Foo` trait with one async method per RPC for servers to implement.
+/// * `FooServer`, which dispatches calls arriving on a connection to a `Foo`.
+///
+/// Both are built on the `ipc::rpc` layer, so many calls may be in flight on a connection at
+/// once.
+///
+/// Only unary RPCs are supported.
+#[derive(Debug, Default)]
+pub struct IpcServiceGenerator {}
+
+impl IpcServiceGenerator {
+    pub fn new() -> IpcServiceGenerator {
+        IpcServiceGenerator {}
+    }
+}
+
+fn full_service_name(service: &Service) -> String {
+    if service.package.is_empty() {
+        service.proto_name.clone()
+    } else {
+        format!("{}.{}", service.package, service.proto_name)
+    }
+}
+
+fn method_path(service: &Service, method: &Method) -> String {
+    format!("{}/{}", full_service_name(service), method.proto_name)
+}
+
+impl IpcServiceGenerator {
+    fn generate_client(&self, service: &Service, buf: &mut String) {
+        let name = &service.name;
+
+        service.comments.append_with_indent(0, buf);
+        writeln!(buf, "/// Client for the `{}` IPC service.", full_service_name(service)).unwrap();
+        writeln!(buf, "pub struct {}Client {{", name).unwrap();
+        writeln!(buf, "    client: ::ipc::protobuf::ProtobufClient,").unwrap();
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "impl {}Client {{", name).unwrap();
+        writeln!(buf, "    /// Creates a client calling the service over `connection`. Must be called within a tokio").unwrap();
+        writeln!(buf, "    /// runtime.").unwrap();
+        writeln!(buf, "    pub fn new(connection: ::ipc::MessageIpcConnection) -> ::ipc::Result<{}Client> {{", name).unwrap();
+        writeln!(buf, "        ::std::result::Result::Ok({}Client {{", name).unwrap();
+        writeln!(buf, "            client: ::ipc::protobuf::ProtobufClient::new(connection)?,").unwrap();
+        writeln!(buf, "        }})").unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "    /// Gives each call `timeout` to complete, after which it fails with").unwrap();
+        writeln!(buf, "    /// `IpcError::DeadlineExceeded`.").unwrap();
+        writeln!(buf, "    pub fn with_timeout(self, timeout: ::std::time::Duration) -> {}Client {{", name).unwrap();
+        writeln!(buf, "        {}Client {{ client: self.client.with_timeout(timeout) }}", name).unwrap();
+        writeln!(buf, "    }}").unwrap();
+
+        for method in &service.methods {
+            writeln!(buf).unwrap();
+            method.comments.append_with_indent(1, buf);
+            writeln!(
+                buf,
+                "    pub async fn {}(&self, request: {}) -> ::ipc::Result<{}> {{",
+                method.name, method.input_type, method.output_type
+            )
+            .unwrap();
+            writeln!(buf, "        self.client.call(\"{}\", &request).await", method_path(service, method)).unwrap();
+            writeln!(buf, "    }}").unwrap();
+        }
+
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+    }
+
+    fn generate_trait(&self, service: &Service, buf: &mut String) {
+        service.comments.append_with_indent(0, buf);
+        writeln!(
+            buf,
+            "/// Implement this to serve the `{}` IPC service with [`{}Server`].",
+            full_service_name(service),
+            service.name
+        )
+        .unwrap();
+        writeln!(buf, "pub trait {}: Send + Sync {{", service.name).unwrap();
+
+        for method in &service.methods {
+            method.comments.append_with_indent(1, buf);
+            writeln!(
+                buf,
+                "    fn {}(&self, request: {}) -> impl ::std::future::Future<Output = ::ipc::Result<{}>> + Send;",
+                method.name, method.input_type, method.output_type
+            )
+            .unwrap();
+        }
+
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+    }
+
+    fn generate_server(&self, service: &Service, buf: &mut String) {
+        let name = &service.name;
+
+        writeln!(buf, "/// Dispatches calls arriving on a connection to an implementation of [`{}`].", name).unwrap();
+        writeln!(buf, "pub struct {}Server {{", name).unwrap();
+        writeln!(buf, "    server: ::ipc::protobuf::ProtobufServer,").unwrap();
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "impl {}Server {{", name).unwrap();
+        writeln!(buf, "    pub fn new<T: {} + 'static>(service: T) -> {}Server {{", name, name).unwrap();
+        writeln!(buf, "        let service = ::std::sync::Arc::new(service);").unwrap();
+        writeln!(buf, "        let mut server = ::ipc::protobuf::ProtobufServer::new();").unwrap();
+
+        for method in &service.methods {
+            writeln!(buf).unwrap();
+            writeln!(buf, "        let {}_service = service.clone();", method.name).unwrap();
+            writeln!(
+                buf,
+                "        server.register(\"{}\", move |request: {}| {{",
+                method_path(service, method),
+                method.input_type
+            )
+            .unwrap();
+            writeln!(buf, "            let service = {}_service.clone();", method.name).unwrap();
+            writeln!(buf).unwrap();
+            writeln!(buf, "            async move {{ service.{}(request).await }}", method.name).unwrap();
+            writeln!(buf, "        }});").unwrap();
+        }
+
+        writeln!(buf).unwrap();
+        writeln!(buf, "        {}Server {{ server }}", name).unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "    /// Serves calls on `connection` until the client disconnects.").unwrap();
+        writeln!(buf, "    pub async fn serve(&self, connection: ::ipc::MessageIpcConnection) -> ::ipc::Result<()> {{").unwrap();
+        writeln!(buf, "        self.server.serve(connection).await").unwrap();
+        writeln!(buf, "    }}").unwrap();
+        writeln!(buf, "}}").unwrap();
+        writeln!(buf).unwrap();
+    }
+}
+
+impl ServiceGenerator for IpcServiceGenerator {
+    fn generate(&mut self, service: Service, buf: &mut String) {
+        for method in &service.methods {
+            if method.client_streaming || method.server_streaming {
+                panic!(
+                    "{} is a streaming RPC, which IPC services don't support",
+                    method_path(&service, method)
+                );
+            }
+        }
+
+        self.generate_client(&service, buf);
+        self.generate_trait(&service, buf);
+        self.generate_server(&service, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpcServiceGenerator;
+
+    use prost_build::{Comments, Method, Service, ServiceGenerator};
+
+    fn method(name: &str, proto_name: &str, input: &str, output: &str) -> Method {
+        Method {
+            name: name.to_owned(),
+            proto_name: proto_name.to_owned(),
+            comments: Comments::default(),
+            input_type: input.to_owned(),
+            output_type: output.to_owned(),
+            input_proto_type: format!(".storage.{}", input),
+            output_proto_type: format!(".storage.{}", output),
+            options: Default::default(),
+            client_streaming: false,
+            server_streaming: false,
+        }
+    }
+
+    fn storage_service() -> Service {
+        Service {
+            name: "Storage".to_owned(),
+            proto_name: "Storage".to_owned(),
+            package: "storage".to_owned(),
+            comments: Comments::default(),
+            methods: vec![
+                method("get", "Get", "GetRequest", "GetResponse"),
+                method("put", "Put", "PutRequest", "PutResponse"),
+            ],
+            options: Default::default(),
+        }
+    }
+
+    #[test]
+    fn generates_client_trait_and_server() {
+        let mut buf = String::new();
+
+        IpcServiceGenerator::new().generate(storage_service(), &mut buf);
+
+        assert!(buf.contains("pub struct StorageClient {"));
+        assert!(buf.contains("pub async fn get(&self, request: GetRequest) -> ::ipc::Result<GetResponse> {"));
+        assert!(buf.contains("self.client.call(\"storage.Storage/Put\", &request).await"));
+
+        assert!(buf.contains("pub trait Storage: Send + Sync {"));
+        assert!(buf.contains(
+            "fn put(&self, request: PutRequest) -> impl ::std::future::Future<Output = ::ipc::Result<PutResponse>> + Send;"
+        ));
+
+        assert!(buf.contains("pub struct StorageServer {"));
+        assert!(buf.contains("server.register(\"storage.Storage/Get\", move |request: GetRequest| {"));
+        assert!(buf.contains("async move { service.get(request).await }"));
+    }
+
+    #[test]
+    #[should_panic(expected = "storage.Storage/Get is a streaming RPC")]
+    fn rejects_streaming_methods() {
+        let mut service = storage_service();
+        service.methods[0].server_streaming = true;
+
+        IpcServiceGenerator::new().generate(service, &mut String::new());
+    }
+}